@@ -0,0 +1,600 @@
+use crate::{
+    environment::Environment,
+    finance, integrate,
+    math::{self, Rounding},
+    matrix,
+    parser::TreeNode,
+    polynomial::Polynomial,
+    solve::{find_root, find_roots, Func},
+    time,
+    tokeniser::Token,
+    value::{EvalError, Value},
+};
+
+/// Gets the name of the variable an argument like the `x` in `diff(x^2, x)` is
+pub(crate) fn variable_arg(arg: &TreeNode) -> Result<&str, EvalError> {
+    return match &arg.value {
+        Some(Token::Variable(name)) => Ok(name),
+        _ => Err(EvalError::ExpectedVariable(arg.to_string())),
+    };
+}
+
+/// `piecewise(cond1, value1, cond2, value2, ..., default)`
+///
+/// Only the conditions up to the first true one and its value are evaluated,
+/// so `piecewise(x == 0, 0, 1 / x)` never divides by zero.
+pub fn piecewise(args: &[TreeNode], env: &Environment) -> Result<Value, EvalError> {
+    let mut pairs = args.chunks_exact(2);
+    for pair in &mut pairs {
+        if pair[0].eval_with(env)?.as_bool()? {
+            return pair[1].eval_with(env);
+        }
+    }
+    return match pairs.remainder() {
+        [default] => default.eval_with(env),
+        _ => Err(EvalError::NoMatchingPiece),
+    };
+}
+
+/// `diff(expr, x)`
+///
+/// If every variable in the derivative is set, this is the value of the
+/// derivative there. Otherwise, it's the derivative itself.
+pub fn diff(args: &[TreeNode], env: &Environment) -> Result<Value, EvalError> {
+    let var = variable_arg(&args[1])?;
+    let derivative = args[0].inline_functions(env)?.derivative(var)?;
+
+    return match derivative.eval_with(env) {
+        Err(EvalError::UnknownVariable(_)) => Ok(Value::Expression(Box::new(derivative))),
+        result => result,
+    };
+}
+
+fn number_arg(arg: &TreeNode, env: &Environment) -> Result<f64, EvalError> {
+    return arg.eval_with(env)?.as_number();
+}
+
+/// `lhs = rhs` becomes `lhs - rhs`, which is 0 where the two sides are equal
+fn equation(arg: &TreeNode) -> TreeNode {
+    if let (Some("=" | "=="), Some(lhs), Some(rhs)) = (arg.name(), &arg.left, &arg.right) {
+        return TreeNode::operator("-", *lhs.clone(), *rhs.clone());
+    }
+    return arg.clone();
+}
+
+/// `solve(x^2 - 2 = 0, x)` or `solve(x^2 = 2, x, a, b)`
+///
+/// Every root between a and b (-100 and 100 if they aren't given), as a list.
+/// Without `=`, the expression itself is solved for 0.
+///
+/// `solve(A, b)` with a matrix solves the linear system `A * x = b` instead.
+pub fn solve(args: &[TreeNode], env: &Environment) -> Result<Value, EvalError> {
+    if let [a, b] = args {
+        // An equation with its variable unset can't be evaluated, so this only catches lists
+        if let Ok(a @ Value::List(_)) = a.eval_with(env) {
+            return matrix::solve(&a, &b.eval_with(env)?);
+        }
+    }
+    let var = variable_arg(&args[1])?;
+    let (a, b) = match args {
+        [_, _] => (-100.0, 100.0),
+        [_, _, a, b] => (number_arg(a, env)?, number_arg(b, env)?),
+        _ => {
+            return Err(EvalError::WrongType(
+                "solve takes both ends of the interval or neither".to_string(),
+            ))
+        }
+    };
+
+    let f = Func::new(&equation(&args[0]), var, env)?;
+    let roots = find_roots(&f, a.min(b), a.max(b))?;
+    return Ok(Value::List(roots.into_iter().map(Value::Number).collect()));
+}
+
+/// `root(x^2 - 2, 0, 2)`
+///
+/// One root between a and b, where the expression has to change sign.
+/// The expression can only have one variable that isn't set.
+pub fn root(args: &[TreeNode], env: &Environment) -> Result<Value, EvalError> {
+    let f = equation(&args[0]);
+    let var = single_variable(std::slice::from_ref(&f), env)?;
+    let f = Func::new(&f, &var, env)?;
+    let a = number_arg(&args[1], env)?;
+    let b = number_arg(&args[2], env)?;
+    return Ok(Value::Number(find_root(&f, a, b)?));
+}
+
+/// The variable of expressions like `x^2 - 2`, which has to be the only one that isn't set
+/// (or the only one at all)
+fn single_variable(trees: &[TreeNode], env: &Environment) -> Result<String, EvalError> {
+    let mut variables: Vec<String> = vec![];
+    for name in trees.iter().flat_map(|t| t.variables()) {
+        if !variables.contains(&name) {
+            variables.push(name);
+        }
+    }
+    let unset = variables
+        .iter()
+        .filter(|v| env.get(v).is_none())
+        .collect::<Vec<_>>();
+    return match (&unset[..], &variables[..]) {
+        ([var], _) => Ok(var.to_string()),
+        ([], [var]) => Ok(var.to_string()),
+        _ => {
+            let trees = trees.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+            Err(EvalError::ExpectedVariable(trees.join(", ")))
+        }
+    };
+}
+
+/// `integrate(expr, x, a, b)`
+///
+/// The definite integral from a to b, found numerically with adaptive Gauss–Kronrod quadrature.
+/// It's an error if the estimated error can't be made small enough.
+pub fn integrate(args: &[TreeNode], env: &Environment) -> Result<Value, EvalError> {
+    let var = variable_arg(&args[1])?;
+    let f = Func::new(&args[0], var, env)?;
+    let a = number_arg(&args[2], env)?;
+    let b = number_arg(&args[3], env)?;
+    return Ok(Value::Number(integrate::integrate(&f, a, b)?));
+}
+
+/// The most terms `sum` and `prod` will add up, so a typo like `sum(1, k, 1, 1e12)`
+/// is an error instead of running for hours
+pub const MAX_TERMS: f64 = 1e7;
+
+/// Evaluates `args[0]` with the variable `args[1]` set to a, a + 1, ... up to b,
+/// and combines the results with `fun`
+fn series(
+    args: &[TreeNode],
+    env: &Environment,
+    start: f64,
+    fun: fn(f64, f64) -> f64,
+) -> Result<Value, EvalError> {
+    let var = variable_arg(&args[1])?;
+    let f = Func::new(&args[0], var, env)?;
+    let a = number_arg(&args[2], env)?;
+    let b = number_arg(&args[3], env)?;
+
+    // Counting instead of adding 1 to k, which gets stuck once k is too big for 1 to matter
+    let count = (b - a).floor() + 1.0;
+    if !count.is_finite() {
+        return Err(EvalError::WrongType(format!(
+            "Can't count from {} to {}",
+            a, b
+        )));
+    }
+    if count > MAX_TERMS {
+        return Err(EvalError::Domain(format!(
+            "Can't add up more than {} terms ({} to {} has {})",
+            MAX_TERMS, a, b, count
+        )));
+    }
+    let mut total = start;
+    for i in 0..count.max(0.0) as u64 {
+        total = fun(total, f.at(a + i as f64)?);
+    }
+    return Ok(Value::Number(total));
+}
+
+/// `sum(expr, k, a, b)`, which is 0 if b is less than a, or the sum of a list. It's only
+/// a series if `k` isn't a variable already, so `sum(a, b, c, d)` adds up four variables.
+pub fn sum(args: &[TreeNode], env: &Environment) -> Result<Value, EvalError> {
+    let index = match &args.get(1).and_then(|arg| arg.value.as_ref()) {
+        Some(Token::Variable(k)) => env.get(k).is_none(),
+        _ => false,
+    };
+    if args.len() == 4 && index {
+        return series(args, env, 0.0, |a, b| a + b);
+    }
+    return Ok(Value::Number(numbers(args, env)?.iter().sum()));
+}
+
+/// `prod(expr, k, a, b)`, which is 1 if b is less than a
+pub fn prod(args: &[TreeNode], env: &Environment) -> Result<Value, EvalError> {
+    return series(args, env, 1.0, |a, b| a * b);
+}
+
+/// The first `count` arguments as polynomials in the variable after them,
+/// or in their only variable if it isn't given
+fn polynomials(
+    args: &[TreeNode],
+    count: usize,
+    env: &Environment,
+) -> Result<(Vec<Polynomial>, String), EvalError> {
+    let trees = args[..count].iter().map(|arg| arg.inline_functions(env));
+    let trees = trees.collect::<Result<Vec<_>, _>>()?;
+    let var = match args.get(count) {
+        Some(arg) => variable_arg(arg)?.to_string(),
+        None => single_variable(&trees, env)?,
+    };
+    let polynomials = trees
+        .iter()
+        .map(|arg| Polynomial::from_tree(&equation(arg), &var, env))
+        .collect::<Result<_, _>>()?;
+    return Ok((polynomials, var));
+}
+
+/// `coeffs(x^2 - 1, x)`, from the highest power down, so that's `[1, 0, -1]`
+pub fn coeffs(args: &[TreeNode], env: &Environment) -> Result<Value, EvalError> {
+    let (p, _) = polynomials(args, 1, env)?;
+    return Ok(Value::List(
+        p[0].coeffs().into_iter().map(Value::Number).collect(),
+    ));
+}
+
+/// `expand((x + 1)^3, x)`, which is `x^3 + 3 * x^2 + 3 * x + 1`
+pub fn expand(args: &[TreeNode], env: &Environment) -> Result<Value, EvalError> {
+    let (p, var) = polynomials(args, 1, env)?;
+    return Ok(Value::Expression(Box::new(p[0].to_tree(&var))));
+}
+
+/// `poly_div(a, b, x)`, the quotient and remainder of a / b as a list
+pub fn poly_div(args: &[TreeNode], env: &Environment) -> Result<Value, EvalError> {
+    let (p, var) = polynomials(args, 2, env)?;
+    let (quotient, remainder) = p[0].divide(&p[1])?;
+    return Ok(Value::List(vec![
+        Value::Expression(Box::new(quotient.to_tree(&var))),
+        Value::Expression(Box::new(remainder.to_tree(&var))),
+    ]));
+}
+
+/// `poly_roots(x^2 + 1, x)`
+///
+/// Every root, including complex ones (which are written like `0 + i`),
+/// as many times as it's repeated.
+pub fn poly_roots(args: &[TreeNode], env: &Environment) -> Result<Value, EvalError> {
+    // A constant has no variable to find, and no roots (unless it's 0)
+    if let [arg] = args {
+        let tree = equation(&arg.inline_functions(env)?);
+        if tree.variables().iter().all(|v| env.get(v).is_some()) {
+            return match tree.eval_with(env)?.as_number()? {
+                0.0 => Err(EvalError::Domain("Every number is a root of 0".to_string())),
+                _ => Ok(Value::List(vec![])),
+            };
+        }
+    }
+    let (p, _) = polynomials(args, 1, env)?;
+    return Ok(Value::List(
+        p[0].roots()?.into_iter().map(Value::from).collect(),
+    ));
+}
+
+/// `now`, the current date and time (from the environment's clock, if it has one)
+pub fn now(_args: &[TreeNode], env: &Environment) -> Result<Value, EvalError> {
+    return Ok(Value::Date(env.now()));
+}
+
+/// `today`, the start of the current day in UTC
+pub fn today(_args: &[TreeNode], env: &Environment) -> Result<Value, EvalError> {
+    return Ok(Value::Date(time::start_of_day(env.now())));
+}
+
+/// `[1, 2, 3]`, which is `list(1, 2, 3)`
+pub fn list(args: &[TreeNode], env: &Environment) -> Result<Value, EvalError> {
+    let items = args.iter().map(|arg| arg.eval_with(env));
+    return Ok(Value::List(items.collect::<Result<_, _>>()?));
+}
+
+/// The numbers in the arguments, with the items of lists taken out,
+/// so both `mean([1, 2, 3])` and `mean(1, 2, 3)` work
+fn numbers(args: &[TreeNode], env: &Environment) -> Result<Vec<f64>, EvalError> {
+    let mut numbers = vec![];
+    for arg in args {
+        match arg.eval_with(env)? {
+            Value::List(items) => {
+                for item in items {
+                    numbers.push(item.as_number()?);
+                }
+            }
+            value => numbers.push(value.as_number()?),
+        }
+    }
+    return Ok(numbers);
+}
+
+/// Like `numbers`, but there has to be at least `min` of them for the function to make sense
+fn at_least(
+    args: &[TreeNode],
+    env: &Environment,
+    min: usize,
+    name: &str,
+) -> Result<Vec<f64>, EvalError> {
+    let numbers = numbers(args, env)?;
+    if numbers.len() < min {
+        return Err(EvalError::Domain(format!(
+            "{} needs at least {} value{}",
+            name,
+            min,
+            if min == 1 { "" } else { "s" }
+        )));
+    }
+    return Ok(numbers);
+}
+
+fn sorted(mut numbers: Vec<f64>) -> Vec<f64> {
+    numbers.sort_by(f64::total_cmp);
+    return numbers;
+}
+
+fn average(numbers: &[f64]) -> f64 {
+    return numbers.iter().sum::<f64>() / numbers.len() as f64;
+}
+
+/// The sum of the squared distances from the mean, divided by `numbers.len() - correction`
+fn variance(numbers: &[f64], correction: usize) -> f64 {
+    let mean = average(numbers);
+    let squares: f64 = numbers.iter().map(|x| (x - mean).powi(2)).sum();
+    return squares / (numbers.len() - correction) as f64;
+}
+
+pub fn count(args: &[TreeNode], env: &Environment) -> Result<Value, EvalError> {
+    return Ok(Value::Number(numbers(args, env)?.len() as f64));
+}
+
+pub fn mean(args: &[TreeNode], env: &Environment) -> Result<Value, EvalError> {
+    return Ok(Value::Number(average(&at_least(args, env, 1, "mean")?)));
+}
+
+pub fn median(args: &[TreeNode], env: &Environment) -> Result<Value, EvalError> {
+    let numbers = sorted(at_least(args, env, 1, "median")?);
+    let middle = numbers.len() / 2;
+    if numbers.len().is_multiple_of(2) {
+        return Ok(Value::Number((numbers[middle - 1] + numbers[middle]) / 2.0));
+    }
+    return Ok(Value::Number(numbers[middle]));
+}
+
+/// The most common value, or the smallest of them if there's a tie
+pub fn mode(args: &[TreeNode], env: &Environment) -> Result<Value, EvalError> {
+    let numbers = sorted(at_least(args, env, 1, "mode")?);
+    let (mut best, mut best_count) = (numbers[0], 0);
+    for run in numbers.chunk_by(|a, b| a == b) {
+        if run.len() > best_count {
+            best = run[0];
+            best_count = run.len();
+        }
+    }
+    return Ok(Value::Number(best));
+}
+
+/// The sample variance, which divides by n - 1
+pub fn var(args: &[TreeNode], env: &Environment) -> Result<Value, EvalError> {
+    return Ok(Value::Number(variance(&at_least(args, env, 2, "var")?, 1)));
+}
+
+/// The population variance, which divides by n
+pub fn pvar(args: &[TreeNode], env: &Environment) -> Result<Value, EvalError> {
+    return Ok(Value::Number(variance(&at_least(args, env, 1, "pvar")?, 0)));
+}
+
+/// The sample standard deviation
+pub fn stdev(args: &[TreeNode], env: &Environment) -> Result<Value, EvalError> {
+    let numbers = at_least(args, env, 2, "stdev")?;
+    return Ok(Value::Number(variance(&numbers, 1).sqrt()));
+}
+
+/// The population standard deviation
+pub fn pstdev(args: &[TreeNode], env: &Environment) -> Result<Value, EvalError> {
+    let numbers = at_least(args, env, 1, "pstdev")?;
+    return Ok(Value::Number(variance(&numbers, 0).sqrt()));
+}
+
+pub fn min(args: &[TreeNode], env: &Environment) -> Result<Value, EvalError> {
+    let numbers = at_least(args, env, 1, "min")?;
+    return Ok(Value::Number(
+        numbers.into_iter().fold(f64::INFINITY, f64::min),
+    ));
+}
+
+pub fn max(args: &[TreeNode], env: &Environment) -> Result<Value, EvalError> {
+    let numbers = at_least(args, env, 1, "max")?;
+    return Ok(Value::Number(
+        numbers.into_iter().fold(f64::NEG_INFINITY, f64::max),
+    ));
+}
+
+/// `percentile([1, 2, 3, 4], 50)`
+///
+/// Goes linearly between the two closest values, so the 0th is the min and the 100th is the max.
+pub fn percentile(args: &[TreeNode], env: &Environment) -> Result<Value, EvalError> {
+    let numbers = sorted(at_least(&args[..1], env, 1, "percentile")?);
+    let p = number_arg(&args[1], env)?;
+    if !(0.0..=100.0).contains(&p) {
+        return Err(EvalError::Domain(format!(
+            "The percentile has to be between 0 and 100, not {}",
+            p
+        )));
+    }
+
+    let rank = p / 100.0 * (numbers.len() - 1) as f64;
+    let below = numbers[rank.floor() as usize];
+    let above = numbers[rank.ceil() as usize];
+    return Ok(Value::Number(below + (above - below) * rank.fract()));
+}
+
+/// `npv(0.1, [-1000, 300, 400, 500])`, or with the values as separate arguments.
+///
+/// Like spreadsheets, the first value is discounted by one period, so an amount paid now
+/// has to be added on separately.
+pub fn npv(args: &[TreeNode], env: &Environment) -> Result<Value, EvalError> {
+    let rate = number_arg(&args[0], env)?;
+    let values = at_least(&args[1..], env, 1, "npv")?;
+    return Ok(Value::Number(finance::npv(rate, &values)));
+}
+
+/// `irr([-1000, 300, 400, 500])`, with the first value now and the rest a period apart
+pub fn irr(args: &[TreeNode], env: &Environment) -> Result<Value, EvalError> {
+    let values = at_least(args, env, 2, "irr")?;
+    return Ok(Value::Number(finance::irr(&values)?));
+}
+
+/// The rounding mode in the last argument of `round(x, 2, half_even)`, if it's there
+pub(crate) fn rounding_arg(arg: Option<&TreeNode>) -> Result<Rounding, EvalError> {
+    return match arg {
+        Some(arg) => Rounding::from_name(variable_arg(arg)?),
+        None => Ok(Rounding::HalfUp),
+    };
+}
+
+/// `round(x)`, `round(x, decimals)` or `round(x, decimals, mode)`
+pub fn round(args: &[TreeNode], env: &Environment) -> Result<Value, EvalError> {
+    let mode = rounding_arg(args.get(2))?;
+    let args = &args[..args.len().min(2)];
+    return numeric(args, env, |x| {
+        math::round(x[0], x.get(1).copied().unwrap_or(0.0), mode)
+    });
+}
+
+/// `round_sig(x, figures)` or `round_sig(x, figures, mode)`
+pub fn round_sig(args: &[TreeNode], env: &Environment) -> Result<Value, EvalError> {
+    let mode = rounding_arg(args.get(2))?;
+    return numeric(&args[..2], env, |x| math::round_sig(x[0], x[1], mode));
+}
+
+/// `roundto(x, step)` or `roundto(x, step, mode)`
+pub fn roundto(args: &[TreeNode], env: &Environment) -> Result<Value, EvalError> {
+    let mode = rounding_arg(args.get(2))?;
+    return numeric(&args[..2], env, |x| math::round_to(x[0], x[1], mode));
+}
+
+/// Evaluates the arguments and calls `fun` with them, like `dot(a, b)`
+pub fn values(
+    args: &[TreeNode],
+    env: &Environment,
+    fun: fn(&[Value]) -> Result<Value, EvalError>,
+) -> Result<Value, EvalError> {
+    let values = args.iter().map(|arg| arg.eval_with(env));
+    return fun(&values.collect::<Result<Vec<_>, _>>()?);
+}
+
+/// Evaluates the arguments as numbers and calls `fun` with them, like `normcdf(x, mu, sigma)`.
+///
+/// If some of them are lists, `fun` is called for each item, so `normcdf([0, 1])` is a list too.
+pub fn numeric<T: Into<Value>>(
+    args: &[TreeNode],
+    env: &Environment,
+    fun: impl Fn(&[f64]) -> Result<T, EvalError>,
+) -> Result<Value, EvalError> {
+    let values = args.iter().map(|arg| arg.eval_with(env));
+    return apply_numeric(&values.collect::<Result<Vec<_>, _>>()?, &fun);
+}
+
+fn apply_numeric<T: Into<Value>>(
+    values: &[Value],
+    fun: &dyn Fn(&[f64]) -> Result<T, EvalError>,
+) -> Result<Value, EvalError> {
+    let mut len = None;
+    for value in values {
+        if let Value::List(items) = value {
+            match len {
+                Some(len) if len != items.len() => {
+                    return Err(EvalError::MismatchedLengths(len, items.len()))
+                }
+                _ => len = Some(items.len()),
+            }
+        }
+    }
+
+    let len = match len {
+        Some(len) => len,
+        None => {
+            let numbers = values.iter().map(|v| v.as_number());
+            return Ok(fun(&numbers.collect::<Result<Vec<_>, _>>()?)?.into());
+        }
+    };
+    let mut items = vec![];
+    for i in 0..len {
+        let row = values.iter().map(|v| match v {
+            Value::List(items) => items[i].clone(),
+            v => v.clone(),
+        });
+        items.push(apply_numeric(&row.collect::<Vec<_>>(), fun)?);
+    }
+    return Ok(Value::List(items));
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        environment::Environment,
+        error::parse_str,
+        value::{EvalError, Value},
+    };
+
+    fn eval(input: &str) -> Result<f64, EvalError> {
+        return parse_str(input).unwrap().eval()?.as_number();
+    }
+
+    #[test]
+    fn sums_and_products() {
+        assert_eq!(eval("sum(k^2, k, 1, 10)").unwrap(), 385.0);
+        assert_eq!(eval("prod(k, k, 1, 5)").unwrap(), 120.0);
+        assert_eq!(eval("sum(k, k, 5, 1)").unwrap(), 0.0);
+        assert_eq!(eval("prod(k, k, 5, 1)").unwrap(), 1.0);
+        assert_eq!(eval("sum(1, 2, 3)").unwrap(), 6.0);
+
+        // Four variables that are already set are added up, not a series over the second
+        let mut env = Environment::new();
+        for (name, x) in [("a", 1.0), ("b", 2.0), ("c", 3.0), ("d", 4.0)] {
+            env.set(name, Value::Number(x));
+        }
+        let sum = |input: &str| parse_str(input).unwrap().eval_with(&env);
+        assert_eq!(sum("sum(a, b, c, d)").unwrap().as_number().ok(), Some(10.0));
+        assert_eq!(
+            sum("sum(a * k, k, c, d)").unwrap().as_number().ok(),
+            Some(7.0)
+        );
+    }
+
+    #[test]
+    fn too_many_terms() {
+        assert!(matches!(
+            eval("sum(1, k, 1, 1e12)"),
+            Err(EvalError::Domain(_))
+        ));
+    }
+
+    #[test]
+    fn integrals() {
+        assert!((eval("integrate(x^2, x, 0, 3)").unwrap() - 9.0).abs() < 1e-12);
+        assert!((eval("integrate(sin(x), x, 0, pi)").unwrap() - 2.0).abs() < 1e-12);
+        assert!(
+            (eval("integrate(e^(-x^2), x, -10, 10)").unwrap() - std::f64::consts::PI.sqrt()).abs()
+                < 1e-12
+        );
+    }
+
+    #[test]
+    fn statistics() {
+        let data = "2, 4, 4, 4, 5, 5, 7, 9";
+        assert_eq!(eval("mean(1, 2, 3, 4)").unwrap(), 2.5);
+        assert_eq!(eval("mean([1, 2, 3, 4])").unwrap(), 2.5);
+        assert_eq!(eval("median(3, 1, 2, 10)").unwrap(), 2.5);
+        assert_eq!(eval("median([1, 2, 3])").unwrap(), 2.0);
+        // The smallest of the most common values
+        assert_eq!(eval("mode(2, 2, 1, 1, 3)").unwrap(), 1.0);
+        assert_eq!(eval(&format!("pvar({})", data)).unwrap(), 4.0);
+        assert_eq!(eval(&format!("pstdev({})", data)).unwrap(), 2.0);
+        assert_eq!(eval(&format!("var({})", data)).unwrap(), 32.0 / 7.0);
+        assert_eq!(eval("min(3, 1, 2)").unwrap(), 1.0);
+        assert_eq!(eval("max([3, 1, 2])").unwrap(), 3.0);
+        assert_eq!(eval("count([1, 2, 3])").unwrap(), 3.0);
+        assert_eq!(eval("percentile([1, 2, 3, 4, 5], 25)").unwrap(), 2.0);
+        assert_eq!(eval("percentile([1, 2, 3, 4], 50)").unwrap(), 2.5);
+    }
+
+    #[test]
+    fn statistics_errors() {
+        assert!(matches!(eval("mean([])"), Err(EvalError::Domain(_))));
+        assert!(matches!(eval("var(1)"), Err(EvalError::Domain(_))));
+        assert!(matches!(
+            eval("percentile([1, 2], 150)"),
+            Err(EvalError::Domain(_))
+        ));
+        assert!(matches!(
+            eval("mean([1, [2, 3]])"),
+            Err(EvalError::WrongType(_))
+        ));
+    }
+}
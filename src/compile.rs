@@ -0,0 +1,615 @@
+use crate::{
+    builtins::{rounding_arg, variable_arg},
+    environment::Environment,
+    math,
+    parser::TreeNode,
+    tokeniser::Token,
+    value::{EvalError, Value},
+};
+
+/// A compiled node. Everything is boxed up front, so running it doesn't allocate.
+type Node = Box<dyn Fn(&[f64]) -> Result<f64, EvalError> + Send + Sync>;
+
+/// A compiled node that works on a chunk of rows at once.
+///
+/// It's given a slice of each input column and fills `out`, which is just as long.
+type BatchNode = Box<dyn Fn(&[&[f64]], &mut [f64]) -> Result<(), EvalError> + Send + Sync>;
+
+/// How many rows are done at once by [`CompiledExpr::eval_batch`].
+/// Small enough for the temporary arrays to live on the stack.
+const CHUNK: usize = 64;
+
+/// An expression that's ready to be evaluated over and over with different variables.
+///
+/// Made with [`TreeNode::compile`]. Everything is a number here, so booleans
+/// are 1 and 0 like they are in `true + true`.
+pub struct CompiledExpr {
+    root: Node,
+    batch: BatchNode,
+    variables: Vec<String>,
+}
+
+impl CompiledExpr {
+    /// The variables the expression needs, in the order `eval` takes them
+    pub fn variables(&self) -> &[String] {
+        return &self.variables;
+    }
+
+    /// Where the variable goes in the bindings, if the expression uses it
+    pub fn index_of(&self, name: &str) -> Option<usize> {
+        return self.variables.iter().position(|v| v == name);
+    }
+
+    /// Evaluates the expression, where `bindings[i]` is the value of `variables()[i]`
+    pub fn eval(&self, bindings: &[f64]) -> Result<f64, EvalError> {
+        if bindings.len() < self.variables.len() {
+            return Err(EvalError::UnknownVariable(
+                self.variables[bindings.len()].clone(),
+            ));
+        }
+        return (self.root)(bindings);
+    }
+
+    /// Evaluates the expression once per row, where `columns[i]` has the values of `variables()[i]`
+    pub fn eval_batch(&self, columns: &[&[f64]]) -> Result<Vec<f64>, EvalError> {
+        let rows = columns.first().map_or(0, |c| c.len());
+        let mut out = vec![0.0; rows];
+        self.eval_batch_into(columns, &mut out)?;
+        return Ok(out);
+    }
+
+    /// Like [`CompiledExpr::eval_batch`], but writes into `out`, which decides the number of rows.
+    ///
+    /// The rows are done in chunks so the simple operators become tight loops over arrays
+    /// that the compiler can vectorise.
+    pub fn eval_batch_into(&self, columns: &[&[f64]], out: &mut [f64]) -> Result<(), EvalError> {
+        if columns.len() < self.variables.len() {
+            return Err(EvalError::UnknownVariable(
+                self.variables[columns.len()].clone(),
+            ));
+        }
+        if let Some(column) = columns.iter().find(|c| c.len() != out.len()) {
+            return Err(EvalError::MismatchedLengths(column.len(), out.len()));
+        }
+
+        let mut chunk: Vec<&[f64]> = Vec::with_capacity(columns.len());
+        for (i, out) in out.chunks_mut(CHUNK).enumerate() {
+            let start = i * CHUNK;
+            chunk.clear();
+            chunk.extend(columns.iter().map(|c| &c[start..start + out.len()]));
+            (self.batch)(&chunk, out)?;
+        }
+        return Ok(());
+    }
+}
+
+fn constant(n: f64) -> Node {
+    return Box::new(move |_| Ok(n));
+}
+
+fn bool_to_number(b: bool) -> f64 {
+    return if b { 1.0 } else { 0.0 };
+}
+
+fn truthy(n: f64) -> bool {
+    return n != 0.0 && !n.is_nan();
+}
+
+impl TreeNode {
+    /// Compiles the expression so it can be evaluated quickly many times.
+    ///
+    /// Every variable becomes an input of the [`CompiledExpr`], in the order
+    /// they first show up.
+    pub fn compile(&self) -> Result<CompiledExpr, EvalError> {
+        return self.compile_with(&Environment::new());
+    }
+
+    /// Like [`TreeNode::compile`], but the variables that are set in `env`
+    /// are baked in as constants instead of being inputs.
+    pub fn compile_with(&self, env: &Environment) -> Result<CompiledExpr, EvalError> {
+        // Defined functions are written out, so they're compiled like the rest
+        let tree = self.inline_functions(env)?;
+        let mut variables = vec![];
+        let root = tree.lower(env, &mut variables)?;
+        let batch = tree.lower_batch(env, &mut variables)?;
+        return Ok(CompiledExpr {
+            root,
+            batch,
+            variables,
+        });
+    }
+
+    /// Whether there's a variable that isn't set in `env`
+    fn has_free_variables(&self, env: &Environment) -> bool {
+        if let Some(Token::Variable(name)) = &self.value {
+            if env.get(name).is_none() && !self.is_defined_call() {
+                return true;
+            }
+        }
+        return self
+            .left
+            .as_ref()
+            .is_some_and(|t| t.has_free_variables(env))
+            || self
+                .right
+                .as_ref()
+                .is_some_and(|t| t.has_free_variables(env))
+            || self.args.iter().any(|t| t.has_free_variables(env));
+    }
+
+    /// Whether the value is a percentage, which `+` and `-` treat differently from
+    /// the number it stands for (ex: `x% + 5%`, or `-10%`, which is `0 - 10%`)
+    fn is_percent(&self, env: &Environment) -> bool {
+        if !self.has_free_variables(env) {
+            return matches!(self.eval_with(env), Ok(Value::Percent(_)));
+        }
+        if let Some(Token::Function { name, .. }) = &self.value {
+            return name == "%";
+        }
+        if let (Some(name @ ("+" | "-")), Some(l), Some(r)) = (self.name(), &self.left, &self.right)
+        {
+            let negation = name == "-" && l.as_number() == Some(0.0);
+            return (negation || l.is_percent(env)) && r.is_percent(env);
+        }
+        return false;
+    }
+
+    /// Lowers a node where `is_percent` is true to the percentage itself (10 for `10%`)
+    fn lower_percent(
+        &self,
+        env: &Environment,
+        variables: &mut Vec<String>,
+    ) -> Result<Node, EvalError> {
+        if !self.has_free_variables(env) {
+            return match self.eval_with(env)? {
+                Value::Percent(p) => Ok(constant(p)),
+                value => Err(EvalError::WrongType(format!(
+                    "{} isn't a percentage",
+                    value
+                ))),
+            };
+        }
+        return match (self.name(), &self.left, &self.right) {
+            (Some("%"), Some(arg), None) => arg.lower(env, variables),
+            (Some(name @ ("+" | "-")), Some(l), Some(r)) => {
+                let l = if l.is_percent(env) {
+                    l.lower_percent(env, variables)?
+                } else {
+                    constant(0.0)
+                };
+                let r = r.lower_percent(env, variables)?;
+                if name == "+" {
+                    Ok(Box::new(move |b| Ok(l(b)? + r(b)?)))
+                } else {
+                    Ok(Box::new(move |b| Ok(l(b)? - r(b)?)))
+                }
+            }
+            _ => Err(EvalError::MalformedTree("Not a percentage".to_string())),
+        };
+    }
+
+    /** lower
+
+    Algorithm:
+    1. If nothing in the node depends on the inputs, evaluate it now and use the number
+    2. Otherwise lower the children, and wrap them in a closure that does what the node does
+    3. Things that only evaluate some of their children (`if`, `and`, `piecewise`) still
+       only call the closures they need
+    4. Adding or subtracting a percentage goes through the `Value` version, since
+       `200 + 10%` is 220 and not 200.1
+    */
+    fn lower(&self, env: &Environment, variables: &mut Vec<String>) -> Result<Node, EvalError> {
+        if !self.has_free_variables(env) {
+            // If it fails (like `1 / 0`), leave it to fail when it's actually reached
+            match self.eval_with(env) {
+                Ok(Value::Number(n)) => return Ok(constant(n)),
+                Ok(Value::Bool(b)) => return Ok(constant(bool_to_number(b))),
+                Ok(Value::Percent(p)) => return Ok(constant(p / 100.0)),
+                _ => {}
+            }
+        }
+
+        let value = match &self.value {
+            Some(value) => value,
+            None => return Err(EvalError::MalformedTree("No value".to_string())),
+        };
+
+        match value {
+            Token::Constant(value) => {
+                return Ok(constant(value.as_number()?));
+            }
+            Token::Variable(name) => {
+                if let Some(value) = env.get(name) {
+                    return Ok(constant(value.as_number()?));
+                }
+                let i = match variables.iter().position(|v| v == name) {
+                    Some(i) => i,
+                    None => {
+                        variables.push(name.clone());
+                        variables.len() - 1
+                    }
+                };
+                return Ok(Box::new(move |b| Ok(b[i])));
+            }
+            Token::Operator { fun, name, .. } => {
+                if let (Some(l), Some(r)) = (&self.left, &self.right) {
+                    if matches!(name.as_str(), "+" | "-") && r.is_percent(env) {
+                        if self.is_percent(env) {
+                            let p = self.lower_percent(env, variables)?;
+                            return Ok(Box::new(move |b| Ok(p(b)? / 100.0)));
+                        }
+                        let (l, r) = (l.lower(env, variables)?, r.lower_percent(env, variables)?);
+                        let fun = *fun;
+                        return Ok(Box::new(move |b| {
+                            fun(Value::Number(l(b)?), Value::Percent(r(b)?))?.as_number()
+                        }));
+                    }
+                }
+                let (l, r) = match (&self.left, &self.right) {
+                    (Some(l), Some(r)) => (l.lower(env, variables)?, r.lower(env, variables)?),
+                    _ => {
+                        return Err(EvalError::MalformedTree(format!(
+                            "No left or right for {}",
+                            name
+                        )))
+                    }
+                };
+                let fun = *fun;
+                return Ok(match name.as_str() {
+                    "+" => Box::new(move |b| Ok(l(b)? + r(b)?)),
+                    "-" => Box::new(move |b| Ok(l(b)? - r(b)?)),
+                    "*" => Box::new(move |b| Ok(l(b)? * r(b)?)),
+                    "/" => Box::new(move |b| {
+                        let d = r(b)?;
+                        if d == 0.0 {
+                            return Err(EvalError::DivisionByZero);
+                        }
+                        return Ok(l(b)? / d);
+                    }),
+                    "^" => Box::new(move |b| Ok(l(b)?.powf(r(b)?))),
+                    "<" => Box::new(move |b| Ok(bool_to_number(l(b)? < r(b)?))),
+                    "<=" => Box::new(move |b| Ok(bool_to_number(l(b)? <= r(b)?))),
+                    ">" => Box::new(move |b| Ok(bool_to_number(l(b)? > r(b)?))),
+                    ">=" => Box::new(move |b| Ok(bool_to_number(l(b)? >= r(b)?))),
+                    "==" => Box::new(move |b| Ok(bool_to_number(l(b)? == r(b)?))),
+                    "!=" => Box::new(move |b| Ok(bool_to_number(l(b)? != r(b)?))),
+                    "and" => Box::new(move |b| Ok(bool_to_number(truthy(l(b)?) && truthy(r(b)?)))),
+                    "or" => Box::new(move |b| Ok(bool_to_number(truthy(l(b)?) || truthy(r(b)?)))),
+                    // Numbers aren't allocated, so going through the Value version is fine
+                    _ => Box::new(move |b| {
+                        fun(Value::Number(l(b)?), Value::Number(r(b)?))?.as_number()
+                    }),
+                });
+            }
+            Token::Function { fun, name, .. } => {
+                let arg = match &self.left {
+                    Some(arg) => arg.lower(env, variables)?,
+                    None => return Err(EvalError::MalformedTree(format!("No left for {}", name))),
+                };
+                let fun = *fun;
+                return Ok(Box::new(move |b| fun(Value::Number(arg(b)?))?.as_number()));
+            }
+            Token::Keyword(name) => {
+                if let [cond, then, otherwise] = &self.args[..] {
+                    let cond = cond.lower(env, variables)?;
+                    let then = then.lower(env, variables)?;
+                    let otherwise = otherwise.lower(env, variables)?;
+                    return Ok(Box::new(move |b| {
+                        if truthy(cond(b)?) {
+                            return then(b);
+                        }
+                        return otherwise(b);
+                    }));
+                }
+                return Err(EvalError::MalformedTree(format!("Bad {}", name)));
+            }
+            Token::Call { name, .. } => match name.as_str() {
+                "piecewise" => {
+                    let mut pairs = vec![];
+                    let mut chunks = self.args.chunks_exact(2);
+                    for pair in &mut chunks {
+                        pairs.push((
+                            pair[0].lower(env, variables)?,
+                            pair[1].lower(env, variables)?,
+                        ));
+                    }
+                    let default = match chunks.remainder() {
+                        [default] => Some(default.lower(env, variables)?),
+                        _ => None,
+                    };
+                    return Ok(Box::new(move |b| {
+                        for (cond, value) in &pairs {
+                            if truthy(cond(b)?) {
+                                return value(b);
+                            }
+                        }
+                        return match &default {
+                            Some(default) => default(b),
+                            None => Err(EvalError::NoMatchingPiece),
+                        };
+                    }));
+                }
+                // The derivative is worked out once, here
+                "diff" => {
+                    let var = variable_arg(&self.args[1])?;
+                    return self.args[0].derivative(var)?.lower(env, variables);
+                }
+                // The mode is a name rather than a value, so it's worked out here
+                "round" | "round_sig" | "roundto" => {
+                    let mode = rounding_arg(self.args.get(2))?;
+                    let x = self.args[0].lower(env, variables)?;
+                    let n = match self.args.get(1) {
+                        Some(n) => n.lower(env, variables)?,
+                        None => constant(0.0),
+                    };
+                    let fun = match name.as_str() {
+                        "round" => math::round,
+                        "round_sig" => math::round_sig,
+                        _ => math::round_to,
+                    };
+                    return Ok(Box::new(move |b| fun(x(b)?, n(b)?, mode)));
+                }
+                // Things like `root(x^2 - 2, 0, 2)` have variables of their own,
+                // so they might not depend on the inputs at all
+                _ => {
+                    return match self.eval_with(env) {
+                        Ok(value) => Ok(constant(value.as_number()?)),
+                        Err(_) => Err(EvalError::NotCompilable(name.clone())),
+                    };
+                }
+            },
+            Token::LParen | Token::RParen | Token::LBracket | Token::RBracket | Token::Comma => {
+                return Err(EvalError::MalformedTree("Punctuation in tree".to_string()));
+            }
+        }
+    }
+}
+
+fn fill(n: f64) -> BatchNode {
+    return Box::new(move |_, out| {
+        out.fill(n);
+        return Ok(());
+    });
+}
+
+/// Runs both sides, then `op` on each pair of values.
+///
+/// `op` is a generic so that it's inlined into the loop, which is what lets it be vectorised.
+fn binary<F>(
+    l: BatchNode,
+    r: BatchNode,
+    check: fn(&[f64]) -> Result<(), EvalError>,
+    op: F,
+) -> BatchNode
+where
+    F: Fn(f64, f64) -> f64 + Send + Sync + 'static,
+{
+    return Box::new(move |columns, out| {
+        let mut right = [0.0; CHUNK];
+        let right = &mut right[..out.len()];
+        l(columns, out)?;
+        r(columns, right)?;
+        check(right)?;
+        for (a, b) in out.iter_mut().zip(right.iter()) {
+            *a = op(*a, *b);
+        }
+        return Ok(());
+    });
+}
+
+fn no_check(_: &[f64]) -> Result<(), EvalError> {
+    return Ok(());
+}
+
+fn nonzero(right: &[f64]) -> Result<(), EvalError> {
+    if right.contains(&0.0) {
+        return Err(EvalError::DivisionByZero);
+    }
+    return Ok(());
+}
+
+/// Runs a scalar node one row at a time, for things that can't work on whole chunks
+/// (like `if`, which must only evaluate the branch it picks)
+fn per_row(node: Node) -> BatchNode {
+    return Box::new(move |columns, out| {
+        // Once per chunk, not per row
+        let mut row = vec![0.0; columns.len()];
+        for (i, out) in out.iter_mut().enumerate() {
+            for (value, column) in row.iter_mut().zip(columns) {
+                *value = column[i];
+            }
+            *out = node(&row)?;
+        }
+        return Ok(());
+    });
+}
+
+impl TreeNode {
+    /** lower_batch
+
+    Algorithm:
+    1. Constants and variables fill the chunk or copy their column
+    2. Arithmetic and comparisons run both sides over the whole chunk, then combine them in one loop
+    3. Functions are applied to each value of the chunk
+    4. Anything that only evaluates some of its children, or adds a percentage, is lowered
+       with `lower` and run one row at a time
+    */
+    fn lower_batch(
+        &self,
+        env: &Environment,
+        variables: &mut Vec<String>,
+    ) -> Result<BatchNode, EvalError> {
+        if !self.has_free_variables(env) {
+            match self.eval_with(env) {
+                Ok(Value::Number(n)) => return Ok(fill(n)),
+                Ok(Value::Bool(b)) => return Ok(fill(bool_to_number(b))),
+                Ok(Value::Percent(p)) => return Ok(fill(p / 100.0)),
+                _ => {}
+            }
+        }
+
+        match &self.value {
+            Some(Token::Constant(value)) => {
+                return Ok(fill(value.as_number()?));
+            }
+            Some(Token::Variable(name)) => {
+                if let Some(value) = env.get(name) {
+                    return Ok(fill(value.as_number()?));
+                }
+                let i = match variables.iter().position(|v| v == name) {
+                    Some(i) => i,
+                    None => {
+                        variables.push(name.clone());
+                        variables.len() - 1
+                    }
+                };
+                return Ok(Box::new(move |columns, out| {
+                    out.copy_from_slice(columns[i]);
+                    return Ok(());
+                }));
+            }
+            Some(Token::Operator { name, .. })
+                if matches!(name.as_str(), "+" | "-")
+                    && self.right.as_ref().is_some_and(|r| r.is_percent(env)) =>
+            {
+                return Ok(per_row(self.lower(env, variables)?));
+            }
+            Some(Token::Operator { fun, name, .. }) if !matches!(name.as_str(), "and" | "or") => {
+                let (l, r) = match (&self.left, &self.right) {
+                    (Some(l), Some(r)) => (
+                        l.lower_batch(env, variables)?,
+                        r.lower_batch(env, variables)?,
+                    ),
+                    _ => {
+                        return Err(EvalError::MalformedTree(format!(
+                            "No left or right for {}",
+                            name
+                        )))
+                    }
+                };
+                let fun = *fun;
+                return Ok(match name.as_str() {
+                    "+" => binary(l, r, no_check, |a, b| a + b),
+                    "-" => binary(l, r, no_check, |a, b| a - b),
+                    "*" => binary(l, r, no_check, |a, b| a * b),
+                    "/" => binary(l, r, nonzero, |a, b| a / b),
+                    "^" => binary(l, r, no_check, f64::powf),
+                    "<" => binary(l, r, no_check, |a, b| bool_to_number(a < b)),
+                    "<=" => binary(l, r, no_check, |a, b| bool_to_number(a <= b)),
+                    ">" => binary(l, r, no_check, |a, b| bool_to_number(a > b)),
+                    ">=" => binary(l, r, no_check, |a, b| bool_to_number(a >= b)),
+                    "==" => binary(l, r, no_check, |a, b| bool_to_number(a == b)),
+                    "!=" => binary(l, r, no_check, |a, b| bool_to_number(a != b)),
+                    _ => Box::new(move |columns, out| {
+                        let mut right = [0.0; CHUNK];
+                        let right = &mut right[..out.len()];
+                        l(columns, out)?;
+                        r(columns, right)?;
+                        for (a, b) in out.iter_mut().zip(right.iter()) {
+                            *a = fun(Value::Number(*a), Value::Number(*b))?.as_number()?;
+                        }
+                        return Ok(());
+                    }),
+                });
+            }
+            Some(Token::Function { fun, name, .. }) => {
+                let arg = match &self.left {
+                    Some(arg) => arg.lower_batch(env, variables)?,
+                    None => return Err(EvalError::MalformedTree(format!("No left for {}", name))),
+                };
+                let fun = *fun;
+                return Ok(Box::new(move |columns, out| {
+                    arg(columns, out)?;
+                    for x in out.iter_mut() {
+                        *x = fun(Value::Number(*x))?.as_number()?;
+                    }
+                    return Ok(());
+                }));
+            }
+            Some(Token::Call { name, .. }) if name == "diff" => {
+                let var = variable_arg(&self.args[1])?;
+                return self.args[0].derivative(var)?.lower_batch(env, variables);
+            }
+            _ => {
+                return Ok(per_row(self.lower(env, variables)?));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{environment::Environment, error::parse_str, value::Value};
+
+    #[test]
+    fn inputs_in_order() {
+        let compiled = parse_str("x^2 + y").unwrap().compile().unwrap();
+        assert_eq!(compiled.variables(), ["x", "y"]);
+        assert_eq!(compiled.index_of("y"), Some(1));
+        assert_eq!(compiled.eval(&[3.0, 1.0]).unwrap(), 10.0);
+        assert!(compiled.eval(&[3.0]).is_err());
+    }
+
+    #[test]
+    fn set_variables_are_constants() {
+        let mut env = Environment::new();
+        env.set("a", Value::Number(2.0));
+        let compiled = parse_str("a * x").unwrap().compile_with(&env).unwrap();
+        assert_eq!(compiled.variables(), ["x"]);
+        assert_eq!(compiled.eval(&[5.0]).unwrap(), 10.0);
+    }
+
+    #[test]
+    fn branches_and_errors() {
+        let compiled = parse_str("if x > 0 then 1 / x else 0")
+            .unwrap()
+            .compile()
+            .unwrap();
+        assert_eq!(compiled.eval(&[0.0]).unwrap(), 0.0);
+        assert_eq!(compiled.eval(&[4.0]).unwrap(), 0.25);
+        let compiled = parse_str("1 / x").unwrap().compile().unwrap();
+        assert!(compiled.eval(&[0.0]).is_err());
+    }
+
+    #[test]
+    fn batches_match_single_rows() {
+        let compiled = parse_str("sin(x) * y + (x > y)")
+            .unwrap()
+            .compile()
+            .unwrap();
+        // More than a chunk, so the last chunk is a short one
+        let xs: Vec<f64> = (0..150).map(|i| i as f64 / 10.0).collect();
+        let ys: Vec<f64> = (0..150).map(|i| 15.0 - i as f64 / 7.0).collect();
+        let batch = compiled.eval_batch(&[&xs, &ys]).unwrap();
+        for i in 0..xs.len() {
+            assert_eq!(batch[i], compiled.eval(&[xs[i], ys[i]]).unwrap());
+        }
+        assert!(compiled.eval_batch(&[&xs, &ys[1..]]).is_err());
+    }
+
+    #[test]
+    fn percentages() {
+        let compiled = parse_str("x + 10%").unwrap().compile().unwrap();
+        assert_eq!(compiled.eval(&[200.0]).unwrap(), 220.0);
+        let compiled = parse_str("x - y% + 5").unwrap().compile().unwrap();
+        assert_eq!(compiled.eval(&[200.0, 10.0]).unwrap(), 185.0);
+        assert_eq!(compiled.eval_batch(&[&[200.0], &[10.0]]).unwrap(), [185.0]);
+        // Every one is the same as evaluating it
+        for input in ["x + 10% - 10%", "x - -10%", "x% + 5%", "x * 10%", "10% + x"] {
+            let tree = parse_str(input).unwrap();
+            let compiled = tree.compile().unwrap();
+            for x in [0.0, 200.0] {
+                let mut env = Environment::new();
+                env.set("x", Value::Number(x));
+                let expected = tree.eval_with(&env).unwrap().as_number().unwrap();
+                assert_eq!(compiled.eval(&[x]).unwrap(), expected, "{}", input);
+                assert_eq!(
+                    compiled.eval_batch(&[&[x]]).unwrap(),
+                    [expected],
+                    "{}",
+                    input
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,322 @@
+use crate::{parser::TreeNode, tokeniser::Token, value::EvalError};
+
+fn num(n: f64) -> TreeNode {
+    return TreeNode::number(n);
+}
+
+fn as_num(t: &TreeNode) -> Option<f64> {
+    return t.as_number();
+}
+
+fn is_num(t: &TreeNode, n: f64) -> bool {
+    return as_num(t) == Some(n);
+}
+
+fn op(name: &str, left: TreeNode, right: TreeNode) -> TreeNode {
+    return TreeNode::operator(name, left, right);
+}
+
+fn fun(name: &str, arg: TreeNode) -> TreeNode {
+    return TreeNode::function(name, arg);
+}
+
+// These skip the obvious `x + 0` and `x * 1` while building, so that the
+// derivative doesn't blow up in size before it's simplified
+
+fn add(a: TreeNode, b: TreeNode) -> TreeNode {
+    if is_num(&a, 0.0) {
+        return b;
+    }
+    if is_num(&b, 0.0) {
+        return a;
+    }
+    if let (Some(x), Some(y)) = (as_num(&a), as_num(&b)) {
+        return num(x + y);
+    }
+    return op("+", a, b);
+}
+
+fn sub(a: TreeNode, b: TreeNode) -> TreeNode {
+    if is_num(&b, 0.0) {
+        return a;
+    }
+    if let (Some(x), Some(y)) = (as_num(&a), as_num(&b)) {
+        return num(x - y);
+    }
+    return op("-", a, b);
+}
+
+fn neg(a: TreeNode) -> TreeNode {
+    return sub(num(0.0), a);
+}
+
+fn mul(a: TreeNode, b: TreeNode) -> TreeNode {
+    if is_num(&a, 0.0) || is_num(&b, 0.0) {
+        return num(0.0);
+    }
+    if is_num(&a, 1.0) {
+        return b;
+    }
+    if is_num(&b, 1.0) {
+        return a;
+    }
+    if let (Some(x), Some(y)) = (as_num(&a), as_num(&b)) {
+        return num(x * y);
+    }
+    return op("*", a, b);
+}
+
+fn div(a: TreeNode, b: TreeNode) -> TreeNode {
+    if is_num(&a, 0.0) {
+        return num(0.0);
+    }
+    if is_num(&b, 1.0) {
+        return a;
+    }
+    return op("/", a, b);
+}
+
+fn pow(a: TreeNode, b: TreeNode) -> TreeNode {
+    if is_num(&b, 0.0) {
+        return num(1.0);
+    }
+    if is_num(&b, 1.0) {
+        return a;
+    }
+    return op("^", a, b);
+}
+
+fn not_differentiable(name: &str) -> EvalError {
+    return EvalError::NotDifferentiable(name.to_string());
+}
+
+impl TreeNode {
+    /// Whether the variable appears anywhere in the tree
+    pub fn contains_variable(&self, var: &str) -> bool {
+        if let Some(Token::Variable(name)) = &self.value {
+            if name == var && !self.is_defined_call() {
+                return true;
+            }
+        }
+        return self.left.as_ref().is_some_and(|t| t.contains_variable(var))
+            || self
+                .right
+                .as_ref()
+                .is_some_and(|t| t.contains_variable(var))
+            || self.args.iter().any(|t| t.contains_variable(var));
+    }
+
+    /// The names of the variables in the tree, in the order they first show up
+    pub fn variables(&self) -> Vec<String> {
+        let mut names = vec![];
+        self.collect_variables(&mut names);
+        return names;
+    }
+
+    fn collect_variables(&self, names: &mut Vec<String>) {
+        if let Some(Token::Variable(name)) = &self.value {
+            if !names.contains(name) && !self.is_defined_call() {
+                names.push(name.clone());
+            }
+        }
+        for child in self.left.iter().chain(self.right.iter()) {
+            child.collect_variables(names);
+        }
+        for arg in &self.args {
+            arg.collect_variables(names);
+        }
+    }
+
+    /// The derivative of the expression with respect to `var`, as a new simplified tree.
+    ///
+    /// Every other variable is treated as a constant.
+    pub fn derivative(&self, var: &str) -> Result<TreeNode, EvalError> {
+        return Ok(self.derive(var)?.simplify());
+    }
+
+    fn derive(&self, var: &str) -> Result<TreeNode, EvalError> {
+        if !self.contains_variable(var) && !matches!(self.value, Some(Token::Call { .. })) {
+            return Ok(num(0.0));
+        }
+
+        let left = self.left.as_deref();
+        let right = self.right.as_deref();
+
+        match &self.value {
+            // Calls to defined functions have to be inlined first (see `inline_functions`)
+            Some(Token::Variable(name)) if self.is_defined_call() => {
+                return Err(not_differentiable(name));
+            }
+            Some(Token::Variable(_)) => {
+                // It contains the variable, so it is the variable
+                return Ok(num(1.0));
+            }
+            Some(Token::Operator { name, .. }) => {
+                let (u, v) = match (left, right) {
+                    (Some(u), Some(v)) => (u, v),
+                    _ => {
+                        return Err(EvalError::MalformedTree(format!(
+                            "No left or right for {}",
+                            name
+                        )))
+                    }
+                };
+                let du = u.derive(var)?;
+                let dv = v.derive(var)?;
+
+                return match name.as_str() {
+                    "+" => Ok(add(du, dv)),
+                    "-" => Ok(sub(du, dv)),
+                    // (uv)' = u'v + uv'
+                    "*" => Ok(add(mul(du, v.clone()), mul(u.clone(), dv))),
+                    // (u/v)' = (u'v - uv') / v^2
+                    "/" => Ok(div(
+                        sub(mul(du, v.clone()), mul(u.clone(), dv)),
+                        pow(v.clone(), num(2.0)),
+                    )),
+                    // u mod v = u - v * floor(u / v), and floor' is 0
+                    "mod" => Ok(sub(du, mul(dv, fun("floor", div(u.clone(), v.clone()))))),
+                    "^" => {
+                        if !v.contains_variable(var) {
+                            // (u^n)' = n u^(n-1) u'
+                            let n = match as_num(v) {
+                                Some(n) => num(n - 1.0),
+                                None => sub(v.clone(), num(1.0)),
+                            };
+                            Ok(mul(mul(v.clone(), pow(u.clone(), n)), du))
+                        } else if !u.contains_variable(var) {
+                            // (a^v)' = a^v ln(a) v'
+                            Ok(mul(mul(self.clone(), fun("ln", u.clone())), dv))
+                        } else {
+                            // (u^v)' = u^v (v' ln(u) + v u' / u)
+                            Ok(mul(
+                                self.clone(),
+                                add(
+                                    mul(dv, fun("ln", u.clone())),
+                                    div(mul(v.clone(), du), u.clone()),
+                                ),
+                            ))
+                        }
+                    }
+                    _ => Err(not_differentiable(name)),
+                };
+            }
+            Some(Token::Function { name, .. }) => {
+                let u = match left {
+                    Some(u) => u,
+                    None => return Err(EvalError::MalformedTree(format!("No left for {}", name))),
+                };
+                let du = u.derive(var)?;
+                let u = u.clone();
+
+                // The derivative of f(u) is f'(u) u'
+                let outer = match name.as_str() {
+                    "sin" => fun("cos", u),
+                    "cos" => neg(fun("sin", u)),
+                    "tan" => div(num(1.0), pow(fun("cos", u), num(2.0))),
+                    "asin" => div(num(1.0), fun("sqrt", sub(num(1.0), pow(u, num(2.0))))),
+                    "acos" => neg(div(num(1.0), fun("sqrt", sub(num(1.0), pow(u, num(2.0)))))),
+                    "atan" => div(num(1.0), add(num(1.0), pow(u, num(2.0)))),
+                    "sinh" => fun("cosh", u),
+                    "cosh" => fun("sinh", u),
+                    "tanh" => div(num(1.0), pow(fun("cosh", u), num(2.0))),
+                    "asinh" => div(num(1.0), fun("sqrt", add(pow(u, num(2.0)), num(1.0)))),
+                    "acosh" => div(num(1.0), fun("sqrt", sub(pow(u, num(2.0)), num(1.0)))),
+                    "atanh" => div(num(1.0), sub(num(1.0), pow(u, num(2.0)))),
+                    "sqrt" => div(num(1.0), mul(num(2.0), fun("sqrt", u))),
+                    "cbrt" => div(num(1.0), mul(num(3.0), pow(fun("cbrt", u), num(2.0)))),
+                    "exp" => fun("exp", u),
+                    "ln" => div(num(1.0), u),
+                    "log" => div(num(1.0), mul(u, fun("ln", num(10.0)))),
+                    "abs" => div(u.clone(), fun("abs", u)),
+                    // Flat everywhere except at the jumps
+                    "floor" | "ceil" | "trunc" | "sign" => num(0.0),
+                    // x - trunc(x)
+                    "frac" => num(1.0),
+                    _ => return Err(not_differentiable(name)),
+                };
+                return Ok(mul(outer, du));
+            }
+            Some(Token::Call { name, .. }) => match name.as_str() {
+                // diff(diff(x^3, x), x)
+                "diff" => {
+                    let inner = match &self.args[1].value {
+                        Some(Token::Variable(inner)) => inner,
+                        _ => return Err(EvalError::ExpectedVariable(self.args[1].to_string())),
+                    };
+                    return self.args[0].derive(inner)?.derive(var);
+                }
+                // Flat everywhere except at the jumps
+                "round" | "round_sig" | "roundto" => return Ok(num(0.0)),
+                // Conditions stay the same, only the values change
+                "piecewise" => {
+                    let mut args = vec![];
+                    for (i, arg) in self.args.iter().enumerate() {
+                        let is_condition = i % 2 == 0 && i + 1 < self.args.len();
+                        if is_condition {
+                            args.push(arg.clone());
+                        } else {
+                            args.push(arg.derive(var)?);
+                        }
+                    }
+                    return Ok(TreeNode::with_args(self.value.clone().unwrap(), args));
+                }
+                _ => {
+                    if !self.contains_variable(var) {
+                        return Ok(num(0.0));
+                    }
+                    return Err(not_differentiable(name));
+                }
+            },
+            Some(Token::Keyword(_)) => {
+                if let [cond, then, otherwise] = &self.args[..] {
+                    return Ok(TreeNode::with_args(
+                        self.value.clone().unwrap(),
+                        vec![cond.clone(), then.derive(var)?, otherwise.derive(var)?],
+                    ));
+                }
+                return Err(EvalError::MalformedTree("Bad if".to_string()));
+            }
+            _ => {
+                return Ok(num(0.0));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{environment::Environment, error::parse_str, value::Value};
+
+    fn diff(input: &str) -> String {
+        return parse_str(input).unwrap().eval().unwrap().to_string();
+    }
+
+    #[test]
+    fn rules() {
+        assert_eq!(diff("diff(x^3, x)"), "3 * x^2");
+        assert_eq!(
+            diff("diff(x^2 * sin(x), x)"),
+            "2 * x * sin(x) + x^2 * cos(x)"
+        );
+        assert_eq!(diff("diff(1 / x, x)"), "-1 / x^2");
+        assert_eq!(diff("diff(sin(x)^2, x)"), "2 * cos(x) * sin(x)");
+        assert_eq!(diff("diff(x^2, y)"), "0");
+        // The result is simplified, so `x / x` from the chain rule is gone
+        assert_eq!(diff("diff(x^x, x)"), "x^x * (ln(x) + 1)");
+        assert_eq!(diff("diff(x * ln(x), x)"), "ln(x) + 1");
+    }
+
+    #[test]
+    fn at_a_value() {
+        let mut env = Environment::new();
+        env.set("x", Value::Number(3.0));
+        let tree = parse_str("diff(x^2, x)").unwrap();
+        assert_eq!(tree.eval_with(&env).unwrap().as_number().unwrap(), 6.0);
+    }
+
+    #[test]
+    fn not_differentiable() {
+        assert!(parse_str("diff(x!, x)").unwrap().eval().is_err());
+    }
+}
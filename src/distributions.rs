@@ -0,0 +1,407 @@
+//! Probability distributions, as `pdf`, `cdf` and inverse `cdf` functions.
+//!
+//! They all take their arguments as a slice of numbers, in the same order as
+//! they're written (ex: `normcdf(x, mu, sigma)`), so they can be used with `builtins::numeric`.
+
+use std::f64::consts::PI;
+
+use crate::{
+    math::{beta_inc, erfc, gamma_p, gamma_q, ln_gamma},
+    value::EvalError,
+};
+
+/// The argument at `i`, or `default` if it wasn't given
+fn arg(args: &[f64], i: usize, default: f64) -> f64 {
+    return args.get(i).copied().unwrap_or(default);
+}
+
+fn domain(message: String) -> EvalError {
+    return EvalError::Domain(message);
+}
+
+fn probability(p: f64) -> Result<f64, EvalError> {
+    if !(0.0..=1.0).contains(&p) {
+        return Err(domain(format!("{} isn't a probability", p)));
+    }
+    return Ok(p);
+}
+
+/// The inverse cdf only gets to `p` at infinity
+fn infinite(p: f64) -> EvalError {
+    return domain(format!(
+        "The cdf is never {}, it only gets there at infinity",
+        p
+    ));
+}
+
+fn positive(name: &str, x: f64) -> Result<f64, EvalError> {
+    if x <= 0.0 || x.is_nan() {
+        return Err(domain(format!("{} has to be more than 0, not {}", name, x)));
+    }
+    return Ok(x);
+}
+
+/// A whole number that's at least 0, like the number of trials of a binomial
+fn whole(name: &str, x: f64) -> Result<f64, EvalError> {
+    if x < 0.0 || x.fract() != 0.0 {
+        return Err(domain(format!(
+            "{} has to be a whole number, not {}",
+            name, x
+        )));
+    }
+    return Ok(x);
+}
+
+/** invert
+
+The x where a continuous `cdf` is `p`, for a distribution that goes from `min` to `max`.
+
+Algorithm:
+1. If `p` is 0 or 1, it's `min` or `max`, unless that's infinite
+2. Start with `low` and `high`, and move them out until `p` is between their cdfs
+3. Cut the interval in half until it can't get any smaller
+*/
+fn invert(cdf: impl Fn(f64) -> f64, p: f64, min: f64, max: f64) -> Result<f64, EvalError> {
+    if p == 0.0 || p == 1.0 {
+        let end = if p == 0.0 { min } else { max };
+        if end.is_infinite() {
+            return Err(infinite(p));
+        }
+        return Ok(end);
+    }
+    let mut low = min.max(-1.0);
+    let mut high = max.min(1.0);
+    while cdf(low) > p && low.is_finite() {
+        low = low * 2.0 - 1.0;
+    }
+    while cdf(high) < p && high.is_finite() {
+        high = high * 2.0 + 1.0;
+    }
+    loop {
+        let middle = low + (high - low) / 2.0;
+        if middle <= low || middle >= high {
+            return Ok(middle);
+        }
+        if cdf(middle) < p {
+            low = middle;
+        } else {
+            high = middle;
+        }
+    }
+}
+
+/// The smallest whole k (up to `max`) where a discrete `cdf` is at least `p`
+fn invert_discrete(cdf: impl Fn(f64) -> f64, p: f64, max: f64) -> f64 {
+    let mut high = 1.0;
+    while high < max && cdf(high) < p {
+        high *= 2.0;
+    }
+    let mut high = high.min(max);
+
+    // The cdf is less than p at low (or it's -1) and at least p at high (or it's max)
+    let mut low = -1.0;
+    while high - low > 1.0 {
+        let middle = ((low + high) / 2.0_f64).floor();
+        if cdf(middle) < p {
+            low = middle;
+        } else {
+            high = middle;
+        }
+    }
+    return high;
+}
+
+// Normal
+
+fn normal(args: &[f64]) -> Result<(f64, f64, f64), EvalError> {
+    let mu = arg(args, 1, 0.0);
+    let sigma = positive("sigma", arg(args, 2, 1.0))?;
+    return Ok((args[0], mu, sigma));
+}
+
+fn standard_normal_cdf(z: f64) -> f64 {
+    return 0.5 * erfc(-z / std::f64::consts::SQRT_2);
+}
+
+/// `normpdf(x, mu, sigma)`, with the standard normal if mu and sigma aren't given
+pub fn normpdf(args: &[f64]) -> Result<f64, EvalError> {
+    let (x, mu, sigma) = normal(args)?;
+    let z = (x - mu) / sigma;
+    return Ok((-z * z / 2.0).exp() / (sigma * (2.0 * PI).sqrt()));
+}
+
+/// `normcdf(x, mu, sigma)`
+pub fn normcdf(args: &[f64]) -> Result<f64, EvalError> {
+    let (x, mu, sigma) = normal(args)?;
+    return Ok(standard_normal_cdf((x - mu) / sigma));
+}
+
+/// `invnorm(p, mu, sigma)`
+pub fn invnorm(args: &[f64]) -> Result<f64, EvalError> {
+    let (p, mu, sigma) = normal(args)?;
+    let z = invert(
+        standard_normal_cdf,
+        probability(p)?,
+        f64::NEG_INFINITY,
+        f64::INFINITY,
+    )?;
+    return Ok(mu + sigma * z);
+}
+
+// Binomial
+
+fn binomial(args: &[f64]) -> Result<(f64, f64, f64), EvalError> {
+    return Ok((whole("n", args[0])?, probability(args[1])?, args[2]));
+}
+
+fn binomial_cdf(n: f64, p: f64, k: f64) -> f64 {
+    let k = k.floor();
+    if k < 0.0 {
+        return 0.0;
+    }
+    if k >= n {
+        return 1.0;
+    }
+    return beta_inc(n - k, k + 1.0, 1.0 - p);
+}
+
+/// `binompdf(n, p, k)`, the chance of exactly k successes in n trials
+pub fn binompdf(args: &[f64]) -> Result<f64, EvalError> {
+    let (n, p, k) = binomial(args)?;
+    if k < 0.0 || k > n || k.fract() != 0.0 {
+        return Ok(0.0);
+    }
+    // 0^0 is 1 here, so these are done separately to avoid ln(0)
+    if p == 0.0 || p == 1.0 {
+        let certain = if p == 0.0 { 0.0 } else { n };
+        return Ok(if k == certain { 1.0 } else { 0.0 });
+    }
+    let ln_choose = ln_gamma(n + 1.0) - ln_gamma(k + 1.0) - ln_gamma(n - k + 1.0);
+    return Ok((ln_choose + k * p.ln() + (n - k) * (1.0 - p).ln()).exp());
+}
+
+/// `binomcdf(n, p, k)`, the chance of at most k successes in n trials
+pub fn binomcdf(args: &[f64]) -> Result<f64, EvalError> {
+    let (n, p, k) = binomial(args)?;
+    return Ok(binomial_cdf(n, p, k));
+}
+
+/// `invbinom(q, n, p)`, the smallest k where `binomcdf(n, p, k)` is at least q
+pub fn invbinom(args: &[f64]) -> Result<f64, EvalError> {
+    let q = probability(args[0])?;
+    let n = whole("n", args[1])?;
+    let p = probability(args[2])?;
+    return Ok(invert_discrete(|k| binomial_cdf(n, p, k), q, n));
+}
+
+// Poisson
+
+/// `poissonpdf(lambda, k)`
+pub fn poissonpdf(args: &[f64]) -> Result<f64, EvalError> {
+    let lambda = positive("lambda", args[0])?;
+    let k = args[1];
+    if k < 0.0 || k.fract() != 0.0 {
+        return Ok(0.0);
+    }
+    return Ok((k * lambda.ln() - lambda - ln_gamma(k + 1.0)).exp());
+}
+
+fn poisson_cdf(lambda: f64, k: f64) -> f64 {
+    let k = k.floor();
+    if k < 0.0 {
+        return 0.0;
+    }
+    return gamma_q(k + 1.0, lambda);
+}
+
+/// `poissoncdf(lambda, k)`
+pub fn poissoncdf(args: &[f64]) -> Result<f64, EvalError> {
+    let lambda = positive("lambda", args[0])?;
+    return Ok(poisson_cdf(lambda, args[1]));
+}
+
+/// `invpoisson(q, lambda)`
+pub fn invpoisson(args: &[f64]) -> Result<f64, EvalError> {
+    let q = probability(args[0])?;
+    let lambda = positive("lambda", args[1])?;
+    return Ok(invert_discrete(
+        |k| poisson_cdf(lambda, k),
+        q,
+        f64::INFINITY,
+    ));
+}
+
+// Exponential
+
+/// `exppdf(x, lambda)`
+pub fn exppdf(args: &[f64]) -> Result<f64, EvalError> {
+    let lambda = positive("lambda", args[1])?;
+    if args[0] < 0.0 {
+        return Ok(0.0);
+    }
+    return Ok(lambda * (-lambda * args[0]).exp());
+}
+
+/// `expcdf(x, lambda)`
+pub fn expcdf(args: &[f64]) -> Result<f64, EvalError> {
+    let lambda = positive("lambda", args[1])?;
+    if args[0] < 0.0 {
+        return Ok(0.0);
+    }
+    return Ok(-(-lambda * args[0]).exp_m1());
+}
+
+/// `invexp(p, lambda)`
+pub fn invexp(args: &[f64]) -> Result<f64, EvalError> {
+    let p = probability(args[0])?;
+    let lambda = positive("lambda", args[1])?;
+    if p == 1.0 {
+        return Err(infinite(p));
+    }
+    return Ok(-(-p).ln_1p() / lambda);
+}
+
+// Uniform
+
+fn uniform(args: &[f64]) -> Result<(f64, f64, f64), EvalError> {
+    let (a, b) = (args[1], args[2]);
+    if a >= b {
+        return Err(domain(format!("{} has to be less than {}", a, b)));
+    }
+    return Ok((args[0], a, b));
+}
+
+/// `unifpdf(x, a, b)`
+pub fn unifpdf(args: &[f64]) -> Result<f64, EvalError> {
+    let (x, a, b) = uniform(args)?;
+    if x < a || x > b {
+        return Ok(0.0);
+    }
+    return Ok(1.0 / (b - a));
+}
+
+/// `unifcdf(x, a, b)`
+pub fn unifcdf(args: &[f64]) -> Result<f64, EvalError> {
+    let (x, a, b) = uniform(args)?;
+    return Ok(((x - a) / (b - a)).clamp(0.0, 1.0));
+}
+
+/// `invunif(p, a, b)`
+pub fn invunif(args: &[f64]) -> Result<f64, EvalError> {
+    let (p, a, b) = uniform(args)?;
+    return Ok(a + probability(p)? * (b - a));
+}
+
+// Student's t
+
+fn t_cdf(x: f64, df: f64) -> f64 {
+    let tail = beta_inc(df / 2.0, 0.5, df / (df + x * x)) / 2.0;
+    return if x > 0.0 { 1.0 - tail } else { tail };
+}
+
+/// `tpdf(x, df)`
+pub fn tpdf(args: &[f64]) -> Result<f64, EvalError> {
+    let (x, df) = (args[0], positive("df", args[1])?);
+    let ln_front = ln_gamma((df + 1.0) / 2.0) - ln_gamma(df / 2.0) - 0.5 * (df * PI).ln();
+    return Ok((ln_front - (df + 1.0) / 2.0 * (x * x / df).ln_1p()).exp());
+}
+
+/// `tcdf(x, df)`
+pub fn tcdf(args: &[f64]) -> Result<f64, EvalError> {
+    return Ok(t_cdf(args[0], positive("df", args[1])?));
+}
+
+/// `invt(p, df)`
+pub fn invt(args: &[f64]) -> Result<f64, EvalError> {
+    let (p, df) = (probability(args[0])?, positive("df", args[1])?);
+    return invert(|x| t_cdf(x, df), p, f64::NEG_INFINITY, f64::INFINITY);
+}
+
+// Chi-squared
+
+fn chi2_cdf(x: f64, df: f64) -> f64 {
+    return gamma_p(df / 2.0, x / 2.0);
+}
+
+/// `chi2pdf(x, df)`
+pub fn chi2pdf(args: &[f64]) -> Result<f64, EvalError> {
+    let (x, k) = (args[0], positive("df", args[1])?);
+    if x < 0.0 {
+        return Ok(0.0);
+    }
+    if x == 0.0 {
+        // The limit, which ln(0) can't give
+        if k < 2.0 {
+            return Ok(f64::INFINITY);
+        }
+        return Ok(if k == 2.0 { 0.5 } else { 0.0 });
+    }
+    let ln_pdf = (k / 2.0 - 1.0) * x.ln() - x / 2.0 - k / 2.0 * 2f64.ln() - ln_gamma(k / 2.0);
+    return Ok(ln_pdf.exp());
+}
+
+/// `chi2cdf(x, df)`
+pub fn chi2cdf(args: &[f64]) -> Result<f64, EvalError> {
+    return Ok(chi2_cdf(args[0], positive("df", args[1])?));
+}
+
+/// `invchi2(p, df)`
+pub fn invchi2(args: &[f64]) -> Result<f64, EvalError> {
+    let (p, df) = (probability(args[0])?, positive("df", args[1])?);
+    return invert(|x| chi2_cdf(x, df), p, 0.0, f64::INFINITY);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        return (a - b).abs() < 1e-9;
+    }
+
+    #[test]
+    fn normal() {
+        assert!(close(normpdf(&[0.0]).unwrap(), 1.0 / (2.0 * PI).sqrt()));
+        assert!(close(normcdf(&[1.96]).unwrap(), 0.9750021048517795));
+        assert!(close(
+            normcdf(&[115.0, 100.0, 15.0]).unwrap(),
+            normcdf(&[1.0]).unwrap()
+        ));
+        assert!(close(invnorm(&[0.975]).unwrap(), 1.959963984540054));
+        assert!(normcdf(&[0.0, 0.0, -1.0]).is_err());
+        assert!(invnorm(&[1.5]).is_err());
+        // The quantiles at 0 and 1 are infinite, like any other probability out of range
+        assert!(matches!(invnorm(&[0.0]), Err(EvalError::Domain(_))));
+        assert!(matches!(invnorm(&[1.0]), Err(EvalError::Domain(_))));
+    }
+
+    #[test]
+    fn discrete() {
+        assert!(close(binompdf(&[10.0, 0.5, 5.0]).unwrap(), 252.0 / 1024.0));
+        assert!(close(binomcdf(&[10.0, 0.5, 5.0]).unwrap(), 638.0 / 1024.0));
+        assert_eq!(invbinom(&[0.5, 10.0, 0.5]).unwrap(), 5.0);
+        assert!(close(
+            poissonpdf(&[2.0, 3.0]).unwrap(),
+            8.0 * (-2f64).exp() / 6.0
+        ));
+        assert!(close(poissoncdf(&[2.0, 0.0]).unwrap(), (-2f64).exp()));
+        assert!(binompdf(&[10.5, 0.5, 2.0]).is_err());
+        assert!(binompdf(&[10.0, 1.5, 2.0]).is_err());
+    }
+
+    #[test]
+    fn continuous() {
+        assert!(close(expcdf(&[1.0, 2.0]).unwrap(), 1.0 - (-2f64).exp()));
+        assert!(close(invexp(&[0.5, 1.0]).unwrap(), 2f64.ln()));
+        assert!(close(unifcdf(&[3.0, 2.0, 6.0]).unwrap(), 0.25));
+        assert!(close(invunif(&[0.25, 2.0, 6.0]).unwrap(), 3.0));
+        assert!(close(tcdf(&[0.0, 5.0]).unwrap(), 0.5));
+        assert!(close(invt(&[0.975, 10.0]).unwrap(), 2.2281388519649385));
+        assert!(close(chi2cdf(&[2.0, 2.0]).unwrap(), 1.0 - (-1f64).exp()));
+        assert!(close(invchi2(&[0.95, 1.0]).unwrap(), 3.841458820694124));
+        assert_eq!(invchi2(&[0.0, 1.0]).unwrap(), 0.0);
+        assert!(invchi2(&[1.0, 1.0]).is_err());
+        assert!(invexp(&[1.0, 1.0]).is_err());
+        assert!(invt(&[0.0, 10.0]).is_err());
+    }
+}
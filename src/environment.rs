@@ -0,0 +1,219 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    parser::TreeNode,
+    value::{EvalError, Value},
+};
+
+/// How many calls to defined functions can be inside each other, so `f(x) = f(x)` fails
+/// instead of overflowing the stack
+const MAX_DEPTH: usize = 200;
+
+/// The error for calling a defined function with the wrong number of arguments
+pub(crate) fn arity_error(name: &str, params: usize, args: usize) -> EvalError {
+    return EvalError::Domain(format!(
+        "{} takes {} argument{}, not {}",
+        name,
+        params,
+        if params == 1 { "" } else { "s" },
+        args
+    ));
+}
+
+/// A function defined like `f(x, y) = x^2 + y`
+#[derive(Debug, Clone)]
+pub struct Function {
+    pub params: Vec<String>,
+    pub body: TreeNode,
+}
+
+/// The variables (and defined functions) an expression is evaluated with.
+///
+/// The variables and functions are shared between an environment and its scopes (see
+/// `scope`), and only copied when one of them changes them with `set` or `define`.
+#[derive(Debug, Clone, Default)]
+pub struct Environment {
+    variables: Arc<HashMap<String, Value>>,
+    functions: Arc<HashMap<String, Function>>,
+    /// The parameters of the functions being called and the variables of things like `sum`,
+    /// which hide the variables with the same name
+    locals: Vec<(String, Value)>,
+    /// What `now` is, in seconds since 1970-01-01 in UTC, instead of the system clock
+    clock: Option<f64>,
+    /// How many defined functions are being called, inside each other
+    depth: usize,
+    /// Set from another thread to stop the evaluation early
+    cancel: Option<Arc<AtomicBool>>,
+}
+
+impl Environment {
+    pub fn new() -> Environment {
+        return Environment::default();
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        let local = self.locals.iter().find(|(local, _)| local == name);
+        return local
+            .map(|(_, value)| value)
+            .or_else(|| self.variables.get(name));
+    }
+
+    pub fn set(&mut self, name: &str, value: Value) {
+        self.locals.retain(|(local, _)| local != name);
+        Arc::make_mut(&mut self.variables).insert(name.to_string(), value);
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.locals.retain(|(local, _)| local != name);
+        if self.variables.contains_key(name) {
+            Arc::make_mut(&mut self.variables).remove(name);
+        }
+    }
+
+    /// An environment for evaluating something with a few more variables (see `bind`),
+    /// which shares everything else with this one instead of copying it
+    pub fn scope(&self) -> Environment {
+        return Environment {
+            variables: Arc::clone(&self.variables),
+            functions: Arc::clone(&self.functions),
+            locals: self.locals.clone(),
+            clock: self.clock,
+            depth: self.depth,
+            cancel: self.cancel.clone(),
+        };
+    }
+
+    /// Sets a variable only in this scope, without copying the others like `set` might
+    pub fn bind(&mut self, name: &str, value: Value) {
+        match self.locals.iter_mut().find(|(local, _)| local == name) {
+            Some((_, local)) => *local = value,
+            None => self.locals.push((name.to_string(), value)),
+        }
+    }
+
+    /// The names of the variables, in no particular order
+    pub fn variables(&self) -> impl Iterator<Item = (&String, &Value)> {
+        let hidden = |name: &String| self.locals.iter().any(|(local, _)| local == name);
+        let variables = self.variables.iter().filter(move |(name, _)| !hidden(name));
+        return variables.chain(self.locals.iter().map(|(name, value)| (name, value)));
+    }
+
+    pub fn function(&self, name: &str) -> Option<&Function> {
+        return self.functions.get(name);
+    }
+
+    /// Defines (or replaces) a function that can be called like `f(1, 2)`
+    pub fn define(&mut self, name: &str, params: Vec<String>, body: TreeNode) {
+        Arc::make_mut(&mut self.functions).insert(name.to_string(), Function { params, body });
+    }
+
+    pub fn undefine(&mut self, name: &str) {
+        if self.functions.contains_key(name) {
+            Arc::make_mut(&mut self.functions).remove(name);
+        }
+    }
+
+    pub fn functions(&self) -> impl Iterator<Item = (&String, &Function)> {
+        return self.functions.iter();
+    }
+
+    /// The environment to evaluate the body of a function in, with its parameters set
+    pub fn call(
+        &self,
+        name: &str,
+        args: Vec<Value>,
+    ) -> Result<(Environment, &TreeNode), EvalError> {
+        let function = match self.functions.get(name) {
+            Some(function) => function,
+            None => return Err(EvalError::UnknownFunction(name.to_string())),
+        };
+        if args.len() != function.params.len() {
+            return Err(arity_error(name, function.params.len(), args.len()));
+        }
+        if self.depth >= MAX_DEPTH {
+            return Err(EvalError::Domain(format!(
+                "{} calls itself more than {} times",
+                name, MAX_DEPTH
+            )));
+        }
+        let mut inner = self.scope();
+        inner.depth += 1;
+        for (param, value) in function.params.iter().zip(args) {
+            inner.bind(param, value);
+        }
+        return Ok((inner, &function.body));
+    }
+
+    /// Makes evaluating with this environment fail with `Cancelled` once the flag is set
+    pub fn set_cancel(&mut self, cancel: Option<Arc<AtomicBool>>) {
+        self.cancel = cancel;
+    }
+
+    pub fn check_cancelled(&self) -> Result<(), EvalError> {
+        if self
+            .cancel
+            .as_ref()
+            .is_some_and(|c| c.load(Ordering::Relaxed))
+        {
+            return Err(EvalError::Cancelled);
+        }
+        return Ok(());
+    }
+
+    /// Makes `now` and `today` always use this time, so results don't change from run to run
+    pub fn set_clock(&mut self, now: f64) {
+        self.clock = Some(now);
+    }
+
+    /// The current time in seconds since 1970-01-01 in UTC
+    pub fn now(&self) -> f64 {
+        if let Some(now) = self.clock {
+            return now;
+        }
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH);
+        return since_epoch.map_or(0.0, |t| t.as_secs_f64());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::parse_str;
+
+    #[test]
+    fn scopes_share_variables() {
+        let mut env = Environment::new();
+        env.set("x", Value::Number(1.0));
+        env.set("y", Value::Number(2.0));
+        let mut scope = env.scope();
+        scope.bind("x", Value::Number(3.0));
+        assert!(Arc::ptr_eq(&env.variables, &scope.variables));
+        assert_eq!(scope.get("x").unwrap().as_number().ok(), Some(3.0));
+        assert_eq!(scope.variables().count(), 2);
+        // The environment it came from doesn't change
+        assert_eq!(env.get("x").unwrap().as_number().ok(), Some(1.0));
+        scope.set("z", Value::Number(4.0));
+        assert!(env.get("z").is_none());
+    }
+
+    #[test]
+    fn calls_bind_their_parameters() {
+        let mut env = Environment::new();
+        env.set("x", Value::Number(10.0));
+        env.define("f", vec!["x".to_string()], parse_str("x + 1").unwrap());
+        let (inner, _) = env.call("f", vec![Value::Number(1.0)]).unwrap();
+        assert!(Arc::ptr_eq(&env.variables, &inner.variables));
+        assert!(Arc::ptr_eq(&env.functions, &inner.functions));
+        assert_eq!(inner.get("x").unwrap().as_number().ok(), Some(1.0));
+        let tree = parse_str("f(2) + x").unwrap();
+        assert_eq!(tree.eval_with(&env).unwrap().as_number().ok(), Some(13.0));
+        assert!(matches!(env.call("f", vec![]), Err(EvalError::Domain(_))));
+    }
+}
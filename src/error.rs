@@ -0,0 +1,139 @@
+use std::fmt::Display;
+
+use crate::{
+    environment::Environment,
+    parser::{parse_with_position, ParseError, TreeNode},
+    tokeniser::{tokenise_with_spans, Span, Token, TokenError},
+    value::{EvalError, Value},
+};
+
+/// Anything that can go wrong going from the text of an expression to its value
+#[derive(Debug)]
+pub enum Error {
+    Token(TokenError),
+    /// The error and the span of the token it's about
+    Parse(ParseError, Span),
+    /// The error and the span of the name it's about, if it's about one (see `Error::locate`)
+    Eval(EvalError, Option<Span>),
+}
+
+impl Error {
+    /// A name for the kind of error that won't change, like `unclosed_lparen`
+    /// or `division_by_zero`, for programs to check instead of the message
+    pub fn kind(&self) -> &'static str {
+        return match self {
+            Error::Token(e) => e.kind(),
+            Error::Parse(e, _) => e.kind(),
+            Error::Eval(e, _) => e.kind(),
+        };
+    }
+
+    /// Where in the input it went wrong, in characters.
+    /// Evaluation errors only have one if they've been located
+    pub fn span(&self) -> Option<Span> {
+        return match self {
+            Error::Token(e) => Some(e.span()),
+            Error::Parse(_, span) => Some(span.clone()),
+            Error::Eval(_, span) => span.clone(),
+        };
+    }
+
+    /// Whether the input couldn't be read at all, as opposed to not being evaluated
+    pub fn is_syntax(&self) -> bool {
+        return !matches!(self, Error::Eval(..));
+    }
+
+    /// Gives an evaluation error about a variable or function the span of that name in
+    /// the input it came from (ex: `y` in `x + y` for an unknown variable `y`)
+    pub fn locate(self, input: &str) -> Error {
+        let span = match &self {
+            Error::Eval(EvalError::UnknownVariable(name), None) => name_span(input, name, false),
+            Error::Eval(EvalError::UnknownFunction(name), None) => name_span(input, name, true),
+            _ => return self,
+        };
+        return match self {
+            Error::Eval(e, _) => Error::Eval(e, span),
+            _ => self,
+        };
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return match self {
+            Error::Token(e) => write!(f, "{}", e),
+            Error::Parse(e, _) => write!(f, "{}", e),
+            Error::Eval(e, _) => write!(f, "{}", e),
+        };
+    }
+}
+
+impl From<EvalError> for Error {
+    fn from(e: EvalError) -> Error {
+        return Error::Eval(e, None);
+    }
+}
+
+/// Tokenises and parses the input, with the span of the problem if it can't be
+pub fn parse_str(input: &str) -> Result<TreeNode, Error> {
+    let (tokens, spans) = tokenise_with_spans(input).map_err(Error::Token)?;
+    return parse_with_position(&tokens).map_err(|(e, at)| {
+        let end = input.chars().count();
+        Error::Parse(e, spans.get(at).cloned().unwrap_or(end..end))
+    });
+}
+
+/// Parses and evaluates the input, with the span of the problem if it can't be
+pub fn eval_str(input: &str, env: &Environment) -> Result<Value, Error> {
+    let tree = parse_str(input)?;
+    return tree
+        .eval_with(env)
+        .map_err(|e| Error::from(e).locate(input));
+}
+
+/// Where the variable or function called `name` first is in the input.
+/// With `call`, it's where it's first called (ex: `f` in `f + f(2)`), if it ever is.
+pub(crate) fn name_span(input: &str, name: &str, call: bool) -> Option<Span> {
+    let (tokens, spans) = tokenise_with_spans(input).ok()?;
+    let is_name = |i: usize| matches!(&tokens[i], Token::Variable(n) if n == name);
+    let called =
+        (0..tokens.len()).find(|i| is_name(*i) && matches!(tokens.get(i + 1), Some(Token::LParen)));
+    let first = (0..tokens.len()).find(|i| is_name(*i));
+    let i = if call { called.or(first) } else { first }?;
+    return Some(spans[i].clone());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(input: &str) -> Option<Span> {
+        return eval_str(input, &Environment::new()).unwrap_err().span();
+    }
+
+    #[test]
+    fn syntax_errors_have_spans() {
+        assert_eq!(span("1 + (2"), Some(6..6));
+        assert_eq!(span("1 $ 2"), Some(2..3));
+    }
+
+    #[test]
+    fn unknown_names_have_spans() {
+        assert_eq!(span("1 + x * 2"), Some(4..5));
+        assert_eq!(span("1 + foo(2)"), Some(4..7));
+        // The call, not the variable with the same name
+        assert_eq!(span("f + f(2)"), Some(0..1));
+        let mut env = Environment::new();
+        env.set("f", Value::Number(1.0));
+        let error = eval_str("f + f(2)", &env).unwrap_err();
+        assert_eq!(error.kind(), "unknown_function");
+        assert_eq!(error.span(), Some(4..5));
+    }
+
+    #[test]
+    fn other_evaluation_errors_do_not() {
+        let error = eval_str("1 / 0", &Environment::new()).unwrap_err();
+        assert!(!error.is_syntax());
+        assert_eq!(error.span(), None);
+    }
+}
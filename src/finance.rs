@@ -0,0 +1,294 @@
+//! Time value of money, with the same arguments and sign conventions as spreadsheets.
+//!
+//! Money paid out is negative and money received is positive, so borrowing 1000 is a
+//! present value of 1000 and paying it back is a negative payment. `type` is 0 when
+//! payments are made at the end of each period (the default) and 1 when they're made at
+//! the start.
+
+use crate::value::{EvalError, Value};
+
+/// How many steps `rate` and `irr` can take before giving up
+const MAX_ITERATIONS: usize = 200;
+
+/// How many rates `find_rate` tries when looking for a sign change
+const STEPS: usize = 2000;
+
+/// The argument at `i`, or `default` if it wasn't given
+fn arg(args: &[f64], i: usize, default: f64) -> f64 {
+    return args.get(i).copied().unwrap_or(default);
+}
+
+fn payment_type(args: &[f64], i: usize) -> Result<f64, EvalError> {
+    let t = arg(args, i, 0.0);
+    if t != 0.0 && t != 1.0 {
+        return Err(EvalError::Domain(format!(
+            "The payment type has to be 0 (end of the period) or 1 (start), not {}",
+            t
+        )));
+    }
+    return Ok(t);
+}
+
+/// What a payment each period grows to after `nper` periods, as a multiple of the payment
+fn annuity(rate: f64, nper: f64, t: f64) -> f64 {
+    if rate == 0.0 {
+        return nper;
+    }
+    return (1.0 + rate * t) * ((1.0 + rate).powf(nper) - 1.0) / rate;
+}
+
+/// The amount left over when everything is moved to the end, which is 0 for the right values
+fn balance(rate: f64, nper: f64, pmt: f64, pv: f64, fv: f64, t: f64) -> f64 {
+    return pv * (1.0 + rate).powf(nper) + pmt * annuity(rate, nper, t) + fv;
+}
+
+/// `pv(rate, nper, pmt, fv, type)`, what a series of payments is worth now
+pub fn pv(args: &[f64]) -> Result<f64, EvalError> {
+    let (rate, nper, pmt) = (args[0], args[1], args[2]);
+    let (fv, t) = (arg(args, 3, 0.0), payment_type(args, 4)?);
+    return Ok(-(fv + pmt * annuity(rate, nper, t)) / (1.0 + rate).powf(nper));
+}
+
+/// `fv(rate, nper, pmt, pv, type)`, what a series of payments is worth at the end
+pub fn fv(args: &[f64]) -> Result<f64, EvalError> {
+    let (rate, nper, pmt) = (args[0], args[1], args[2]);
+    let (pv, t) = (arg(args, 3, 0.0), payment_type(args, 4)?);
+    return Ok(-balance(rate, nper, pmt, pv, 0.0, t));
+}
+
+/// `pmt(rate, nper, pv, fv, type)`, the payment each period (ex: of a loan)
+pub fn pmt(args: &[f64]) -> Result<f64, EvalError> {
+    let (rate, nper, pv) = (args[0], args[1], args[2]);
+    let (fv, t) = (arg(args, 3, 0.0), payment_type(args, 4)?);
+    let annuity = annuity(rate, nper, t);
+    if annuity == 0.0 {
+        return Err(EvalError::DivisionByZero);
+    }
+    return Ok(-(pv * (1.0 + rate).powf(nper) + fv) / annuity);
+}
+
+/// `nper(rate, pmt, pv, fv, type)`, how many periods it takes to pay off
+pub fn nper(args: &[f64]) -> Result<f64, EvalError> {
+    let (rate, pmt, pv) = (args[0], args[1], args[2]);
+    let (fv, t) = (arg(args, 3, 0.0), payment_type(args, 4)?);
+    if rate == 0.0 {
+        if pmt == 0.0 {
+            return Err(EvalError::Domain(
+                "With no rate and no payments, the balance never changes".to_string(),
+            ));
+        }
+        return Ok(-(pv + fv) / pmt);
+    }
+    // Solving the balance for (1 + rate)^nper
+    let payment = pmt * (1.0 + rate * t) / rate;
+    let growth = (payment - fv) / (payment + pv);
+    if growth <= 0.0 || !growth.is_finite() {
+        return Err(EvalError::Domain(
+            "Those payments never pay it off".to_string(),
+        ));
+    }
+    return Ok(growth.ln() / rate.ln_1p());
+}
+
+/** find_rate
+
+The rate (above -1) where `f` is 0.
+
+Algorithm:
+1. Try Newton's method from `guess`, with the derivative worked out numerically
+2. If that goes below -1 or doesn't settle down, look for a sign change between -0.99
+   and 1000 (spaced out evenly in ln(1 + rate)) and cut it in half until it's small enough
+*/
+fn find_rate(f: impl Fn(f64) -> f64, guess: f64, name: &str) -> Result<f64, EvalError> {
+    let mut r = guess;
+    for _ in 0..MAX_ITERATIONS {
+        let h = 1e-6 * (1.0 + r.abs());
+        let slope = (f(r + h) - f(r - h)) / (2.0 * h);
+        let next = r - f(r) / slope;
+        if !next.is_finite() || next <= -1.0 {
+            break;
+        }
+        if (next - r).abs() <= 1e-12 * (1.0 + next.abs()) {
+            return Ok(next);
+        }
+        r = next;
+    }
+
+    let (low, high) = (0.01_f64.ln(), 1001_f64.ln());
+    let rate = |i: usize| (low + (high - low) * i as f64 / STEPS as f64).exp() - 1.0;
+    for i in 0..STEPS {
+        let (mut a, mut b) = (rate(i), rate(i + 1));
+        if f(a).signum() == f(b).signum() {
+            continue;
+        }
+        for _ in 0..MAX_ITERATIONS {
+            let middle = (a + b) / 2.0;
+            if middle <= a || middle >= b {
+                break;
+            }
+            if f(middle).signum() == f(a).signum() {
+                a = middle;
+            } else {
+                b = middle;
+            }
+        }
+        return Ok((a + b) / 2.0);
+    }
+    return Err(EvalError::TooManyIterations(name.to_string()));
+}
+
+/// `rate(nper, pmt, pv, fv, type, guess)`, the interest rate per period
+pub fn rate(args: &[f64]) -> Result<f64, EvalError> {
+    let (nper, pmt, pv) = (args[0], args[1], args[2]);
+    let (fv, t) = (arg(args, 3, 0.0), payment_type(args, 4)?);
+    let guess = arg(args, 5, 0.1);
+    return find_rate(|r| balance(r, nper, pmt, pv, fv, t), guess, "rate");
+}
+
+/// `npv(rate, values)`, where the first value is one period from now
+pub fn npv(rate: f64, values: &[f64]) -> f64 {
+    let mut discount = 1.0;
+    let mut total = 0.0;
+    for value in values {
+        discount /= 1.0 + rate;
+        total += value * discount;
+    }
+    return total;
+}
+
+/// `irr(values)`, the rate where the npv of the values (with the first one now) is 0
+pub fn irr(values: &[f64]) -> Result<f64, EvalError> {
+    let has_sign = |positive: bool| values.iter().any(|v| (*v > 0.0) == positive && *v != 0.0);
+    if !has_sign(true) || !has_sign(false) {
+        return Err(EvalError::Domain(
+            "irr needs at least one positive and one negative value".to_string(),
+        ));
+    }
+    // `npv` starts a period from now, so this is the npv from now
+    return find_rate(|r| npv(r, values) * (1.0 + r), 0.1, "irr");
+}
+
+/// `compound(principal, rate, years, n)`, with the yearly rate compounded n times a year
+/// (once if n isn't given)
+pub fn compound(args: &[f64]) -> Result<f64, EvalError> {
+    let (principal, rate, years) = (args[0], args[1], args[2]);
+    let n = arg(args, 3, 1.0);
+    if n <= 0.0 {
+        return Err(EvalError::Domain(format!(
+            "It has to be compounded more than 0 times a year, not {}",
+            n
+        )));
+    }
+    return Ok(principal * (1.0 + rate / n).powf(n * years));
+}
+
+/** amortize
+
+`amortize(rate, nper, pv)`, the payment schedule of a loan as a matrix, with a row for each
+period of `[period, payment, interest, principal, balance]`.
+
+The payment, interest and principal have the same signs as `pmt`, so they're negative
+for a loan, and the balance is how much is left to pay after that period.
+*/
+pub fn amortize(args: &[f64]) -> Result<Value, EvalError> {
+    let (rate, nper, pv) = (args[0], args[1], args[2]);
+    if nper < 1.0 || nper.fract() != 0.0 {
+        return Err(EvalError::Domain(format!(
+            "The number of periods has to be a whole number that's at least 1, not {}",
+            nper
+        )));
+    }
+    let payment = pmt(&[rate, nper, pv])?;
+
+    let mut rows = vec![];
+    let mut balance = pv;
+    for period in 1..=nper as usize {
+        let interest = -balance * rate;
+        let principal = payment - interest;
+        balance += principal;
+        // The last payment pays it off exactly, even with rounding errors
+        if period == nper as usize {
+            balance = 0.0;
+        }
+        let row = [period as f64, payment, interest, principal, balance];
+        rows.push(Value::List(row.into_iter().map(Value::Number).collect()));
+    }
+    return Ok(Value::List(rows));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64, tolerance: f64) -> bool {
+        return (a - b).abs() < tolerance;
+    }
+
+    #[test]
+    fn time_value() {
+        let payment = pmt(&[0.05 / 12.0, 360.0, 200000.0]).unwrap();
+        assert!(close(payment, -1073.6432460242797, 1e-9));
+        assert!(close(
+            pv(&[0.05 / 12.0, 360.0, payment]).unwrap(),
+            200000.0,
+            1e-6
+        ));
+        assert!(close(
+            nper(&[0.05 / 12.0, payment, 200000.0]).unwrap(),
+            360.0,
+            1e-9
+        ));
+        assert_eq!(nper(&[0.0, -100.0, 1000.0]).unwrap(), 10.0);
+        assert!(matches!(
+            nper(&[0.0, 0.0, 1000.0]),
+            Err(EvalError::Domain(_))
+        ));
+        assert!(close(
+            rate(&[360.0, payment, 200000.0]).unwrap(),
+            0.05 / 12.0,
+            1e-12
+        ));
+        assert!(close(
+            fv(&[0.05, 10.0, -100.0]).unwrap(),
+            1257.789253554884,
+            1e-9
+        ));
+        // Paying at the start of each period
+        assert!(close(
+            fv(&[0.05, 10.0, -100.0, 0.0, 1.0]).unwrap(),
+            1320.6787162326282,
+            1e-9
+        ));
+        assert_eq!(pmt(&[0.0, 10.0, 1000.0]).unwrap(), -100.0);
+        assert!(pmt(&[0.05, 10.0, 100.0, 0.0, 2.0]).is_err());
+    }
+
+    #[test]
+    fn cash_flows() {
+        assert!(close(
+            npv(0.1, &[-100.0, 50.0, 60.0]),
+            -4.507888805409472,
+            1e-9
+        ));
+        let rate = irr(&[-100.0, 50.0, 60.0]).unwrap();
+        assert!(close(npv(rate, &[-100.0, 50.0, 60.0]), 0.0, 1e-9));
+        assert!(matches!(irr(&[100.0, 50.0]), Err(EvalError::Domain(_))));
+    }
+
+    #[test]
+    fn compounding() {
+        let yearly = compound(&[1000.0, 0.05, 10.0]).unwrap();
+        assert!(close(yearly, 1000.0 * 1.05f64.powi(10), 1e-9));
+        let monthly = compound(&[1000.0, 0.05, 10.0, 12.0]).unwrap();
+        assert!(close(monthly, 1647.00949769028, 1e-9));
+        assert!(compound(&[1000.0, 0.05, 10.0, 0.0]).is_err());
+    }
+
+    #[test]
+    fn schedule() {
+        let schedule = amortize(&[0.01, 3.0, 1000.0]).unwrap().to_string();
+        assert!(schedule.starts_with("[[1, -340.0221114814678, -10, -330.0221114814678, "));
+        assert!(schedule.ends_with(", 0]]"));
+        assert!(amortize(&[0.01, 2.5, 1000.0]).is_err());
+    }
+}
@@ -0,0 +1,353 @@
+use clap::ValueEnum;
+
+use crate::{time, value::Value};
+
+/// How the exponent of a number is written
+#[derive(Debug, Clone, Copy, PartialEq, Default, ValueEnum)]
+pub enum Notation {
+    /// Plain digits, switching to scientific for very large or very small numbers
+    #[default]
+    Auto,
+    /// Always plain digits (ex: `1500000`)
+    Fixed,
+    /// One digit before the point (ex: `1.5e6`)
+    Scientific,
+    /// Exponent is always a multiple of 3 (ex: `1.5e6` or `150e3`)
+    Engineering,
+}
+
+/// The base numbers are written in
+#[derive(Debug, Clone, Copy, PartialEq, Default, ValueEnum)]
+pub enum Radix {
+    #[default]
+    Dec,
+    Hex,
+    Bin,
+    Oct,
+}
+
+impl Radix {
+    fn base(self) -> u32 {
+        return match self {
+            Radix::Dec => 10,
+            Radix::Hex => 16,
+            Radix::Bin => 2,
+            Radix::Oct => 8,
+        };
+    }
+
+    fn prefix(self) -> &'static str {
+        return match self {
+            Radix::Dec => "",
+            Radix::Hex => "0x",
+            Radix::Bin => "0b",
+            Radix::Oct => "0o",
+        };
+    }
+}
+
+/// Options for turning a result into a string.
+///
+/// The default formats numbers the same way `{}` does, except that huge and
+/// tiny numbers are written in scientific notation.
+#[derive(Debug, Clone, Default)]
+pub struct Format {
+    /// Digits after the decimal point (or after the point of the mantissa)
+    pub decimals: Option<usize>,
+    /// Significant figures. Takes precedence over `decimals`, and isn't used for other radices
+    pub sig_figs: Option<usize>,
+    /// Isn't used for other radices, which are always written with plain digits
+    pub notation: Notation,
+    /// Separate groups of digits (`1,000,000` or `0xffff_ffff`)
+    pub thousands: bool,
+    pub radix: Radix,
+    /// Print booleans as `1` and `0` instead of `true` and `false`
+    pub bool_as_number: bool,
+}
+
+// Outside of this range, `Notation::Auto` switches to scientific notation
+const AUTO_MAX: f64 = 1e21;
+const AUTO_MIN: f64 = 1e-7;
+
+// Fractional digits to print in other radices when `decimals` isn't set
+const RADIX_MAX_DIGITS: usize = 16;
+
+impl Format {
+    pub fn format_value(&self, value: &Value) -> String {
+        return match value {
+            Value::Number(n) => self.format(*n),
+            Value::Bool(b) if self.bool_as_number => self.format(if *b { 1.0 } else { 0.0 }),
+            Value::Bool(b) => b.to_string(),
+            Value::List(items) => {
+                let items = items
+                    .iter()
+                    .map(|v| self.format_value(v))
+                    .collect::<Vec<_>>();
+                format!("[{}]", items.join(", "))
+            }
+            Value::Date(date) => time::format_date(*date),
+            Value::Duration(t) => time::format_duration(*t),
+            Value::Percent(p) => format!("{}%", self.format(*p)),
+            Value::Expression(tree) => tree.to_string(),
+        };
+    }
+
+    pub fn format(&self, value: f64) -> String {
+        if value.is_nan() {
+            return "NaN".to_string();
+        }
+        if value.is_infinite() {
+            return if value > 0.0 { "inf" } else { "-inf" }.to_string();
+        }
+        if self.radix != Radix::Dec {
+            return self.format_radix(value);
+        }
+
+        let value = match self.sig_figs {
+            Some(n) => round_sig(value, n),
+            None => value,
+        };
+
+        let notation = match self.notation {
+            Notation::Auto => {
+                if value != 0.0 && (value.abs() >= AUTO_MAX || value.abs() < AUTO_MIN) {
+                    Notation::Scientific
+                } else {
+                    Notation::Fixed
+                }
+            }
+            n => n,
+        };
+
+        return match notation {
+            Notation::Fixed | Notation::Auto => self.format_fixed(value),
+            Notation::Scientific => self.format_scientific(value),
+            Notation::Engineering => self.format_engineering(value),
+        };
+    }
+
+    fn format_fixed(&self, value: f64) -> String {
+        let s = if let Some(n) = self.sig_figs {
+            // Keep trailing zeros as they are significant
+            let decimals = (n as i32 - 1 - exponent(value)).max(0) as usize;
+            format!("{:.*}", decimals, value)
+        } else if let Some(d) = self.decimals {
+            format!("{:.*}", d, value)
+        } else {
+            format!("{}", value)
+        };
+        return self.group(&s);
+    }
+
+    fn format_scientific(&self, value: f64) -> String {
+        let s = if let Some(n) = self.sig_figs {
+            format!("{:.*e}", n.max(1) - 1, value)
+        } else if let Some(d) = self.decimals {
+            format!("{:.*e}", d, value)
+        } else {
+            format!("{:e}", value)
+        };
+        return self.group(&s);
+    }
+
+    fn format_engineering(&self, value: f64) -> String {
+        if value == 0.0 {
+            return self.format_fixed(value);
+        }
+
+        let mut exp = exponent(value);
+        exp -= exp.rem_euclid(3);
+        let mut mantissa = value / 10f64.powi(exp);
+
+        // Rounding the mantissa can push it up to the next multiple of 3
+        if let Some(d) = self.decimals.filter(|_| self.sig_figs.is_none()) {
+            let rounded: f64 = format!("{:.*}", d, mantissa).parse().unwrap_or(mantissa);
+            if rounded.abs() >= 1000.0 {
+                exp += 3;
+                mantissa /= 1000.0;
+            }
+        }
+
+        let sub = Format {
+            notation: Notation::Fixed,
+            thousands: false,
+            ..self.clone()
+        };
+        return format!("{}e{}", sub.format_fixed(mantissa), exp);
+    }
+
+    fn format_radix(&self, value: f64) -> String {
+        let base = self.radix.base();
+        let fbase = base as f64;
+        let max = self.decimals.unwrap_or(RADIX_MAX_DIGITS);
+
+        // Since every radix is a power of 2, all of these divisions and
+        // multiplications are exact (as long as the scale doesn't overflow).
+        // Rounding to the last digit first means it can carry into the integer part.
+        let scale = fbase.powi(max.min(i32::MAX as usize) as i32);
+        let rounded = (value.abs() * scale).round() / scale;
+        let abs = if rounded.is_finite() {
+            rounded
+        } else {
+            value.abs()
+        };
+        let mut int = abs.trunc();
+        let mut frac = abs.fract();
+
+        let mut digits = Vec::new();
+        while int > 0.0 {
+            let d = int % fbase;
+            digits.push(digit(d as u32, base));
+            int = ((int - d) / fbase).trunc();
+        }
+        if digits.is_empty() {
+            digits.push('0');
+        }
+        digits.reverse();
+
+        let mut s = String::new();
+        if value < 0.0 {
+            s.push('-');
+        }
+        s.push_str(self.radix.prefix());
+        s.push_str(&self.group_digits(&digits.into_iter().collect::<String>(), 4, '_'));
+
+        // Like decimals, a fixed number of digits is written out in full
+        let pad = self.decimals.is_some();
+        if (frac > 0.0 || pad) && max > 0 {
+            s.push('.');
+            let mut i = 0;
+            while (frac > 0.0 || pad) && i < max {
+                frac *= fbase;
+                let d = frac.trunc();
+                s.push(digit(d as u32, base));
+                frac -= d;
+                i += 1;
+            }
+        }
+
+        return s;
+    }
+
+    /// Adds thousands separators to the integer part of a decimal number
+    fn group(&self, s: &str) -> String {
+        if !self.thousands {
+            return s.to_string();
+        }
+        let (sign, rest) = match s.strip_prefix('-') {
+            Some(rest) => ("-", rest),
+            None => ("", s),
+        };
+        let end = rest.find(['.', 'e']).unwrap_or(rest.len());
+        let (int, tail) = rest.split_at(end);
+        return format!("{}{}{}", sign, self.group_digits(int, 3, ','), tail);
+    }
+
+    fn group_digits(&self, digits: &str, size: usize, sep: char) -> String {
+        if !self.thousands {
+            return digits.to_string();
+        }
+        let mut s = String::new();
+        for (i, c) in digits.chars().enumerate() {
+            if i > 0 && (digits.len() - i).is_multiple_of(size) {
+                s.push(sep);
+            }
+            s.push(c);
+        }
+        return s;
+    }
+}
+
+/// The power of ten of the most significant digit
+fn exponent(value: f64) -> i32 {
+    if value == 0.0 {
+        return 0;
+    }
+    return value.abs().log10().floor() as i32;
+}
+
+/// Rounds a number to `n` significant figures
+pub fn round_sig(value: f64, n: usize) -> f64 {
+    if value == 0.0 || !value.is_finite() {
+        return value;
+    }
+    // Going through the string avoids the error of multiplying by a power of 10
+    return format!("{:.*e}", n.max(1) - 1, value)
+        .parse()
+        .unwrap_or(value);
+}
+
+fn digit(d: u32, base: u32) -> char {
+    return std::char::from_digit(d, base).unwrap_or('?');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn radix(radix: Radix, decimals: Option<usize>) -> Format {
+        return Format {
+            radix,
+            decimals,
+            ..Format::default()
+        };
+    }
+
+    #[test]
+    fn decimals_and_sig_figs() {
+        let decimals = Format {
+            decimals: Some(2),
+            ..Format::default()
+        };
+        assert_eq!(decimals.format(1.0 / 3.0), "0.33");
+        let sig_figs = Format {
+            sig_figs: Some(3),
+            ..Format::default()
+        };
+        assert_eq!(sig_figs.format(0.1 + 0.2), "0.300");
+        assert_eq!(sig_figs.format(123456.0), "123000");
+    }
+
+    #[test]
+    fn notation() {
+        let format = |notation| Format {
+            notation,
+            ..Format::default()
+        };
+        assert_eq!(format(Notation::Auto).format(1e25), "1e25");
+        assert_eq!(format(Notation::Scientific).format(1500.0), "1.5e3");
+        assert_eq!(
+            format(Notation::Engineering).format(2f64.powi(20)),
+            "1.048576e6"
+        );
+    }
+
+    #[test]
+    fn thousands() {
+        let format = Format {
+            thousands: true,
+            ..Format::default()
+        };
+        assert_eq!(format.format(-1234567.5), "-1,234,567.5");
+        let hex = Format {
+            thousands: true,
+            ..radix(Radix::Hex, None)
+        };
+        assert_eq!(hex.format(4294967295.0), "0xffff_ffff");
+    }
+
+    #[test]
+    fn radices() {
+        assert_eq!(radix(Radix::Hex, None).format(255.0), "0xff");
+        assert_eq!(radix(Radix::Bin, None).format(-2.5), "-0b10.1");
+        assert_eq!(radix(Radix::Oct, None).format(8.0), "0o10");
+    }
+
+    #[test]
+    fn radix_decimals_round_the_last_digit() {
+        assert_eq!(radix(Radix::Hex, Some(2)).format(0.9999), "0x1.00");
+        assert_eq!(radix(Radix::Bin, Some(1)).format(2.75), "0b11.0");
+        assert_eq!(radix(Radix::Bin, Some(3)).format(1.5), "0b1.100");
+        assert_eq!(radix(Radix::Hex, Some(0)).format(2.5), "0x3");
+    }
+}
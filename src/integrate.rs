@@ -0,0 +1,115 @@
+#![allow(clippy::excessive_precision)] // The constants are copied as they are from QUADPACK
+
+use crate::{solve::Func, value::EvalError};
+
+/// How many times the worst piece can be cut in half before giving up
+const MAX_PIECES: usize = 1000;
+
+/// The answer is good enough when the estimated error is below this, relative to the answer
+const TOLERANCE: f64 = 1e-12;
+
+/// Where the 15 point Kronrod rule looks, from the ends (±1) to the middle (0).
+/// Every other one, starting at the second, is also a point of the 7 point Gauss rule.
+const NODES: [f64; 8] = [
+    0.991455371120812639206854697526329,
+    0.949107912342758524526189684047851,
+    0.864864423359769072789712788640926,
+    0.741531185599394439863864773280788,
+    0.586087235467691130294144845693013,
+    0.405845151377397166906606412076961,
+    0.207784955007898467600689403773245,
+    0.0,
+];
+
+const KRONROD_WEIGHTS: [f64; 8] = [
+    0.022935322010529224963732008058970,
+    0.063092092629978553290700663189204,
+    0.104790010322250183839876322541518,
+    0.140653259715525918745189590510238,
+    0.169004726639267902826583426598550,
+    0.190350578064785409913256402421014,
+    0.204432940075298892414161999234649,
+    0.209482141084727828012999174891714,
+];
+
+const GAUSS_WEIGHTS: [f64; 4] = [
+    0.129484966168869693270611432679082,
+    0.279705391489276667901467771423780,
+    0.381830050505118944950369775488975,
+    0.417959183673469387755102040816327,
+];
+
+/// A piece of the interval, with its integral and how wrong that might be
+struct Piece {
+    a: f64,
+    b: f64,
+    value: f64,
+    error: f64,
+}
+
+/// The integral over one piece with the 15 point Kronrod rule.
+/// The error is how far it is from the 7 point Gauss rule that uses the same points.
+fn gauss_kronrod(f: &Func, a: f64, b: f64) -> Result<Piece, EvalError> {
+    let center = (a + b) / 2.0;
+    let half = (b - a) / 2.0;
+
+    let mut kronrod = 0.0;
+    let mut gauss = 0.0;
+    for (i, node) in NODES.iter().enumerate() {
+        let y = if i == 7 {
+            f.at(center)?
+        } else {
+            f.at(center - half * node)? + f.at(center + half * node)?
+        };
+        kronrod += KRONROD_WEIGHTS[i] * y;
+        if i % 2 == 1 {
+            gauss += GAUSS_WEIGHTS[i / 2] * y;
+        }
+    }
+
+    return Ok(Piece {
+        a,
+        b,
+        value: kronrod * half,
+        error: ((kronrod - gauss) * half).abs(),
+    });
+}
+
+/** integrate
+
+The integral of `f` from `a` to `b`.
+
+Algorithm:
+1. Estimate the integral and its error over the whole interval with `gauss_kronrod`
+2. While the total error is too big, cut the piece with the biggest error in half
+   and estimate both halves
+3. Add up the pieces
+
+The function is only evaluated inside the interval, so things like `1 / sqrt(x)`
+from 0 work even though they're infinite at the end.
+*/
+pub(crate) fn integrate(f: &Func, a: f64, b: f64) -> Result<f64, EvalError> {
+    if a == b {
+        return Ok(0.0);
+    }
+
+    let mut pieces = vec![gauss_kronrod(f, a, b)?];
+    loop {
+        let value: f64 = pieces.iter().map(|p| p.value).sum();
+        let error: f64 = pieces.iter().map(|p| p.error).sum();
+        if error <= TOLERANCE * value.abs().max(1.0) || error.is_nan() {
+            return Ok(value);
+        }
+        if pieces.len() >= MAX_PIECES {
+            return Err(EvalError::TooManyIterations("integrate".to_string()));
+        }
+
+        let worst = (0..pieces.len())
+            .max_by(|i, j| pieces[*i].error.total_cmp(&pieces[*j].error))
+            .unwrap();
+        let piece = pieces.swap_remove(worst);
+        let middle = (piece.a + piece.b) / 2.0;
+        pieces.push(gauss_kronrod(f, piece.a, middle)?);
+        pieces.push(gauss_kronrod(f, middle, piece.b)?);
+    }
+}
@@ -0,0 +1,106 @@
+//! Results and errors as JSON, for programs that run the calculator.
+
+use serde_json::{json, Value as Json};
+
+use crate::{
+    error::Error,
+    format::Format,
+    parser::TreeNode,
+    time,
+    tokeniser::{Span, Token},
+    value::Value,
+};
+
+/// JSON has no infinity or NaN, so those are written as strings
+fn number(n: f64) -> Json {
+    return match serde_json::Number::from_f64(n) {
+        Some(n) => Json::Number(n),
+        None => Json::String(n.to_string()),
+    };
+}
+
+/// The value as plain JSON: durations and percentages are the numbers they stand for
+/// (seconds and fractions), and dates and expressions are strings
+pub fn value(value: &Value) -> Json {
+    return match value {
+        Value::Number(n) | Value::Duration(n) => number(*n),
+        Value::Percent(p) => number(p / 100.0),
+        Value::Bool(b) => Json::Bool(*b),
+        Value::List(items) => Json::Array(items.iter().map(self::value).collect()),
+        Value::Date(date) => Json::String(time::format_date(*date)),
+        Value::Expression(tree) => Json::String(tree.to_string()),
+    };
+}
+
+/// `{ "expression", "result", "type", "formatted" }`
+pub fn result(expression: &str, result: &Value, format: &Format) -> Json {
+    return json!({
+        "expression": expression,
+        "result": value(result),
+        "type": result.type_name(),
+        "formatted": format.format_value(result),
+    });
+}
+
+/// `{ "kind", "message", "span" }` for the error
+pub fn error(error: &Error) -> Json {
+    return report(error.kind(), &error.to_string(), error.span());
+}
+
+/// `{ "kind", "message", "span" }`, where the span is `{ "start", "end" }` in characters,
+/// or null if it isn't known
+pub fn report(kind: &str, message: &str, at: Option<Span>) -> Json {
+    return json!({
+        "kind": kind,
+        "message": message,
+        "span": at.as_ref().map(span),
+    });
+}
+
+fn span(span: &Span) -> Json {
+    return json!({ "start": span.start, "end": span.end });
+}
+
+/// `[{ "kind", "text", "span" }]` for each token, where `text` is the part of the input it came from
+pub fn tokens(input: &str, tokens: &[Token], spans: &[Span]) -> Json {
+    let chars = input.chars().collect::<Vec<_>>();
+    let tokens = tokens.iter().zip(spans).map(|(token, s)| {
+        json!({
+            "kind": token.kind(),
+            "text": chars[s.clone()].iter().collect::<String>(),
+            "span": span(s),
+        })
+    });
+    return Json::Array(tokens.collect());
+}
+
+/// The tree as `{ "kind", "name", "args" }` for operators and functions (with their operands
+/// in `args`), `{ "kind": "constant", "value", "type" }` and `{ "kind": "variable", "name" }`.
+/// Calls to defined functions are variables with `args`, and `if` is a keyword with the
+/// condition and the two branches as its args.
+pub fn tree(tree: &TreeNode) -> Json {
+    let token = match &tree.value {
+        Some(token) => token,
+        None => return Json::Null,
+    };
+    let args = tree
+        .left
+        .iter()
+        .chain(tree.right.iter())
+        .map(|t| t.as_ref());
+    let args = args
+        .chain(tree.args.iter())
+        .map(self::tree)
+        .collect::<Vec<_>>();
+    return match token {
+        Token::Constant(v) => {
+            json!({ "kind": "constant", "value": value(v), "type": v.type_name() })
+        }
+        Token::Variable(name) if tree.is_defined_call() => {
+            json!({ "kind": "variable", "name": name, "args": args })
+        }
+        Token::Variable(name) => json!({ "kind": "variable", "name": name }),
+        Token::Keyword(_) => json!({ "kind": "keyword", "name": "if", "args": args }),
+        _ => json!({ "kind": token.kind(), "name": tree.name(), "args": args }),
+    };
+}
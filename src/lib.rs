@@ -0,0 +1,26 @@
+#![allow(clippy::needless_return)] // I like my returns explicit
+
+pub mod builtins;
+pub mod compile;
+mod derivative;
+mod distributions;
+pub mod environment;
+pub mod error;
+mod finance;
+pub mod format;
+mod integrate;
+pub mod json;
+pub mod lsp;
+pub mod math;
+mod matrix;
+mod number_theory;
+pub mod parser;
+mod peeker;
+mod polynomial;
+pub mod script;
+pub mod server;
+mod simplify;
+mod solve;
+mod time;
+pub mod tokeniser;
+pub mod value;
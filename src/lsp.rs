@@ -0,0 +1,551 @@
+//! A language server for `.calc` files (see `script`), over stdin and stdout.
+//!
+//! It runs the whole file every time it changes (stopping after `TIME_LIMIT`), and gives:
+//! - diagnostics for statements that can't be tokenised, parsed or evaluated, like unknown
+//!   names, unbalanced parentheses or the wrong number of arguments
+//! - the value of the statement on the line as the hover
+//! - completion for built-in names and the variables and functions defined above
+//! - go to definition for variables, functions and the parameters of a function
+//!
+//! Messages are JSON-RPC with a `Content-Length` header before each one, and positions are
+//! in UTF-16 code units, like the protocol says.
+
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, Write},
+    ops::Range,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use serde_json::{json, Value as Json};
+
+use crate::{
+    environment::{arity_error, Environment},
+    error::{name_span, Error},
+    format::Format,
+    parser::TreeNode,
+    script::{self, Source, Statement},
+    server::{self, RpcError, INVALID_PARAMS, METHOD_NOT_FOUND, PARSE_ERROR},
+    tokeniser::Token,
+    value::{EvalError, Value},
+};
+
+/// `TextDocumentSyncKind.Full`, so every change sends the whole file
+const FULL_SYNC: i64 = 1;
+/// `DiagnosticSeverity.Error`
+const ERROR: i64 = 1;
+/// How long running a document can take, since it's run again after every change
+const TIME_LIMIT: Duration = Duration::from_secs(1);
+/// The longest message (in bytes) that's read, so a wrong `Content-Length` can't use up
+/// the memory
+const MAX_MESSAGE: usize = 64 * 1024 * 1024;
+
+/// Something wrong with a statement
+struct Problem {
+    /// Where it is in the statement, in characters
+    span: Range<usize>,
+    kind: &'static str,
+    message: String,
+}
+
+/// A statement after running it
+struct Checked {
+    source: Source,
+    statement: Option<Statement>,
+    value: Option<Value>,
+    problem: Option<Problem>,
+}
+
+/// The problem with the statement for the error, at the name it's about if it's about one
+fn problem(text: &str, error: Error) -> Problem {
+    let error = error.locate(text);
+    let whole = 0..text.chars().count();
+    return Problem {
+        span: error.span().unwrap_or(whole),
+        kind: error.kind(),
+        message: error.to_string(),
+    };
+}
+
+/// Calls to defined functions with the wrong number of arguments, which are only found
+/// when they're called otherwise (and without saying where)
+fn check_calls(text: &str, tree: &TreeNode, env: &Environment) -> Option<Problem> {
+    if let Some(Token::Variable(name)) = &tree.value {
+        let function = env.function(name);
+        let params = function.map(|f| f.params.len()).unwrap_or(tree.args.len());
+        if tree.is_defined_call() && params != tree.args.len() {
+            let error = Error::from(arity_error(name, params, tree.args.len()));
+            let mut problem = problem(text, error);
+            problem.span = name_span(text, name, true).unwrap_or(problem.span);
+            return Some(problem);
+        }
+    }
+    let mut children = tree
+        .left
+        .iter()
+        .chain(tree.right.iter())
+        .map(|t| t.as_ref());
+    return children
+        .find_map(|t| check_calls(text, t, env))
+        .or_else(|| tree.args.iter().find_map(|t| check_calls(text, t, env)));
+}
+
+/** check
+
+Runs the statements before the line `until` (or all of them), and gives what happened with
+each one and the environment at the end.
+
+Algorithm:
+1. Start a thread that sets the cancel flag of the environment after `TIME_LIMIT`, unless
+   it's told that checking is done first
+2. Parse and run each statement in order, with one environment
+3. Calls to defined functions are checked for the number of arguments before they're run
+4. If a statement is cancelled, say so on it and stop there, since the ones after it
+   would be cancelled too
+5. If it's the whole file, check that every name in the body of a function is defined
+   somewhere, by running it with every parameter set to 1 (a function can use variables
+   and functions defined after it, as long as they're there when it's called)
+*/
+fn check(text: &str, until: Option<usize>) -> (Vec<Checked>, Environment) {
+    let cancel = Arc::new(AtomicBool::new(false));
+    let (done, finished) = mpsc::channel::<()>();
+    let timer = cancel.clone();
+    thread::spawn(move || {
+        if finished.recv_timeout(TIME_LIMIT) == Err(mpsc::RecvTimeoutError::Timeout) {
+            timer.store(true, Ordering::Relaxed);
+        }
+    });
+    let checked = check_until(text, until, cancel);
+    drop(done);
+    return checked;
+}
+
+fn check_until(
+    text: &str,
+    until: Option<usize>,
+    cancel: Arc<AtomicBool>,
+) -> (Vec<Checked>, Environment) {
+    let mut env = Environment::new();
+    env.set_cancel(Some(cancel));
+    let mut checked = vec![];
+    for source in script::split(text) {
+        if until.is_some_and(|line| source.line >= line) {
+            break;
+        }
+        let statement = match Statement::parse(&source.text) {
+            Ok(statement) => statement,
+            Err(e) => {
+                let problem = problem(&source.text, e);
+                checked.push(Checked {
+                    source,
+                    statement: None,
+                    value: None,
+                    problem: Some(problem),
+                });
+                continue;
+            }
+        };
+
+        let tree = statement.tree();
+        let mut problem = tree.and_then(|tree| check_calls(&source.text, tree, &env));
+        let mut value = None;
+        if problem.is_none() {
+            match statement.run(&mut env) {
+                Ok(v) => value = v,
+                Err(EvalError::Cancelled) => {
+                    checked.push(Checked {
+                        problem: Some(Problem {
+                            span: 0..source.text.chars().count(),
+                            kind: EvalError::Cancelled.kind(),
+                            message: format!(
+                                "This took more than {} seconds, so it and the rest of the file weren't checked",
+                                TIME_LIMIT.as_secs()
+                            ),
+                        }),
+                        source,
+                        statement: Some(statement),
+                        value: None,
+                    });
+                    break;
+                }
+                Err(e) => problem = Some(self::problem(&source.text, Error::from(e))),
+            }
+        }
+        checked.push(Checked {
+            source,
+            statement: Some(statement),
+            value,
+            problem,
+        });
+    }
+
+    if until.is_none() {
+        for c in &mut checked {
+            if let Some(Statement::Define { params, body, .. }) = &c.statement {
+                let mut inner = env.scope();
+                for param in params {
+                    inner.bind(param, Value::Number(1.0));
+                }
+                c.problem = check_calls(&c.source.text, body, &env);
+                if c.problem.is_some() {
+                    continue;
+                }
+                if let Err(e @ (EvalError::UnknownVariable(_) | EvalError::UnknownFunction(_))) =
+                    body.eval_with(&inner)
+                {
+                    c.problem = Some(problem(&c.source.text, Error::from(e)));
+                }
+            }
+        }
+    }
+    return (checked, env);
+}
+
+/// The character the UTF-16 position is at on the line
+fn column(line: &str, utf16: usize) -> usize {
+    let mut units = 0;
+    for (i, c) in line.chars().enumerate() {
+        if units >= utf16 {
+            return i;
+        }
+        units += c.len_utf16();
+    }
+    return line.chars().count();
+}
+
+/// `{ line, character }` for the character on the line
+fn position(lines: &[&str], line: usize, column: usize) -> Json {
+    let text = lines.get(line).copied().unwrap_or_default();
+    let character = text
+        .chars()
+        .take(column)
+        .map(|c| c.len_utf16())
+        .sum::<usize>();
+    return json!({ "line": line, "character": character });
+}
+
+/// `{ start, end }` for the span of the statement
+fn range(lines: &[&str], source: &Source, span: &Range<usize>) -> Json {
+    return json!({
+        "start": position(lines, source.line, source.column + span.start),
+        "end": position(lines, source.line, source.column + span.end),
+    });
+}
+
+fn diagnostics(text: &str) -> Json {
+    let lines = text.lines().collect::<Vec<_>>();
+    let (checked, _) = check(text, None);
+    let diagnostics = checked.iter().filter_map(|c| {
+        let problem = c.problem.as_ref()?;
+        return Some(json!({
+            "range": range(&lines, &c.source, &problem.span),
+            "severity": ERROR,
+            "code": problem.kind,
+            "source": "calculator",
+            "message": problem.message,
+        }));
+    });
+    return Json::Array(diagnostics.collect());
+}
+
+/// The text of the document and the line and character (not UTF-16) of the position
+fn document<'a>(
+    documents: &'a HashMap<String, String>,
+    params: &Json,
+) -> Result<(&'a str, usize, usize), RpcError> {
+    let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+    let text = documents
+        .get(uri)
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("{} isn't open", uri)))?;
+    let line = params["position"]["line"].as_u64().unwrap_or(0) as usize;
+    let character = params["position"]["character"].as_u64().unwrap_or(0) as usize;
+    let column = column(text.lines().nth(line).unwrap_or_default(), character);
+    return Ok((text, line, column));
+}
+
+/// The value of the statement on the line, like `x = 5`, or `f(x) = x^2` for a function
+fn hover(text: &str, line: usize, column: usize) -> Json {
+    let lines = text.lines().collect::<Vec<_>>();
+    let (checked, _) = check(text, None);
+    let on_line = checked.iter().filter(|c| c.source.line == line);
+    let c = match on_line.take_while(|c| c.source.column <= column).last() {
+        Some(c) if c.problem.is_none() => c,
+        _ => return Json::Null,
+    };
+    let format = Format::default();
+    let value = c.value.as_ref().map(|v| format.format_value(v));
+    let hover = match (&c.statement, value) {
+        (Some(Statement::Assign { name, .. }), Some(value)) => format!("{} = {}", name, value),
+        (Some(Statement::Define { name, params, body }), _) => {
+            format!("{}({}) = {}", name, params.join(", "), body)
+        }
+        (_, Some(value)) => value,
+        _ => return Json::Null,
+    };
+    return json!({
+        "contents": { "kind": "markdown", "value": format!("```\n{}\n```", hover) },
+        "range": range(&lines, &c.source, &(0..c.source.text.chars().count())),
+    });
+}
+
+/// `CompletionItemKind` for the kind of token
+fn completion_kind(kind: &str) -> i64 {
+    return match kind {
+        "function" | "call" => 3,
+        "variable" => 6,
+        "keyword" => 14,
+        "constant" => 21,
+        _ => 24, // Operator
+    };
+}
+
+/// The built-in names, and the variables and functions defined before the line
+fn complete(text: &str, line: usize, column: usize) -> Json {
+    let chars = text.lines().nth(line).unwrap_or_default();
+    let chars = chars.chars().take(column).collect::<Vec<_>>();
+    let prefix = chars[server::word_start(&chars, chars.len())..]
+        .iter()
+        .collect::<String>();
+    let (_, env) = check(text, Some(line));
+    let items = server::completions(&env, &prefix).into_iter().map(|(label, kind, detail)| {
+        json!({ "label": label, "kind": completion_kind(kind), "detail": detail })
+    });
+    return Json::Array(items.collect());
+}
+
+/// Where the name at the position is set, which is the closest statement above it (or the
+/// first one below, for functions that use things defined after them), or the parameter
+/// if it's in the definition of a function
+fn definition(uri: &str, text: &str, line: usize, column: usize) -> Json {
+    let lines = text.lines().collect::<Vec<_>>();
+    let chars = lines.get(line).copied().unwrap_or_default();
+    let chars = chars.chars().collect::<Vec<_>>();
+    let mut end = column.min(chars.len());
+    while end < chars.len() && (chars[end].is_alphanumeric() || chars[end] == '_') {
+        end += 1;
+    }
+    let name = chars[server::word_start(&chars, end)..end]
+        .iter()
+        .collect::<String>();
+
+    let (checked, _) = check(text, None);
+    let location = |c: &Checked, span: Range<usize>| json!({ "uri": uri, "range": range(&lines, &c.source, &span) });
+    for c in checked.iter().filter(|c| c.source.line == line) {
+        if let Some(Statement::Define { params, .. }) = &c.statement {
+            if params.contains(&name) {
+                if let Some(span) = name_span(&c.source.text, &name, false) {
+                    return location(c, span);
+                }
+            }
+        }
+    }
+    let mut sets = checked
+        .iter()
+        .filter(|c| c.statement.as_ref().and_then(|s| s.name()) == Some(name.as_str()));
+    let above = sets.clone().rfind(|c| c.source.line <= line);
+    return match above.or_else(|| sets.next()) {
+        Some(c) => location(c, 0..name.chars().count()),
+        None => Json::Null,
+    };
+}
+
+fn capabilities() -> Json {
+    return json!({
+        "capabilities": {
+            "textDocumentSync": FULL_SYNC,
+            "hoverProvider": true,
+            "completionProvider": {},
+            "definitionProvider": true,
+        },
+        "serverInfo": { "name": "calculator", "version": env!("CARGO_PKG_VERSION") },
+    });
+}
+
+/// The body of the next message, or `None` at the end of the input
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<Vec<u8>>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let length = length.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "A message needs a Content-Length",
+        )
+    })?;
+    if length > MAX_MESSAGE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("A message can't be longer than {} bytes", MAX_MESSAGE),
+        ));
+    }
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    return Ok(Some(body));
+}
+
+fn write_message(output: &mut impl Write, message: &Json) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    return output.flush();
+}
+
+fn publish(output: &mut impl Write, uri: &str, diagnostics: Json) -> io::Result<()> {
+    return write_message(
+        output,
+        &json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": { "uri": uri, "diagnostics": diagnostics },
+        }),
+    );
+}
+
+/// Answers the messages from `input` on `output` until `exit` or the end of the input
+pub fn serve(mut input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+    let mut documents: HashMap<String, String> = HashMap::new();
+    while let Some(body) = read_message(&mut input)? {
+        let message = match serde_json::from_slice::<Json>(&body) {
+            Ok(message) => message,
+            Err(e) => {
+                let error = RpcError::new(PARSE_ERROR, e.to_string());
+                write_message(&mut output, &server::response(&Json::Null, Err(error)))?;
+                continue;
+            }
+        };
+        let method = message["method"].as_str().unwrap_or_default();
+        let params = &message["params"];
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+
+        match method {
+            "exit" => return Ok(()),
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or_default();
+                documents.insert(uri.to_string(), text.to_string());
+                publish(&mut output, uri, diagnostics(text))?;
+            }
+            "textDocument/didChange" => {
+                let changes = params["contentChanges"].as_array();
+                let text = changes.and_then(|c| c.last()).map(|c| &c["text"]);
+                let text = text.and_then(|t| t.as_str()).unwrap_or_default();
+                documents.insert(uri.to_string(), text.to_string());
+                publish(&mut output, uri, diagnostics(text))?;
+            }
+            "textDocument/didClose" => {
+                documents.remove(uri);
+                publish(&mut output, uri, json!([]))?;
+            }
+            _ => {}
+        }
+
+        // Notifications (and responses, since this never sends requests) aren't answered
+        let id = match (message.get("id"), message.get("method")) {
+            (Some(id), Some(_)) => id,
+            _ => continue,
+        };
+        let result = match method {
+            "initialize" => Ok(capabilities()),
+            "shutdown" => Ok(Json::Null),
+            "textDocument/hover" => {
+                document(&documents, params).map(|(text, line, column)| hover(text, line, column))
+            }
+            "textDocument/completion" => document(&documents, params)
+                .map(|(text, line, column)| complete(text, line, column)),
+            "textDocument/definition" => document(&documents, params)
+                .map(|(text, line, column)| definition(uri, text, line, column)),
+            _ => Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("There's no method called {}", method),
+            )),
+        };
+        write_message(&mut output, &server::response(id, result))?;
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(body: &str) -> String {
+        return format!("Content-Length: {}\r\n\r\n{}", body.len(), body);
+    }
+
+    #[test]
+    fn problems() {
+        let diagnostics = diagnostics("x = 2\ny = x + z\nf(a) = a^2\nf(1, 2)");
+        let diagnostics = diagnostics.as_array().unwrap();
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0]["code"], json!("unknown_variable"));
+        let start = json!({ "line": 1, "character": 8 });
+        assert_eq!(diagnostics[0]["range"]["start"], start);
+        assert_eq!(diagnostics[1]["range"]["start"]["line"], json!(3));
+    }
+
+    #[test]
+    fn slow_documents_are_cancelled() {
+        let text = "f(n) = if n > 0 then f(n - 1) + f(n - 1) else 1\nf(60)\n1 + 1";
+        let started = std::time::Instant::now();
+        let (checked, _) = check(text, None);
+        assert!(started.elapsed() < TIME_LIMIT * 5);
+        let last = checked.last().unwrap();
+        assert_eq!(last.source.line, 1);
+        assert_eq!(last.problem.as_ref().unwrap().kind, "cancelled");
+    }
+
+    #[test]
+    fn messages() {
+        let input = message("{}") + &message("[1]");
+        let mut input = input.as_bytes();
+        assert_eq!(read_message(&mut input).unwrap().unwrap(), b"{}");
+        assert_eq!(read_message(&mut input).unwrap().unwrap(), b"[1]");
+        assert!(read_message(&mut input).unwrap().is_none());
+
+        let mut input = "\r\n{}".as_bytes();
+        assert!(read_message(&mut input).is_err());
+        let too_long = format!("Content-Length: {}\r\n\r\n", MAX_MESSAGE + 1);
+        assert!(read_message(&mut too_long.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn serving() {
+        let open = json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": { "textDocument": { "uri": "file:///a.calc", "text": "x = 4\nx + 1" } },
+        });
+        let hover = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "textDocument/hover",
+            "params": {
+                "textDocument": { "uri": "file:///a.calc" },
+                "position": { "line": 1, "character": 0 },
+            },
+        });
+        let input = message(&open.to_string()) + &message(&hover.to_string());
+        let mut output = vec![];
+        serve(input.as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("\"diagnostics\":[]"));
+        assert!(output.contains("\"id\":1"));
+        assert!(output.contains(r#""value":"```\n5\n```""#));
+    }
+}
@@ -1,12 +1,22 @@
+#![allow(clippy::needless_return)] // I like my returns explicit
+
 // use tokeniser::Token;
 
-use clap::Parser;
+use std::io::{Read, Write};
 
-use crate::{parser::parse, tokeniser::tokenise};
+use clap::{Parser, Subcommand, ValueEnum};
 
-mod math;
-mod parser;
-mod tokeniser;
+use calculator::{
+    compile::CompiledExpr,
+    environment::Environment,
+    error::{parse_str, Error},
+    format::{Format, Notation, Radix},
+    json, lsp,
+    parser::TreeNode,
+    script::{self, Source, Statement},
+    server,
+    value::{EvalError, Value},
+};
 
 // fn to_str(vec: Vec<Token>) -> String {
 //     let mut s = String::default();
@@ -26,23 +36,549 @@ mod tokeniser;
 //     s
 // }
 
+/// The exit code when the result is false or 0, like `test`
+const FALSY: i32 = 1;
+/// The exit code when the expression can't be tokenised or parsed
+const SYNTAX_ERROR: i32 = 3;
+/// The exit code when the expression can't be evaluated (ex: `1 / 0`)
+const EVAL_ERROR: i32 = 4;
+/// The exit code when an option or file is wrong (ex: `--var` without `=`, or a missing CSV file)
+const INPUT_ERROR: i32 = 5;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// Just the result, and errors on stderr
+    #[default]
+    Text,
+    /// A JSON object with the result or the error, on stdout
+    Json,
+}
+
+/// Something that stops the calculator from printing a result
+enum Failure {
+    /// The expression couldn't be worked out
+    Expression(Error),
+    /// An option or a file was wrong (kind, message), like the ones in `Error`
+    Input(&'static str, String),
+    /// A statement in a script couldn't be worked out (the file and the statement)
+    Script(String, Source, Error),
+}
+
+impl Failure {
+    fn exit_code(&self) -> i32 {
+        return match self {
+            Failure::Expression(e) | Failure::Script(_, _, e) if e.is_syntax() => SYNTAX_ERROR,
+            Failure::Expression(_) | Failure::Script(..) => EVAL_ERROR,
+            Failure::Input(..) => INPUT_ERROR,
+        };
+    }
+}
+
+impl From<Error> for Failure {
+    fn from(e: Error) -> Failure {
+        return Failure::Expression(e);
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Answer JSON-RPC requests, one per line, on stdin (or a socket) until it's closed
+    Serve {
+        /// Listen on a TCP address instead (ex: 127.0.0.1:7700)
+        #[arg(long, value_name = "ADDR", conflicts_with = "socket")]
+        listen: Option<String>,
+
+        /// Listen on a Unix socket instead
+        #[arg(long, value_name = "PATH")]
+        socket: Option<String>,
+    },
+    /// Run a language server for .calc files on stdin and stdout
+    Lsp,
+    /// Run the statements in a .calc file (or - for stdin) in order
+    Run {
+        /// The file to run
+        file: String,
+    },
+}
+
 #[derive(Parser)]
+// The options before a subcommand are still used by `run` (ex: calculator -d 2 run budget.calc)
+#[command(subcommand_negates_reqs = true)]
 struct Cli {
     /// The expression to evaluate
-    expression: String,
+    #[arg(allow_hyphen_values = true, required = true)]
+    expression: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Number of digits after the decimal point
+    #[arg(short, long, conflicts_with = "sig_figs")]
+    decimals: Option<usize>,
+
+    /// Number of significant figures
+    #[arg(short, long)]
+    sig_figs: Option<usize>,
+
+    /// How to write the exponent
+    #[arg(short, long, value_enum, default_value_t)]
+    notation: Notation,
+
+    /// Separate groups of digits (1,000,000)
+    #[arg(short, long)]
+    thousands: bool,
+
+    /// The base to print the result in (only with plain digits and --decimals)
+    #[arg(short, long, value_enum, default_value_t, conflicts_with_all = ["sig_figs", "notation"])]
+    radix: Radix,
+
+    /// Print true and false as 1 and 0
+    #[arg(short, long)]
+    bool_as_number: bool,
+
+    /// Print the simplified expression instead of evaluating it
+    #[arg(long)]
+    simplify: bool,
+
+    /// Set a variable (ex: --var x=3). The value can be an expression using the variables before it
+    #[arg(long = "var", value_name = "NAME=VALUE")]
+    vars: Vec<String>,
+
+    /// Use this date and time for `now` and `today` instead of the clock (ex: --now 2026-10-18T09:00)
+    #[arg(long, value_name = "DATE")]
+    now: Option<String>,
+
+    /// Evaluate the expression for each row of a CSV file (or - for stdin), with the columns as variables
+    #[arg(long, value_name = "FILE")]
+    csv: Option<String>,
+
+    /// The name of the column the results are written to
+    #[arg(long, default_value = "result", requires = "csv")]
+    column: String,
+
+    /// Print the result (or error) as JSON: { "expression", "result", "type", "formatted" }
+    /// or { "error": { "kind", "message", "span" } }
+    #[arg(long = "format", value_enum, default_value_t, conflicts_with = "csv")]
+    output: OutputFormat,
+}
+
+impl Cli {
+    fn expression(&self) -> &str {
+        return self.expression.as_deref().unwrap_or_default();
+    }
+
+    fn format(&self) -> Format {
+        return Format {
+            decimals: self.decimals,
+            sig_figs: self.sig_figs,
+            notation: self.notation,
+            thousands: self.thousands,
+            radix: self.radix,
+            bool_as_number: self.bool_as_number,
+        };
+    }
+}
+
+/// Runs the compiled expression over the rows, with the columns it uses as its variables.
+/// Only those columns have to be numbers.
+fn run_compiled(
+    compiled: &CompiledExpr,
+    headers: &csv::StringRecord,
+    records: &[csv::StringRecord],
+) -> Result<Vec<f64>, Failure> {
+    let csv_error = |message: String| Failure::Input("csv", message);
+    let mut columns = vec![];
+    for name in compiled.variables() {
+        let index = headers.iter().position(|h| h.trim() == name);
+        let index =
+            index.ok_or_else(|| csv_error(format!("No column or variable named {}", name)))?;
+        let column = records
+            .iter()
+            .map(|r| r[index].trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>();
+        let column = column.map_err(|_| csv_error(format!("Column {} isn't all numbers", name)));
+        columns.push(column?);
+    }
+    let columns = columns.iter().map(|c| c.as_slice()).collect::<Vec<_>>();
+
+    let mut results = vec![0.0; records.len()];
+    let result = compiled.eval_batch_into(&columns, &mut results);
+    result.map_err(Error::from)?;
+    return Ok(results);
+}
+
+/// Reads the CSV, adds a column with the result for each row, and writes it to `output`
+fn run_csv(
+    cli: &Cli,
+    path: &str,
+    tree: &TreeNode,
+    env: &Environment,
+    output: impl Write,
+) -> Result<(), Failure> {
+    let csv_error = |message: String| Failure::Input("csv", message);
+    let input: Box<dyn Read> = if path == "-" {
+        Box::new(std::io::stdin())
+    } else {
+        let file = std::fs::File::open(path);
+        Box::new(file.map_err(|e| csv_error(format!("Can't open {}: {}", path, e)))?)
+    };
+    let mut reader = csv::Reader::from_reader(input);
+    let headers = reader.headers();
+    let headers = headers.map_err(|e| csv_error(format!("Can't read the header: {}", e)));
+    let headers = headers?.clone();
+    let records = reader.records().collect::<Result<Vec<_>, _>>();
+    let records = records.map_err(|e| csv_error(format!("Can't read the CSV: {}", e)))?;
+
+    let format = cli.format();
+    let results = match tree.compile_with(env) {
+        Ok(compiled) => run_compiled(&compiled, &headers, &records)?
+            .into_iter()
+            .map(|result| format.format(result))
+            .collect::<Vec<_>>(),
+        // Some built-ins (ex: `max` or `sum`) only work on values, so run the whole tree
+        Err(EvalError::NotCompilable(_)) => {
+            let mut env = env.clone();
+            let mut results = vec![];
+            for record in &records {
+                for (name, field) in headers.iter().zip(record) {
+                    match field.trim().parse::<f64>() {
+                        Ok(x) => env.set(name.trim(), Value::Number(x)),
+                        Err(_) => env.remove(name.trim()),
+                    }
+                }
+                let result = tree.eval_with(&env).map_err(|e| match e {
+                    EvalError::UnknownVariable(name) => {
+                        match headers.iter().any(|h| h.trim() == name) {
+                            true => csv_error(format!("Column {} isn't all numbers", name)),
+                            false => csv_error(format!("No column or variable named {}", name)),
+                        }
+                    }
+                    e => Failure::from(Error::from(e).locate(cli.expression())),
+                })?;
+                results.push(format.format_value(&result));
+            }
+            results
+        }
+        Err(e) => return Err(Failure::from(Error::from(e).locate(cli.expression()))),
+    };
+
+    let write_error = |e: csv::Error| csv_error(format!("Can't write the CSV: {}", e));
+    let mut writer = csv::Writer::from_writer(output);
+    let mut header = headers.clone();
+    header.push_field(&cli.column);
+    writer.write_record(&header).map_err(write_error)?;
+    for (record, result) in records.iter().zip(results) {
+        let mut record = record.clone();
+        record.push_field(&result);
+        writer.write_record(&record).map_err(write_error)?;
+    }
+    writer.flush().map_err(|e| write_error(e.into()))?;
+    return Ok(());
+}
+
+/// Evaluates `--now` and the `--var`s into the environment.
+/// Their errors don't have spans, since those would be in a different string
+fn environment(cli: &Cli) -> Result<Environment, Failure> {
+    let mut env = Environment::new();
+    if let Some(now) = &cli.now {
+        let now = parse_str(now).and_then(|tree| Ok(tree.eval()?));
+        match now.map_err(|e| Failure::Input("invalid_now", format!("In --now: {}", e)))? {
+            Value::Date(now) => env.set_clock(now),
+            value => {
+                let message = format!("--now has to be a date, not a {}", value.type_name());
+                return Err(Failure::Input("invalid_now", message));
+            }
+        }
+    }
+    for var in &cli.vars {
+        let (name, expression) = match var.split_once('=') {
+            Some(parts) => parts,
+            None => {
+                return Err(Failure::Input(
+                    "invalid_var",
+                    format!("Variables look like name=value, not {}", var),
+                ))
+            }
+        };
+        let value = parse_str(expression).and_then(|tree| Ok(tree.eval_with(&env)?));
+        let value =
+            value.map_err(|e| Failure::Input("invalid_var", format!("In --var {}: {}", var, e)))?;
+        env.set(name.trim(), value);
+    }
+    return Ok(env);
+}
+
+/// Prints the result, and gives whether it was truthy
+fn run(cli: &Cli) -> Result<bool, Failure> {
+    let format = cli.format();
+    let env = environment(cli)?;
+
+    let tree = parse_str(cli.expression())?;
+
+    if cli.simplify {
+        let simplified = Value::Expression(Box::new(tree.simplify_with(&env)));
+        match cli.output {
+            OutputFormat::Text => println!("{}", simplified),
+            OutputFormat::Json => {
+                println!("{}", json::result(cli.expression(), &simplified, &format))
+            }
+        }
+        return Ok(true);
+    }
+
+    if let Some(path) = &cli.csv {
+        run_csv(cli, path, &tree, &env, std::io::stdout())?;
+        return Ok(true);
+    }
+
+    let result = tree.eval_with(&env);
+    let result = result.map_err(|e| Error::from(e).locate(cli.expression()))?;
+
+    match cli.output {
+        OutputFormat::Text => println!("{}", format.format_value(&result)),
+        OutputFormat::Json => println!("{}", json::result(cli.expression(), &result, &format)),
+    }
+    return Ok(result.is_truthy());
+}
+
+/// Runs the statements in the file, printing the values of `print` statements
+fn run_script(cli: &Cli, path: &str, mut output: impl Write) -> Result<bool, Failure> {
+    let format = cli.format();
+    let mut env = environment(cli)?;
+    let text = if path == "-" {
+        let mut text = String::new();
+        std::io::stdin().read_to_string(&mut text).map(|_| text)
+    } else {
+        std::fs::read_to_string(path)
+    };
+    let text = text.map_err(|e| Failure::Input("file", format!("Can't read {}: {}", path, e)))?;
+
+    for source in script::split(&text) {
+        let fail = |e: Error| Failure::Script(path.to_string(), source.clone(), e);
+        let statement = Statement::parse(&source.text).map_err(fail)?;
+        let value = statement.run(&mut env);
+        let value = value.map_err(|e| fail(Error::from(e).locate(&source.text)))?;
+        if let (Statement::Print(tree), Some(value)) = (&statement, value) {
+            let line = match cli.output {
+                OutputFormat::Text => format.format_value(&value),
+                OutputFormat::Json => json::result(&tree.to_string(), &value, &format).to_string(),
+            };
+            writeln!(output, "{}", line)
+                .map_err(|e| Failure::Input("output", format!("Can't write: {}", e)))?;
+        }
+    }
+    return Ok(true);
+}
+
+/// Writes the error where the output format says it goes, with the file, line and column
+/// (from 1) for scripts
+fn report(cli: &Cli, failure: &Failure) {
+    let (kind, message, span) = match failure {
+        Failure::Expression(e) | Failure::Script(_, _, e) => (e.kind(), e.to_string(), e.span()),
+        Failure::Input(kind, message) => (*kind, message.clone(), None),
+    };
+    let location = match failure {
+        Failure::Script(path, source, _) => {
+            let column = source.column + span.as_ref().map_or(0, |span| span.start);
+            Some((path, source.line + 1, column + 1, &source.text))
+        }
+        _ => None,
+    };
+    if cli.output == OutputFormat::Json {
+        let mut error = json::report(kind, &message, span);
+        if let Some((path, line, column, _)) = location {
+            error["file"] = path.as_str().into();
+            error["line"] = line.into();
+            error["column"] = column.into();
+        }
+        println!("{}", serde_json::json!({ "error": error }));
+        return;
+    }
+
+    match location {
+        Some((path, line, column, text)) => {
+            eprintln!("Error: {}:{}:{}: {}", path, line, column, message);
+            eprintln!("  {}", text);
+        }
+        None => {
+            eprintln!("Error: {}", message);
+            if span.is_some() {
+                eprintln!("  {}", cli.expression());
+            }
+        }
+    }
+    if let Some(span) = span {
+        eprintln!(
+            "  {}{}",
+            " ".repeat(span.start),
+            "^".repeat((span.end - span.start).max(1))
+        );
+    }
+}
+
+/// Runs the server until stdin is closed, or forever for a socket
+fn serve(listen: &Option<String>, socket: &Option<String>) -> std::io::Result<()> {
+    if let Some(address) = listen {
+        return server::serve_tcp(address);
+    }
+    if let Some(path) = socket {
+        #[cfg(unix)]
+        return server::serve_unix(path);
+        #[cfg(not(unix))]
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            format!("Unix sockets like {} aren't supported here", path),
+        ));
+    }
+    return server::serve(std::io::stdin().lock(), std::io::stdout());
 }
 
 fn main() {
     let cli = Cli::parse();
 
-    let tokens = tokenise(cli.expression);
-    let tokens = tokens.expect("Failed to tokenise expression");
+    match &cli.command {
+        Some(Command::Serve { listen, socket }) => {
+            if let Err(e) = serve(listen, socket) {
+                eprintln!("Error: {}", e);
+                std::process::exit(INPUT_ERROR);
+            }
+            return;
+        }
+        Some(Command::Lsp) => {
+            if let Err(e) = lsp::serve(std::io::stdin().lock(), std::io::stdout()) {
+                eprintln!("Error: {}", e);
+                std::process::exit(INPUT_ERROR);
+            }
+            return;
+        }
+        _ => {}
+    }
+
+    let result = match &cli.command {
+        Some(Command::Run { file }) => run_script(&cli, file, std::io::stdout()),
+        _ => run(&cli),
+    };
+    match result {
+        // Like `test`, so `calculator "load > 0.8" && alert` works
+        Ok(truthy) if !truthy => std::process::exit(FALSY),
+        Ok(_) => {}
+        Err(failure) => {
+            report(&cli, &failure);
+            std::process::exit(failure.exit_code());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Tests run in parallel, so each CSV gets its own file
+    static FILES: AtomicUsize = AtomicUsize::new(0);
+
+    /// Runs the command line over a CSV and gives what it writes
+    fn csv(args: &[&str], input: &str) -> Result<String, Failure> {
+        let path = std::env::temp_dir().join(format!(
+            "calculator-{}-{}.csv",
+            std::process::id(),
+            FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::write(&path, input).unwrap();
+        let path = path.to_str().unwrap();
+        let cli = Cli::parse_from(["calculator", "--csv", path].iter().chain(args));
+        let tree = parse_str(cli.expression()).unwrap();
+        let env = environment(&cli)?;
+        let mut output = vec![];
+        let result = run_csv(&cli, path, &tree, &env, &mut output);
+        std::fs::remove_file(path).unwrap();
+        result?;
+        return Ok(String::from_utf8(output).unwrap());
+    }
+
+    #[test]
+    fn csv_columns_are_variables() {
+        let output = csv(&["--var", "k=10", "x * y + k"], "x,y,name\n1, 2,a\n3,4,b\n");
+        assert_eq!(
+            output.ok().unwrap(),
+            "x,y,name,result\n1, 2,a,12\n3,4,b,22\n"
+        );
+        let output = csv(&["--column", "area", "-d", "1", "pi * r^2"], "r\n1\n");
+        assert_eq!(output.ok().unwrap(), "r,area\n1,3.1\n");
+    }
+
+    #[test]
+    fn csv_errors() {
+        let missing = csv(&["x + z"], "x\n1\n");
+        assert!(matches!(missing, Err(Failure::Input("csv", _))));
+        let not_numbers = csv(&["x"], "x\nfoo\n");
+        assert!(matches!(not_numbers, Err(Failure::Input("csv", _))));
+        let division = csv(&["1 / x"], "x\n1\n0\n");
+        assert!(matches!(division, Err(Failure::Expression(_))));
+
+        // The same, for expressions that can't be compiled
+        let missing = csv(&["max(x, z)"], "x\n1\n");
+        assert!(matches!(missing, Err(Failure::Input("csv", _))));
+        let not_numbers = csv(&["max(x, 1)"], "x\n1\nfoo\n");
+        assert!(matches!(not_numbers, Err(Failure::Input("csv", _))));
+    }
+
+    #[test]
+    fn csv_without_compiling() {
+        let input = "x,y,name\n1,4,a\n6,2,b\n";
+        let output = csv(&["max(x, y) + gcd(x, 4)"], input).ok().unwrap();
+        assert_eq!(output, "x,y,name,result\n1,4,a,5\n6,2,b,8\n");
+        let output = csv(&["sum(k * x, k, 1, 3)"], input).ok().unwrap();
+        assert_eq!(output, "x,y,name,result\n1,4,a,6\n6,2,b,36\n");
+        let output = csv(&["-d", "4", "normcdf(x, 0, 1)"], "x\n0\n")
+            .ok()
+            .unwrap();
+        assert_eq!(output, "x,result\n0,0.5000\n");
+    }
+
+    /// Runs the script with the command line and gives what it prints
+    fn script(args: &[&str], text: &str) -> Result<String, Failure> {
+        let path = std::env::temp_dir().join(format!(
+            "calculator-{}-{}.calc",
+            std::process::id(),
+            FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::write(&path, text).unwrap();
+        let path = path.to_str().unwrap();
+        let cli = Cli::parse_from(["calculator"].iter().chain(args).chain(&["run", path]));
+        let mut output = vec![];
+        let result = run_script(&cli, path, &mut output);
+        std::fs::remove_file(path).unwrap();
+        result?;
+        return Ok(String::from_utf8(output).unwrap());
+    }
 
-    let tree = parse(&tokens);
-    let tree = tree.expect("Failed to parse expression");
+    #[test]
+    fn scripts_print_in_order() {
+        let text = "rent = 1200 # a month\nf(x) = x * 12; print f(rent)\nassert rent < 2000\n";
+        assert_eq!(script(&[], text).ok().unwrap(), "14400\n");
+        let json = script(&["--format", "json"], "print 1 + 1").ok().unwrap();
+        assert!(json.contains("\"expression\":\"1 + 1\""));
+    }
 
-    let result = tree.eval();
-    let result = result.expect("Failed to evaluate expression");
+    #[test]
+    fn script_errors_say_where() {
+        let failure = script(&[], "x = 1\nprint x; print y + 1");
+        let (source, error) = match failure {
+            Err(Failure::Script(_, source, error)) => (source, error),
+            _ => panic!("The script should fail"),
+        };
+        assert_eq!((source.line, source.column), (1, 9));
+        assert_eq!(error.kind(), "unknown_variable");
+        assert_eq!(error.span(), Some(6..7));
 
-    println!("{}", result);
+        let failure = script(&[], "assert 1 > 2");
+        assert!(matches!(failure, Err(Failure::Script(..))));
+        let missing = run_script(
+            &Cli::parse_from(["calculator", "run", "-"]),
+            "/no/file.calc",
+            vec![],
+        );
+        assert!(matches!(missing, Err(Failure::Input("file", _))));
+    }
 }
@@ -1,16 +1,516 @@
+use std::f64::consts::PI;
+
+use crate::value::EvalError;
+
 #[allow(non_snake_case)] // Math functions are usually written in camel case
-pub fn nCr(n: f64, r: f64) -> f64 {
-    return factorial(n) / (factorial(r) * factorial(n - r));
+pub fn nCr(n: f64, r: f64) -> Result<f64, EvalError> {
+    // n! / (r! (n - r)!)
+    return gamma_ratio(n + 1.0, &[r + 1.0, n - r + 1.0], "nCr");
 }
 
 #[allow(non_snake_case)]
-pub fn nPr(n: f64, r: f64) -> f64 {
-    return factorial(n) / factorial(n - r);
+pub fn nPr(n: f64, r: f64) -> Result<f64, EvalError> {
+    // n! / (n - r)!
+    return gamma_ratio(n + 1.0, &[n - r + 1.0], "nPr");
+}
+
+/// `x!`, which is `gamma(x + 1)` so it works on more than whole numbers
+pub fn factorial(n: f64) -> Result<f64, EvalError> {
+    if is_pole(n + 1.0) {
+        return Err(EvalError::Domain(format!(
+            "Factorial isn't defined at {}",
+            n
+        )));
+    }
+    return gamma(n + 1.0);
+}
+
+/// Γ is infinite at 0 and the negative integers
+fn is_pole(x: f64) -> bool {
+    return x <= 0.0 && x.fract() == 0.0;
+}
+
+fn pole(name: &str, x: f64) -> EvalError {
+    return EvalError::Domain(format!("{} isn't defined at {}", name, x));
+}
+
+/// The sign of Γ(x), which flips between each of the negative integers
+fn gamma_sign(x: f64) -> f64 {
+    if x > 0.0 || x.floor() % 2.0 == 0.0 {
+        return 1.0;
+    }
+    return -1.0;
+}
+
+/// Γ(top) / (Γ(bottom[0]) Γ(bottom[1]) ...), in logs so it doesn't overflow in the middle
+fn gamma_ratio(top: f64, bottom: &[f64], name: &str) -> Result<f64, EvalError> {
+    if is_pole(top) {
+        return Err(EvalError::Domain(format!(
+            "{} isn't defined for negative integers",
+            name
+        )));
+    }
+    // Dividing by infinity (ex: 3 nCr 5)
+    if bottom.iter().any(|b| is_pole(*b)) {
+        return Ok(0.0);
+    }
+
+    // Small enough that none of them overflow, which is more accurate than logs
+    let small = |x: f64| x.abs() < 170.0;
+    let result = if small(top) && bottom.iter().all(|b| small(*b)) {
+        let mut result = gamma(top)?;
+        for b in bottom {
+            result /= gamma(*b)?;
+        }
+        result
+    } else {
+        let mut ln = ln_gamma(top);
+        let mut sign = gamma_sign(top);
+        for b in bottom {
+            ln -= ln_gamma(*b);
+            sign *= gamma_sign(*b);
+        }
+        sign * ln.exp()
+    };
+
+    // Whole numbers give a whole number, so get rid of the rounding errors from the logs
+    let whole = top.fract() == 0.0 && bottom.iter().all(|b| b.fract() == 0.0);
+    if whole && result.abs() < 2f64.powi(53) {
+        return Ok(result.round());
+    }
+    return Ok(result);
+}
+
+/// The gamma function, Γ(n) = (n - 1)! for whole numbers
+pub fn gamma(x: f64) -> Result<f64, EvalError> {
+    if is_pole(x) {
+        return Err(pole("gamma", x));
+    }
+    if x.fract() == 0.0 && x <= 171.0 {
+        // Exact for whole numbers, as long as the product fits
+        let mut product = 1.0;
+        let mut i = 2.0;
+        while i < x {
+            product *= i;
+            i += 1.0;
+        }
+        return Ok(product);
+    }
+    if x < 0.5 {
+        // Reflection: Γ(x) Γ(1 - x) = π / sin(πx)
+        return Ok(PI / ((PI * x).sin() * gamma(1.0 - x)?));
+    }
+    if x > 171.7 {
+        return Ok(f64::INFINITY);
+    }
+
+    let x = x - 1.0;
+    let t = x + 7.5;
+    // t^(x + 0.5) can overflow even when the answer doesn't, so it's done in two halves
+    let half = t.powf((x + 0.5) / 2.0);
+    return Ok((2.0 * PI).sqrt() * half * (half * (-t).exp()) * lanczos_sum(x));
+}
+
+/// ln(|Γ(x)|), which stays finite long after Γ(x) overflows
+pub fn lgamma(x: f64) -> Result<f64, EvalError> {
+    if is_pole(x) {
+        return Err(pole("lgamma", x));
+    }
+    return Ok(ln_gamma(x));
+}
+
+/// The beta function, B(a, b) = Γ(a) Γ(b) / Γ(a + b)
+pub fn beta(a: f64, b: f64) -> Result<f64, EvalError> {
+    if is_pole(a) || is_pole(b) {
+        return Err(pole("beta", if is_pole(a) { a } else { b }));
+    }
+    if is_pole(a + b) {
+        return Ok(0.0);
+    }
+    if a.abs() < 170.0 && b.abs() < 170.0 && (a + b).abs() < 170.0 {
+        return Ok(gamma(a)? * gamma(b)? / gamma(a + b)?);
+    }
+    let sign = gamma_sign(a) * gamma_sign(b) * gamma_sign(a + b);
+    return Ok(sign * (ln_gamma(a) + ln_gamma(b) - ln_gamma(a + b)).exp());
+}
+
+/// Small enough to stand in for 0 in the continued fractions, without dividing by 0
+const TINY: f64 = 1e-300;
+
+/// How many terms the series and continued fractions can use before giving up
+const MAX_TERMS: usize = 1000;
+
+const LANCZOS: [f64; 9] = [
+    0.999_999_999_999_809_9,
+    676.520_368_121_885_1,
+    -1_259.139_216_722_402_8,
+    771.323_428_777_653_1,
+    -176.615_029_162_140_6,
+    12.507_343_278_686_905,
+    -0.138_571_095_265_720_12,
+    9.984_369_578_019_572e-6,
+    1.505_632_735_149_311_6e-7,
+];
+
+/// The series part of the Lanczos approximation, for x = z - 1
+fn lanczos_sum(x: f64) -> f64 {
+    let mut sum = LANCZOS[0];
+    for (i, c) in LANCZOS.iter().enumerate().skip(1) {
+        sum += c / (x + i as f64);
+    }
+    return sum;
+}
+
+/// ln(|Γ(x)|), with the Lanczos approximation
+pub fn ln_gamma(x: f64) -> f64 {
+    if x < 0.5 {
+        // Reflection: Γ(x) Γ(1 - x) = π / sin(πx)
+        return (PI / (PI * x).sin().abs()).ln() - ln_gamma(1.0 - x);
+    }
+    let x = x - 1.0;
+    let t = x + 7.5;
+    return 0.5 * (2.0 * PI).ln() + (x + 0.5) * t.ln() - t + lanczos_sum(x).ln();
+}
+
+/// Γ(a + b) / (Γ(a) Γ(b)), in logs so it doesn't overflow
+fn ln_beta_factor(a: f64, b: f64) -> f64 {
+    return ln_gamma(a + b) - ln_gamma(a) - ln_gamma(b);
+}
+
+/// The series for P(a, x), which converges quickly when x < a + 1
+fn gamma_series(a: f64, x: f64) -> f64 {
+    let mut term = 1.0 / a;
+    let mut sum = term;
+    for n in 1..MAX_TERMS {
+        term *= x / (a + n as f64);
+        sum += term;
+        if term.abs() < sum.abs() * f64::EPSILON {
+            return sum * (-x + a * x.ln() - ln_gamma(a)).exp();
+        }
+    }
+    return f64::NAN;
+}
+
+/// The continued fraction for Q(a, x), which converges quickly when x > a + 1
+fn gamma_fraction(a: f64, x: f64) -> f64 {
+    let mut b = x + 1.0 - a;
+    let mut c = 1.0 / TINY;
+    let mut d = 1.0 / b;
+    let mut h = d;
+    for i in 1..MAX_TERMS {
+        let an = -(i as f64) * (i as f64 - a);
+        b += 2.0;
+        d = an * d + b;
+        if d.abs() < TINY {
+            d = TINY;
+        }
+        c = b + an / c;
+        if c.abs() < TINY {
+            c = TINY;
+        }
+        d = 1.0 / d;
+        let delta = d * c;
+        h *= delta;
+        if (delta - 1.0).abs() < f64::EPSILON {
+            return (-x + a * x.ln() - ln_gamma(a)).exp() * h;
+        }
+    }
+    return f64::NAN;
+}
+
+/// The regularized lower incomplete gamma function P(a, x)
+pub fn gamma_p(a: f64, x: f64) -> f64 {
+    if x <= 0.0 {
+        return 0.0;
+    }
+    if x < a + 1.0 {
+        return gamma_series(a, x);
+    }
+    return 1.0 - gamma_fraction(a, x);
+}
+
+/// The regularized upper incomplete gamma function Q(a, x) = 1 - P(a, x),
+/// worked out directly so it's still accurate when it's tiny
+pub fn gamma_q(a: f64, x: f64) -> f64 {
+    if x <= 0.0 {
+        return 1.0;
+    }
+    if x < a + 1.0 {
+        return 1.0 - gamma_series(a, x);
+    }
+    return gamma_fraction(a, x);
+}
+
+/// The continued fraction for the incomplete beta function (see `beta_inc`)
+fn beta_fraction(a: f64, b: f64, x: f64) -> f64 {
+    let mut c = 1.0;
+    let mut d = 1.0 - (a + b) * x / (a + 1.0);
+    if d.abs() < TINY {
+        d = TINY;
+    }
+    d = 1.0 / d;
+    let mut h = d;
+    for m in 1..MAX_TERMS {
+        let m = m as f64;
+        let m2 = 2.0 * m;
+
+        // Even step
+        let aa = m * (b - m) * x / ((a - 1.0 + m2) * (a + m2));
+        d = 1.0 + aa * d;
+        if d.abs() < TINY {
+            d = TINY;
+        }
+        c = 1.0 + aa / c;
+        if c.abs() < TINY {
+            c = TINY;
+        }
+        d = 1.0 / d;
+        h *= d * c;
+
+        // Odd step
+        let aa = -(a + m) * (a + b + m) * x / ((a + m2) * (a + 1.0 + m2));
+        d = 1.0 + aa * d;
+        if d.abs() < TINY {
+            d = TINY;
+        }
+        c = 1.0 + aa / c;
+        if c.abs() < TINY {
+            c = TINY;
+        }
+        d = 1.0 / d;
+        let delta = d * c;
+        h *= delta;
+        if (delta - 1.0).abs() < f64::EPSILON {
+            return h;
+        }
+    }
+    return f64::NAN;
 }
 
-pub fn factorial(n: f64) -> f64 {
-    if n == 0.0 {
+/// The regularized incomplete beta function I_x(a, b)
+pub fn beta_inc(a: f64, b: f64, x: f64) -> f64 {
+    if x <= 0.0 {
+        return 0.0;
+    }
+    if x >= 1.0 {
         return 1.0;
     }
-    return n * factorial(n - 1.0);
+    let front = (ln_beta_factor(a, b) + a * x.ln() + b * (1.0 - x).ln()).exp();
+    // The fraction converges quickly on one side, so use the symmetry I_x(a, b) = 1 - I_(1-x)(b, a)
+    if x < (a + 1.0) / (a + b + 2.0) {
+        return front * beta_fraction(a, b, x) / a;
+    }
+    return 1.0 - front * beta_fraction(b, a, 1.0 - x) / b;
+}
+
+/// The complementary error function, 1 - erf(x)
+pub fn erfc(x: f64) -> f64 {
+    if x < 0.0 {
+        return 2.0 - erfc(-x);
+    }
+    return gamma_q(0.5, x * x);
+}
+
+/// How `round` picks between the two closest values
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rounding {
+    /// Halves go away from 0 (ex: 2.5 to 3 and -2.5 to -3), which is the default
+    HalfUp,
+    /// Halves go towards 0
+    HalfDown,
+    /// Halves go to the even one (ex: 2.5 to 2 and 3.5 to 4), which is banker's rounding
+    HalfEven,
+    /// Always away from 0
+    Up,
+    /// Always towards 0
+    Down,
+}
+
+impl Rounding {
+    /// The mode for the name used in `round(x, 2, half_even)`
+    pub fn from_name(name: &str) -> Result<Rounding, EvalError> {
+        return match name {
+            "half_up" => Ok(Rounding::HalfUp),
+            "half_down" => Ok(Rounding::HalfDown),
+            "half_even" => Ok(Rounding::HalfEven),
+            "up" => Ok(Rounding::Up),
+            "down" => Ok(Rounding::Down),
+            _ => Err(EvalError::Domain(format!(
+                "{} isn't a rounding mode (half_up, half_down, half_even, up or down)",
+                name
+            ))),
+        };
+    }
+
+    /// Rounds to a whole number
+    fn apply(self, x: f64) -> f64 {
+        let is_half = (x - x.trunc()).abs() == 0.5;
+        return match self {
+            Rounding::HalfUp => x.round(),
+            Rounding::HalfDown if is_half => x.trunc(),
+            Rounding::HalfDown => x.round(),
+            Rounding::HalfEven => x.round_ties_even(),
+            Rounding::Up if x < 0.0 => x.floor(),
+            Rounding::Up => x.ceil(),
+            Rounding::Down => x.trunc(),
+        };
+    }
+}
+
+/// Gets rid of the error from writing a decimal in binary, so `2.675 * 100` is 267.5 again
+/// instead of 267.49999999999997 (15 significant figures is as many as an f64 always keeps)
+fn snap(x: f64) -> f64 {
+    if !x.is_finite() || x == 0.0 {
+        return x;
+    }
+    return format!("{:.14e}", x).parse().unwrap_or(x);
+}
+
+fn whole(n: f64, what: &str) -> Result<i32, EvalError> {
+    if n.fract() != 0.0 || n.abs() > 400.0 {
+        return Err(EvalError::Domain(format!(
+            "The number of {} has to be a whole number, not {}",
+            what, n
+        )));
+    }
+    return Ok(n as i32);
+}
+
+/// 10^n, as close as an f64 gets (unlike `10_f64.powi(n)`), or infinity if it's too big
+fn power_of_ten(n: i32) -> f64 {
+    return format!("1e{}", n).parse().unwrap_or(f64::INFINITY);
+}
+
+/** round
+
+`round(x, decimals)`, where negative decimals round to tens, hundreds, ...
+
+Algorithm:
+1. If the digit to round at is past the 17 significant figures an f64 can have, x stays as it is
+2. Otherwise multiply by 10^decimals, round to a whole number, and divide by it again.
+   That can only overflow when x is tiny, so then it's done in two smaller steps.
+3. For negative decimals, divide by 10^-decimals and multiply by it again. When that's
+   too big for an f64, x is much smaller than it, so it rounds to 0 (or to infinity for `up`).
+*/
+pub fn round(x: f64, decimals: f64, mode: Rounding) -> Result<f64, EvalError> {
+    let decimals = whole(decimals, "decimals")?;
+    if x == 0.0 || !x.is_finite() {
+        return Ok(x);
+    }
+    let magnitude = x.abs().log10().floor() as i32;
+    if decimals >= 17 - magnitude {
+        return Ok(x);
+    }
+
+    // Dividing by a power of 10 is exact when multiplying by its inverse isn't
+    if decimals >= 0 {
+        let first = decimals.min(300);
+        let (big, rest) = (power_of_ten(first), power_of_ten(decimals - first));
+        return Ok(mode.apply(snap(x * big * rest)) / rest / big);
+    }
+    let scale = power_of_ten(-decimals);
+    if scale.is_infinite() {
+        // Only whether it's 0 or not, and its sign, matter
+        let rounded = mode.apply(x.signum() * f64::MIN_POSITIVE);
+        return Ok(if rounded == 0.0 { 0.0 } else { rounded * scale });
+    }
+    return Ok(mode.apply(snap(x / scale)) * scale);
+}
+
+/// `round_sig(x, figures)`, like `round_sig(123456, 2)` is 120000
+pub fn round_sig(x: f64, figures: f64, mode: Rounding) -> Result<f64, EvalError> {
+    if figures < 1.0 {
+        return Err(EvalError::Domain(format!(
+            "There has to be at least 1 significant figure, not {}",
+            figures
+        )));
+    }
+    if x == 0.0 || !x.is_finite() {
+        return Ok(x);
+    }
+    let figures = whole(figures, "significant figures")?;
+    let magnitude = snap(x).abs().log10().floor() as i32;
+    return round(x, (figures - 1 - magnitude) as f64, mode);
+}
+
+/// `roundto(x, step)`, the closest multiple of `step` (ex: `roundto(1.12, 0.05)` is 1.1)
+pub fn round_to(x: f64, step: f64, mode: Rounding) -> Result<f64, EvalError> {
+    if step == 0.0 {
+        return Err(EvalError::DivisionByZero);
+    }
+    return Ok(snap(mode.apply(snap(x / step)) * step));
+}
+
+/// -1, 0 or 1 (unlike `f64::signum`, which is 1 for 0)
+pub fn sign(x: f64) -> f64 {
+    if x == 0.0 {
+        return 0.0;
+    }
+    return x.signum();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        return ((a - b) / b).abs() < 1e-12;
+    }
+
+    #[test]
+    fn factorials() {
+        assert_eq!(factorial(5.0).unwrap(), 120.0);
+        assert_eq!(factorial(0.0).unwrap(), 1.0);
+        assert!(close(factorial(0.5).unwrap(), PI.sqrt() / 2.0));
+        assert!(close(factorial(-0.5).unwrap(), PI.sqrt()));
+        assert!(close(factorial(170.0).unwrap(), 7.257415615307994e306));
+        assert!(matches!(factorial(-1.0), Err(EvalError::Domain(_))));
+    }
+
+    #[test]
+    fn gamma_function() {
+        assert_eq!(gamma(5.0).unwrap(), 24.0);
+        assert!(close(gamma(-1.5).unwrap(), 4.0 * PI.sqrt() / 3.0));
+        assert!(gamma(0.0).is_err());
+        assert!(gamma(-2.0).is_err());
+        // ln(99!)
+        assert!(close(lgamma(100.0).unwrap(), 359.1342053695754));
+        assert!(close(beta(2.0, 3.0).unwrap(), 1.0 / 12.0));
+    }
+
+    #[test]
+    fn combinations() {
+        assert_eq!(nCr(5.0, 2.0).unwrap(), 10.0);
+        assert_eq!(nPr(5.0, 2.0).unwrap(), 20.0);
+        assert_eq!(nCr(50.0, 25.0).unwrap(), 126410606437752.0);
+    }
+
+    #[test]
+    fn rounding() {
+        assert_eq!(round(2.675, 2.0, Rounding::HalfUp).unwrap(), 2.68);
+        assert_eq!(round(1234.5, -2.0, Rounding::HalfUp).unwrap(), 1200.0);
+        assert_eq!(round(-2.5, 0.0, Rounding::HalfEven).unwrap(), -2.0);
+        assert_eq!(round(2.5, 0.0, Rounding::HalfDown).unwrap(), 2.0);
+        assert_eq!(round(-1.21, 1.0, Rounding::Up).unwrap(), -1.3);
+        assert_eq!(round(0.1 + 0.2, 16.0, Rounding::HalfUp).unwrap(), 0.3);
+        assert_eq!(
+            round_sig(123456.0, 2.0, Rounding::HalfUp).unwrap(),
+            120000.0
+        );
+        assert_eq!(round_to(1.12, 0.05, Rounding::HalfUp).unwrap(), 1.1);
+        assert!(round(1.0, 0.5, Rounding::HalfUp).is_err());
+    }
+
+    #[test]
+    fn rounding_far_away() {
+        let half_up = |x, decimals| round(x, decimals, Rounding::HalfUp).unwrap();
+        // Past the precision of x, so nothing changes
+        assert_eq!(half_up(1e200, 200.0), 1e200);
+        assert_eq!(half_up(1.5, 320.0), 1.5);
+        assert_eq!(half_up(1e-5, 309.0), 1e-5);
+        assert_eq!(half_up(1.23456e-310, 312.0), 1.23e-310);
+        assert_eq!(half_up(1e300, -300.0), 1e300);
+        assert_eq!(half_up(123.0, -400.0), 0.0);
+        assert_eq!(round(123.0, -400.0, Rounding::Up).unwrap(), f64::INFINITY);
+    }
 }
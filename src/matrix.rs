@@ -0,0 +1,421 @@
+//! Vectors and matrices.
+//!
+//! A vector is a list of numbers (ex: `[1, 2]`), and a matrix is a list of rows
+//! that are all vectors of the same length (ex: `[[1, 2], [3, 4]]`).
+
+use crate::{
+    time,
+    value::{EvalError, Value},
+};
+
+type Matrix = Vec<Vec<f64>>;
+
+/// A list where every item is a list, which has to be a matrix
+fn is_matrix(value: &Value) -> bool {
+    return match value {
+        Value::List(rows) => !rows.is_empty() && rows.iter().all(|r| matches!(r, Value::List(_))),
+        _ => false,
+    };
+}
+
+fn wrong_type(name: &str, expected: &str, value: &Value) -> EvalError {
+    return EvalError::WrongType(format!(
+        "{} needs a {}, not a {}",
+        name,
+        expected,
+        value.type_name()
+    ));
+}
+
+fn vector(name: &str, value: &Value) -> Result<Vec<f64>, EvalError> {
+    return match value {
+        Value::List(items) => items.iter().map(|v| v.as_number()).collect(),
+        _ => Err(wrong_type(name, "vector", value)),
+    };
+}
+
+fn matrix(name: &str, value: &Value) -> Result<Matrix, EvalError> {
+    let rows = match value {
+        Value::List(rows) if is_matrix(value) => rows,
+        _ => return Err(wrong_type(name, "matrix", value)),
+    };
+    let rows = rows
+        .iter()
+        .map(|row| vector(name, row))
+        .collect::<Result<Matrix, _>>()?;
+    if rows[0].is_empty() || rows.iter().any(|row| row.len() != rows[0].len()) {
+        return Err(EvalError::MismatchedShapes(
+            "The rows of a matrix have to be the same length, and not empty".to_string(),
+        ));
+    }
+    return Ok(rows);
+}
+
+/// Like `matrix`, but it has to have as many rows as columns
+fn square(name: &str, value: &Value) -> Result<Matrix, EvalError> {
+    let m = matrix(name, value)?;
+    if m.len() != m[0].len() {
+        return Err(EvalError::MismatchedShapes(format!(
+            "{} needs a square matrix, not a {} one",
+            name,
+            shape(&m)
+        )));
+    }
+    return Ok(m);
+}
+
+/// Rows by columns (ex: `2x3`)
+fn shape(m: &Matrix) -> String {
+    return format!("{}x{}", m.len(), m[0].len());
+}
+
+fn from_vector(v: Vec<f64>) -> Value {
+    return Value::List(v.into_iter().map(Value::Number).collect());
+}
+
+fn from_matrix(m: Matrix) -> Value {
+    return Value::List(m.into_iter().map(from_vector).collect());
+}
+
+/** reduce
+
+Puts the first `cols` columns of `m` into reduced row echelon form, doing the same
+to any columns after them (so `[A | b]` becomes `[I | x]` when A can be inverted).
+Returns the rank and the determinant of those columns.
+
+Algorithm (Gauss-Jordan elimination):
+1. For each column, pick the row below the ones already done with the biggest value in it
+2. If that's (close to) 0, the column doesn't add to the rank, so go to the next one
+3. Otherwise, swap it up, divide it by that value, and subtract it from every other row
+   so the rest of the column is 0
+*/
+fn reduce(m: &mut Matrix, cols: usize) -> (usize, f64) {
+    // Anything this small compared to the biggest value in its column is rounding error.
+    // Each column has its own, so a column of tiny values isn't taken for zeros.
+    let tolerances = (0..cols)
+        .map(|col| {
+            let biggest = m.iter().fold(0.0, |a: f64, row| a.max(row[col].abs()));
+            biggest * m.len().max(cols) as f64 * f64::EPSILON
+        })
+        .collect::<Vec<_>>();
+
+    let mut rank = 0;
+    let mut det = 1.0;
+    for col in 0..cols {
+        if rank == m.len() {
+            break;
+        }
+        let pivot = (rank..m.len())
+            .max_by(|i, j| m[*i][col].abs().total_cmp(&m[*j][col].abs()))
+            .unwrap();
+        if m[pivot][col].abs() <= tolerances[col] {
+            det = 0.0;
+            continue;
+        }
+        if pivot != rank {
+            m.swap(pivot, rank);
+            det = -det;
+        }
+
+        let p = m[rank][col];
+        det *= p;
+        for x in &mut m[rank] {
+            *x /= p;
+        }
+        let pivot_row = m[rank].clone();
+        for (i, row) in m.iter_mut().enumerate() {
+            let factor = row[col];
+            if i == rank || factor == 0.0 {
+                continue;
+            }
+            for (x, p) in row.iter_mut().zip(&pivot_row) {
+                *x -= factor * p;
+            }
+        }
+        rank += 1;
+    }
+    if rank < cols {
+        det = 0.0;
+    }
+    return (rank, det);
+}
+
+/// Makes sure two lists can be added together, where a matrix can only be added to a
+/// matrix of the same shape
+fn same_shape(a: &Value, b: &Value, verb: &str) -> Result<(), EvalError> {
+    if !matches!((a, b), (Value::List(_), Value::List(_))) || !(is_matrix(a) || is_matrix(b)) {
+        return Ok(());
+    }
+    let describe = |v: &Value| match matrix(verb, v) {
+        Ok(m) => Ok(format!("a {} matrix", shape(&m))),
+        Err(EvalError::WrongType(_)) => {
+            Ok(format!("a vector of length {}", vector(verb, v)?.len()))
+        }
+        Err(e) => Err(e),
+    };
+    let (a, b) = (describe(a)?, describe(b)?);
+    if a != b {
+        return Err(EvalError::MismatchedShapes(format!(
+            "Can't {} {} and {}",
+            verb, a, b
+        )));
+    }
+    return Ok(());
+}
+
+/// `a + b`, item by item
+pub fn add(a: Value, b: Value) -> Result<Value, EvalError> {
+    same_shape(&a, &b, "add")?;
+    return Value::broadcast(a, b, |a, b| match (a, b) {
+        (Value::Percent(a), Value::Percent(b)) => Ok(Value::Percent(a + b)),
+        (a, Value::Percent(p)) => a.change_by_percent(p),
+        (a, b) => time::add(a, b),
+    });
+}
+
+/// `a - b`, item by item
+pub fn subtract(a: Value, b: Value) -> Result<Value, EvalError> {
+    same_shape(&a, &b, "subtract")?;
+    return Value::broadcast(a, b, |a, b| match (a, b) {
+        (Value::Percent(a), Value::Percent(b)) => Ok(Value::Percent(a - b)),
+        // 0 - x is how the parser writes -x
+        (Value::Number(0.0), Value::Percent(p)) => Ok(Value::Percent(-p)),
+        (a, Value::Percent(p)) => a.change_by_percent(-p),
+        (a, b) => time::subtract(a, b),
+    });
+}
+
+/// `a * b`, which is the matrix product when either side is a matrix.
+/// A vector is a column on the right of a matrix and a row on the left of one.
+/// Anything else is multiplied item by item, so `[1, 2] * [3, 4]` is `[3, 8]`.
+pub fn multiply(a: Value, b: Value) -> Result<Value, EvalError> {
+    let (a_matrix, b_matrix) = (is_matrix(&a), is_matrix(&b));
+    let (a_list, b_list) = (matches!(a, Value::List(_)), matches!(b, Value::List(_)));
+    if !(a_matrix && b_list || a_list && b_matrix) {
+        return Value::broadcast(a, b, time::multiply);
+    }
+
+    // A vector becomes a 1xn matrix on the left or an nx1 matrix on the right
+    let left = if a_matrix {
+        matrix("*", &a)?
+    } else {
+        vec![vector("*", &a)?]
+    };
+    let right = if b_matrix {
+        matrix("*", &b)?
+    } else {
+        vector("*", &b)?.into_iter().map(|x| vec![x]).collect()
+    };
+    if left[0].len() != right.len() {
+        return Err(EvalError::MismatchedShapes(format!(
+            "Can't multiply a {} matrix by a {} matrix",
+            shape(&left),
+            shape(&right)
+        )));
+    }
+
+    let product: Matrix = left
+        .iter()
+        .map(|row| {
+            (0..right[0].len())
+                .map(|j| row.iter().zip(&right).map(|(x, r)| x * r[j]).sum())
+                .collect()
+        })
+        .collect();
+    if !a_matrix {
+        return Ok(from_vector(product.into_iter().next().unwrap()));
+    }
+    if !b_matrix {
+        return Ok(from_vector(product.into_iter().map(|r| r[0]).collect()));
+    }
+    return Ok(from_matrix(product));
+}
+
+/// `det(A)`
+pub fn det(args: &[Value]) -> Result<Value, EvalError> {
+    let mut m = square("det", &args[0])?;
+    let n = m.len();
+    return Ok(Value::Number(reduce(&mut m, n).1));
+}
+
+/// `inv(A)`, which is an error if A's determinant is 0
+pub fn inv(args: &[Value]) -> Result<Value, EvalError> {
+    let m = square("inv", &args[0])?;
+    let n = m.len();
+    // [A | I] becomes [I | A^-1]
+    let mut augmented: Matrix = m
+        .into_iter()
+        .enumerate()
+        .map(|(i, mut row)| {
+            row.extend((0..n).map(|j| if i == j { 1.0 } else { 0.0 }));
+            row
+        })
+        .collect();
+    if reduce(&mut augmented, n).0 < n {
+        return Err(EvalError::Domain(
+            "The matrix can't be inverted because its determinant is 0".to_string(),
+        ));
+    }
+    return Ok(from_matrix(
+        augmented.into_iter().map(|row| row[n..].to_vec()).collect(),
+    ));
+}
+
+/// `transpose(A)`, where a vector is treated as a row so it becomes a column
+pub fn transpose(args: &[Value]) -> Result<Value, EvalError> {
+    let m = match is_matrix(&args[0]) {
+        true => matrix("transpose", &args[0])?,
+        false => vec![vector("transpose", &args[0])?],
+    };
+    let transposed = (0..m[0].len())
+        .map(|j| m.iter().map(|row| row[j]).collect())
+        .collect();
+    return Ok(from_matrix(transposed));
+}
+
+/// `trace(A)`, the sum of the diagonal
+pub fn trace(args: &[Value]) -> Result<Value, EvalError> {
+    let m = square("trace", &args[0])?;
+    return Ok(Value::Number((0..m.len()).map(|i| m[i][i]).sum()));
+}
+
+/// `rank(A)`, how many of the rows (or columns) are linearly independent
+pub fn rank(args: &[Value]) -> Result<Value, EvalError> {
+    let mut m = matrix("rank", &args[0])?;
+    let cols = m[0].len();
+    return Ok(Value::Number(reduce(&mut m, cols).0 as f64));
+}
+
+/// Two vectors of the same length
+fn vector_pair(name: &str, args: &[Value]) -> Result<(Vec<f64>, Vec<f64>), EvalError> {
+    let (a, b) = (vector(name, &args[0])?, vector(name, &args[1])?);
+    if a.len() != b.len() {
+        return Err(EvalError::MismatchedLengths(a.len(), b.len()));
+    }
+    return Ok((a, b));
+}
+
+/// `dot(a, b)`
+pub fn dot(args: &[Value]) -> Result<Value, EvalError> {
+    let (a, b) = vector_pair("dot", args)?;
+    return Ok(Value::Number(a.iter().zip(&b).map(|(a, b)| a * b).sum()));
+}
+
+/// `cross(a, b)`, for vectors of length 3
+pub fn cross(args: &[Value]) -> Result<Value, EvalError> {
+    let (a, b) = vector_pair("cross", args)?;
+    if a.len() != 3 {
+        return Err(EvalError::MismatchedShapes(format!(
+            "cross needs vectors of length 3, not {}",
+            a.len()
+        )));
+    }
+    return Ok(from_vector(vec![
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]));
+}
+
+/// `norm(v)`, the length of a vector, or the Frobenius norm of a matrix
+pub fn norm(args: &[Value]) -> Result<Value, EvalError> {
+    let items = match is_matrix(&args[0]) {
+        true => matrix("norm", &args[0])?.concat(),
+        false => vector("norm", &args[0])?,
+    };
+    return Ok(Value::Number(
+        items.iter().map(|x| x * x).sum::<f64>().sqrt(),
+    ));
+}
+
+/// `solve(A, b)`, the x where `A * x = b`
+pub fn solve(a: &Value, b: &Value) -> Result<Value, EvalError> {
+    let a = square("solve", a)?;
+    let b = vector("solve", b)?;
+    let n = a.len();
+    if b.len() != n {
+        return Err(EvalError::MismatchedShapes(format!(
+            "Can't solve a {} matrix with a vector of length {}",
+            shape(&a),
+            b.len()
+        )));
+    }
+
+    // [A | b] becomes [I | x]
+    let mut augmented: Matrix = a
+        .into_iter()
+        .zip(b)
+        .map(|(mut row, b)| {
+            row.push(b);
+            row
+        })
+        .collect();
+    if reduce(&mut augmented, n).0 < n {
+        return Err(EvalError::Domain(
+            "The system doesn't have exactly one solution because the matrix's determinant is 0"
+                .to_string(),
+        ));
+    }
+    return Ok(from_vector(
+        augmented.into_iter().map(|row| row[n]).collect(),
+    ));
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{error::parse_str, value::EvalError};
+
+    fn eval(input: &str) -> Result<String, EvalError> {
+        return Ok(parse_str(input).unwrap().eval()?.to_string());
+    }
+
+    #[test]
+    fn arithmetic() {
+        let a = "[[1, 2], [3, 4]]";
+        assert_eq!(
+            eval(&format!("{} * [[0, 1], [1, 0]]", a)).unwrap(),
+            "[[2, 1], [4, 3]]"
+        );
+        assert_eq!(eval(&format!("{} * [1, 1]", a)).unwrap(), "[3, 7]");
+        assert_eq!(eval(&format!("{} + {}", a, a)).unwrap(), "[[2, 4], [6, 8]]");
+        assert_eq!(eval(&format!("2 * {}", a)).unwrap(), "[[2, 4], [6, 8]]");
+        assert!(matches!(
+            eval(&format!("{} * [[1, 2, 3]]", a)),
+            Err(EvalError::MismatchedShapes(_))
+        ));
+    }
+
+    #[test]
+    fn functions() {
+        assert_eq!(eval("det([[1, 2], [3, 4]])").unwrap(), "-2");
+        assert_eq!(
+            eval("transpose([[1, 2], [3, 4]])").unwrap(),
+            "[[1, 3], [2, 4]]"
+        );
+        assert_eq!(eval("trace([[1, 2], [3, 4]])").unwrap(), "5");
+        assert_eq!(eval("rank([[1, 2], [2, 4]])").unwrap(), "1");
+        assert_eq!(eval("dot([1, 2, 3], [4, 5, 6])").unwrap(), "32");
+        assert_eq!(eval("cross([1, 0, 0], [0, 1, 0])").unwrap(), "[0, 0, 1]");
+        assert_eq!(eval("norm([3, 4])").unwrap(), "5");
+        assert_eq!(
+            eval("solve([[2, 1], [1, 3]], [3, 5])").unwrap(),
+            "[0.8, 1.4]"
+        );
+        assert!(eval("inv([[1, 2], [2, 4]])").is_err());
+        let error = eval("dot([1, 2], [1, 2, 3])").unwrap_err();
+        assert_eq!(error.to_string(), "Lists of different lengths (2 and 3)");
+    }
+
+    #[test]
+    fn small_values_are_not_zeros() {
+        assert_eq!(
+            eval("det([[1e-20, 0], [0, 1]])").unwrap(),
+            "0.00000000000000000001"
+        );
+        assert_eq!(
+            eval("inv([[1e-20, 0], [0, 1]])").unwrap(),
+            "[[100000000000000000000, 0], [0, 1]]"
+        );
+        assert_eq!(eval("rank([[1e-20, 1], [2e-20, 2]])").unwrap(), "1");
+    }
+}
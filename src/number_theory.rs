@@ -0,0 +1,351 @@
+//! Number theory on whole numbers.
+//!
+//! Everything is worked out with integers instead of floats, so it's exact for every
+//! whole number a float can hold exactly (up to 2^53).
+
+use crate::{
+    parser::TreeNode,
+    value::{EvalError, Value},
+};
+
+/// The biggest whole number where every whole number below it is a float too
+const MAX_EXACT: f64 = 9_007_199_254_740_992.0;
+
+/// Bases that are enough for Miller-Rabin to never be wrong below 2^64
+const WITNESSES: [u128; 12] = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37];
+
+/// How many constants Pollard's rho tries before giving up. One is almost always enough.
+const RHO_TRIES: u128 = 100;
+
+fn integer(name: &str, x: f64) -> Result<i128, EvalError> {
+    if x.fract() != 0.0 || x.abs() > MAX_EXACT {
+        return Err(EvalError::Domain(format!(
+            "{} only works on whole numbers up to 2^53, not {}",
+            name, x
+        )));
+    }
+    return Ok(x as i128);
+}
+
+/// Like `integer`, but it also has to be at least `min`
+fn at_least(name: &str, x: f64, min: i128) -> Result<u128, EvalError> {
+    let n = integer(name, x)?;
+    if n < min {
+        return Err(EvalError::Domain(format!(
+            "{} needs a number that's at least {}, not {}",
+            name, min, n
+        )));
+    }
+    return Ok(n as u128);
+}
+
+/// The answer as a float, as long as it's still exact
+fn exact(name: &str, n: u128) -> Result<f64, EvalError> {
+    if n > MAX_EXACT as u128 {
+        return Err(EvalError::Domain(format!(
+            "The answer of {} is bigger than 2^53, so it can't be exact",
+            name
+        )));
+    }
+    return Ok(n as f64);
+}
+
+fn gcd_of(mut a: u128, mut b: u128) -> u128 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    return a;
+}
+
+fn pow_mod(mut base: u128, mut exponent: u128, modulus: u128) -> u128 {
+    let mut result = 1 % modulus;
+    base %= modulus;
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = result * base % modulus;
+        }
+        base = base * base % modulus;
+        exponent >>= 1;
+    }
+    return result;
+}
+
+/// The inverse of a mod m, if a and m don't share a factor
+fn inverse_mod(a: i128, m: i128) -> Option<i128> {
+    // Extended Euclid: keeps old_s * a = old_r (mod m)
+    let (mut old_r, mut r) = (a.rem_euclid(m), m);
+    let (mut old_s, mut s) = (1, 0);
+    while r != 0 {
+        let q = old_r / r;
+        (old_r, r) = (r, old_r - q * r);
+        (old_s, s) = (s, old_s - q * s);
+    }
+    if old_r != 1 {
+        return None;
+    }
+    return Some(old_s.rem_euclid(m));
+}
+
+/** prime
+
+Algorithm:
+1. Check small primes by dividing
+2. Write n - 1 as d * 2^s with d odd
+3. For each witness a, n is composite if a^d isn't 1 and squaring it up to s times never gives -1
+*/
+fn prime(n: u128) -> bool {
+    if n < 2 {
+        return false;
+    }
+    for p in WITNESSES {
+        if n.is_multiple_of(p) {
+            return n == p;
+        }
+    }
+
+    let s = (n - 1).trailing_zeros();
+    let d = (n - 1) >> s;
+    'witness: for a in WITNESSES {
+        let mut x = pow_mod(a, d, n);
+        if x == 1 || x == n - 1 {
+            continue;
+        }
+        for _ in 1..s {
+            x = x * x % n;
+            if x == n - 1 {
+                continue 'witness;
+            }
+        }
+        return false;
+    }
+    return true;
+}
+
+/// Some factor of n other than 1 and n, where n is odd and not prime (Pollard's rho)
+fn some_factor(n: u128) -> Result<u128, EvalError> {
+    for c in 1..=RHO_TRIES {
+        let f = |x: u128| (x * x + c) % n;
+        let (mut x, mut y, mut d) = (2, 2, 1);
+        while d == 1 {
+            x = f(x);
+            y = f(f(y));
+            d = gcd_of(x.abs_diff(y), n);
+        }
+        if d != n {
+            return Ok(d);
+        }
+    }
+    return Err(EvalError::TooManyIterations("factor".to_string()));
+}
+
+fn collect_factors(n: u128, factors: &mut Vec<u128>) -> Result<(), EvalError> {
+    if n == 1 {
+        return Ok(());
+    }
+    if prime(n) {
+        factors.push(n);
+        return Ok(());
+    }
+    let d = some_factor(n)?;
+    collect_factors(d, factors)?;
+    return collect_factors(n / d, factors);
+}
+
+/// The prime factors of n and how many times each one divides it, from smallest to biggest
+fn factorize(mut n: u128) -> Result<Vec<(u128, u32)>, EvalError> {
+    let mut factors = vec![];
+    // Small factors are faster to divide out than to find with Pollard's rho
+    for p in 2..1000 {
+        while n.is_multiple_of(p) {
+            factors.push(p);
+            n /= p;
+        }
+    }
+    collect_factors(n, &mut factors)?;
+    factors.sort();
+
+    let mut powers: Vec<(u128, u32)> = vec![];
+    for p in factors {
+        match powers.last_mut() {
+            Some((last, count)) if *last == p => *count += 1,
+            _ => powers.push((p, 1)),
+        }
+    }
+    return Ok(powers);
+}
+
+/// `gcd(a, b, ...)`
+pub fn gcd(args: &[f64]) -> Result<f64, EvalError> {
+    let mut result = 0;
+    for x in args {
+        result = gcd_of(result, integer("gcd", *x)?.unsigned_abs());
+    }
+    return Ok(result as f64);
+}
+
+/// `lcm(a, b, ...)`, which has to be at most 2^53
+pub fn lcm(args: &[f64]) -> Result<f64, EvalError> {
+    let mut result = 1;
+    for x in args {
+        let x = integer("lcm", *x)?.unsigned_abs();
+        if x == 0 {
+            return Ok(0.0);
+        }
+        // Both are at most 2^53, so this can't overflow
+        result = result / gcd_of(result, x) * x;
+        exact("lcm", result)?;
+    }
+    return Ok(result as f64);
+}
+
+/// `mod_pow(b, e, m)`, which is b^e mod m without working out b^e.
+/// A negative e uses the inverse of b.
+pub fn mod_pow(args: &[f64]) -> Result<f64, EvalError> {
+    let base = integer("mod_pow", args[0])?;
+    let exponent = integer("mod_pow", args[1])?;
+    let modulus = at_least("mod_pow", args[2], 1)? as i128;
+
+    let base = if exponent < 0 {
+        match inverse_mod(base, modulus) {
+            Some(inverse) => inverse,
+            None => {
+                return Err(EvalError::Domain(format!(
+                    "{} has no inverse mod {}",
+                    base, modulus
+                )))
+            }
+        }
+    } else {
+        base.rem_euclid(modulus)
+    };
+    let result = pow_mod(base as u128, exponent.unsigned_abs(), modulus as u128);
+    return Ok(result as f64);
+}
+
+/// `mod_inv(a, m)`, the x where a * x = 1 mod m
+pub fn mod_inv(args: &[f64]) -> Result<f64, EvalError> {
+    let a = integer("mod_inv", args[0])?;
+    let m = at_least("mod_inv", args[1], 1)? as i128;
+    return match inverse_mod(a, m) {
+        Some(inverse) => Ok(inverse as f64),
+        None => Err(EvalError::Domain(format!("{} has no inverse mod {}", a, m))),
+    };
+}
+
+pub fn is_prime(args: &[f64]) -> Result<bool, EvalError> {
+    let n = integer("is_prime", args[0])?;
+    return Ok(n > 0 && prime(n as u128));
+}
+
+/// `next_prime(n)`, the smallest prime bigger than n, which has to be at most 2^53
+pub fn next_prime(args: &[f64]) -> Result<f64, EvalError> {
+    let n = integer("next_prime", args[0])?;
+    let mut candidate = n.max(1) as u128 + 1;
+    while !prime(candidate) {
+        candidate += 1;
+        exact("next_prime", candidate)?;
+    }
+    return exact("next_prime", candidate);
+}
+
+/// `factor(n)`, as an expression like `2^3 * 5`
+pub fn factor(args: &[f64]) -> Result<Value, EvalError> {
+    let n = integer("factor", args[0])?;
+    if n == 0 {
+        return Err(EvalError::Domain("0 can't be factored".to_string()));
+    }
+
+    let mut tree: Option<TreeNode> = None;
+    for (p, count) in factorize(n.unsigned_abs())? {
+        let mut power = TreeNode::number(p as f64);
+        if count > 1 {
+            power = TreeNode::operator("^", power, TreeNode::number(count as f64));
+        }
+        tree = Some(match tree {
+            Some(tree) => TreeNode::operator("*", tree, power),
+            None => power,
+        });
+    }
+
+    let mut tree = tree.unwrap_or(TreeNode::number(1.0));
+    if n < 0 {
+        tree = TreeNode::operator("-", TreeNode::number(0.0), tree);
+    }
+    return Ok(Value::Expression(Box::new(tree)));
+}
+
+/// `totient(n)`, how many numbers from 1 to n don't share a factor with n
+pub fn totient(args: &[f64]) -> Result<f64, EvalError> {
+    let n = at_least("totient", args[0], 1)?;
+    let mut result = n;
+    for (p, _) in factorize(n)? {
+        result = result / p * (p - 1);
+    }
+    return Ok(result as f64);
+}
+
+/// `a // b`, the division rounded down, which goes with `%`
+pub fn floor_divide(a: f64, b: f64) -> Result<f64, EvalError> {
+    if b == 0.0 {
+        return Err(EvalError::DivisionByZero);
+    }
+    if let (Ok(a), Ok(b)) = (integer("//", a), integer("//", b)) {
+        let q = a / b;
+        // Division of integers rounds towards 0, so fix it when the answer is negative
+        if a % b != 0 && (a < 0) != (b < 0) {
+            return Ok((q - 1) as f64);
+        }
+        return Ok(q as f64);
+    }
+    return Ok((a / b).floor());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn primes() {
+        assert!(is_prime(&[97.0]).unwrap());
+        assert!(!is_prime(&[1.0]).unwrap());
+        assert!(!is_prime(&[561.0]).unwrap());
+        assert_eq!(next_prime(&[13.0]).unwrap(), 17.0);
+        assert_eq!(
+            next_prime(&[MAX_EXACT - 200.0]).unwrap(),
+            9007199254740847.0
+        );
+        assert!(matches!(
+            next_prime(&[MAX_EXACT]),
+            Err(EvalError::Domain(_))
+        ));
+    }
+
+    #[test]
+    fn divisors() {
+        assert_eq!(gcd(&[12.0, -18.0]).unwrap(), 6.0);
+        assert_eq!(lcm(&[4.0, 6.0]).unwrap(), 12.0);
+        assert_eq!(lcm(&[0.0, 5.0]).unwrap(), 0.0);
+        assert!(matches!(
+            lcm(&[2f64.powi(40), 3f64.powi(30)]),
+            Err(EvalError::Domain(_))
+        ));
+        assert!(gcd(&[1.5, 3.0]).is_err());
+    }
+
+    #[test]
+    fn modular() {
+        assert_eq!(mod_pow(&[3.0, 200.0, 7.0]).unwrap(), 2.0);
+        assert_eq!(mod_pow(&[3.0, -1.0, 7.0]).unwrap(), 5.0);
+        assert_eq!(mod_inv(&[3.0, 7.0]).unwrap(), 5.0);
+        assert!(mod_inv(&[2.0, 4.0]).is_err());
+        assert_eq!(floor_divide(-7.0, 2.0).unwrap(), -4.0);
+    }
+
+    #[test]
+    fn factors() {
+        let factors = factor(&[MAX_EXACT - 1.0]).unwrap();
+        assert_eq!(factors.to_string(), "6361 * 69431 * 20394401");
+        assert_eq!(factor(&[-360.0]).unwrap().to_string(), "-2^3 * 3^2 * 5");
+        assert_eq!(totient(&[36.0]).unwrap(), 12.0);
+    }
+}
@@ -1,602 +1,823 @@
-use crate::tokeniser::Token;
+use std::fmt::Display;
+
+use crate::{
+    environment::Environment,
+    peeker::Peeker,
+    tokeniser::{get_thing, Token},
+    value::{EvalError, Value},
+};
 
 #[derive(Debug)]
 pub enum ParseError {
-    // UnknownOperator(char),
-    // UnknownFunctionOrVariable(String),
-    InvalidNumber(String),
     UnexpectedOperator(String),
     UnexpectedFunction(String),
-    // UnexpectedNumber,
-    // UnexpectedLParen,
+    UnexpectedValue(String),
+    UnexpectedKeyword(String),
+    UnexpectedComma,
+    UnexpectedLParen,
     UnexpectedRParen,
-} // TODO: figure out which of these are actually needed
+    UnclosedLParen,
+    UnexpectedLBracket,
+    UnexpectedRBracket,
+    UnclosedLBracket,
+    UnexpectedEnd,
+    /// Expected `then`, `else` or `:`
+    MissingKeyword(String),
+    /// Wrong number of arguments given to a function (name, number given)
+    WrongArgCount(String, usize),
+    /// A comparison right after another one, like `1 < x < 3` (the second one)
+    ChainedComparison(String),
+}
+
+impl ParseError {
+    /// A name for the kind of error that won't change (ex: `unclosed_lparen`)
+    pub fn kind(&self) -> &'static str {
+        return match self {
+            ParseError::UnexpectedOperator(_) => "unexpected_operator",
+            ParseError::UnexpectedFunction(_) => "unexpected_function",
+            ParseError::UnexpectedValue(_) => "unexpected_value",
+            ParseError::UnexpectedKeyword(_) => "unexpected_keyword",
+            ParseError::UnexpectedComma => "unexpected_comma",
+            ParseError::UnexpectedLParen => "unexpected_lparen",
+            ParseError::UnexpectedRParen => "unexpected_rparen",
+            ParseError::UnclosedLParen => "unclosed_lparen",
+            ParseError::UnexpectedLBracket => "unexpected_lbracket",
+            ParseError::UnexpectedRBracket => "unexpected_rbracket",
+            ParseError::UnclosedLBracket => "unclosed_lbracket",
+            ParseError::UnexpectedEnd => "unexpected_end",
+            ParseError::MissingKeyword(_) => "missing_keyword",
+            ParseError::WrongArgCount(..) => "wrong_arg_count",
+            ParseError::ChainedComparison(_) => "chained_comparison",
+        };
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return match self {
+            ParseError::UnexpectedOperator(name) => write!(f, "Unexpected operator '{}'", name),
+            ParseError::UnexpectedFunction(name) => write!(f, "Unexpected function '{}'", name),
+            ParseError::UnexpectedValue(value) => write!(f, "Unexpected value '{}'", value),
+            ParseError::UnexpectedKeyword(name) => write!(f, "Unexpected '{}'", name),
+            ParseError::UnexpectedComma => write!(f, "Unexpected ','"),
+            ParseError::UnexpectedLParen => write!(f, "Unexpected '('"),
+            ParseError::UnexpectedRParen => write!(f, "Unexpected ')'"),
+            ParseError::UnclosedLParen => write!(f, "'(' is never closed"),
+            ParseError::UnexpectedLBracket => write!(f, "Unexpected '['"),
+            ParseError::UnexpectedRBracket => write!(f, "Unexpected ']'"),
+            ParseError::UnclosedLBracket => write!(f, "'[' is never closed"),
+            ParseError::UnexpectedEnd => write!(f, "The expression ends too soon"),
+            ParseError::MissingKeyword(keyword) => write!(f, "Expected '{}'", keyword),
+            ParseError::WrongArgCount(name, count) => write!(
+                f,
+                "{} can't take {} argument{}",
+                name,
+                count,
+                if *count == 1 { "" } else { "s" }
+            ),
+            ParseError::ChainedComparison(name) => write!(
+                f,
+                "Comparisons can't be chained, so '{}' needs 'and' before it (ex: 'a < b and b < c')",
+                name
+            ),
+        };
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct TreeNode {
-    left: Option<Box<TreeNode>>,
-    right: Option<Box<TreeNode>>,
-    value: Option<Token>,
+    pub(crate) left: Option<Box<TreeNode>>,
+    pub(crate) right: Option<Box<TreeNode>>,
+    pub(crate) value: Option<Token>,
+    /// Used instead of left and right by things with more than 2 children,
+    /// like `if` (condition, then, else) or function calls.
+    /// A variable with args is a call to a function defined in the environment (ex: `f(2)`)
+    pub(crate) args: Vec<TreeNode>,
 }
 
 impl TreeNode {
-    fn get_value(self) -> Result<f64, ParseError> {
-        if let Some(val) = self.value {
+    pub fn new(value: Token, left: Option<TreeNode>, right: Option<TreeNode>) -> TreeNode {
+        return TreeNode {
+            left: left.map(Box::new),
+            right: right.map(Box::new),
+            value: Some(value),
+            args: vec![],
+        };
+    }
+
+    pub fn leaf(value: Token) -> TreeNode {
+        return TreeNode::new(value, None, None);
+    }
+
+    pub fn with_args(value: Token, args: Vec<TreeNode>) -> TreeNode {
+        return TreeNode {
+            left: None,
+            right: None,
+            value: Some(value),
+            args,
+        };
+    }
+
+    pub fn number(n: f64) -> TreeNode {
+        return TreeNode::leaf(Token::Constant(Value::Number(n)));
+    }
+
+    /// Makes a node of an operator by its name (ex: `"+"`)
+    ///
+    /// Panics if there's no operator with that name.
+    pub fn operator(name: &str, left: TreeNode, right: TreeNode) -> TreeNode {
+        return TreeNode::new(get_thing(name).unwrap(), Some(left), Some(right));
+    }
+
+    /// Makes a node of a function by its name (ex: `"sin"`)
+    ///
+    /// Panics if there's no function with that name.
+    pub fn function(name: &str, arg: TreeNode) -> TreeNode {
+        return TreeNode::new(get_thing(name).unwrap(), Some(arg), None);
+    }
+
+    /// The number, if the node is just a number
+    pub fn as_number(&self) -> Option<f64> {
+        return match &self.value {
+            Some(Token::Constant(Value::Number(n))) => Some(*n),
+            _ => None,
+        };
+    }
+
+    /// The name of the operator or function of the node, if it is one
+    pub fn name(&self) -> Option<&str> {
+        return match &self.value {
+            Some(Token::Operator { name, .. })
+            | Some(Token::Function { name, .. })
+            | Some(Token::Call { name, .. }) => Some(name),
+            _ => None,
+        };
+    }
+
+    /// Whether it's a call to a function defined in the environment, like `f(2)`
+    pub fn is_defined_call(&self) -> bool {
+        return matches!(self.value, Some(Token::Variable(_))) && !self.args.is_empty();
+    }
+
+    fn get_value(&self, env: &Environment) -> Result<Value, EvalError> {
+        env.check_cancelled()?;
+        if let Some(val) = &self.value {
             match val {
                 Token::Operator {
                     fun,
                     priority: _,
-                    name: _,
+                    name,
                 } => {
                     if self.left.is_none() || self.right.is_none() {
-                        return Err(ParseError::UnexpectedOperator(
-                            "No left or right".to_string(),
-                        ));
+                        return Err(EvalError::MalformedTree(format!(
+                            "No left or right for {}",
+                            name
+                        )));
+                    }
+                    let left = self.left.as_ref().unwrap().get_value(env)?;
+                    // Don't evaluate the right side if we already know the answer
+                    if name == "and" && !left.as_bool()? {
+                        return Ok(Value::Bool(false));
+                    }
+                    if name == "or" && left.as_bool()? {
+                        return Ok(Value::Bool(true));
                     }
-                    let left = self.left.unwrap().get_value()?;
-                    let right = self.right.unwrap().get_value()?;
-                    return Ok(fun(left, right));
+                    let right = self.right.as_ref().unwrap().get_value(env)?;
+                    return fun(left, right);
                 }
                 Token::Function {
                     fun,
                     priority: _,
                     after: _,
-                    name: _,
+                    name,
                 } => {
-                    // if after { // ngl don't see a reason to have left or right depend on after
+                    // Whether the function goes before or after, the value is on the left
                     if self.left.is_none() {
-                        return Err(ParseError::UnexpectedFunction("No left".to_string()));
+                        return Err(EvalError::MalformedTree(format!("No left for {}", name)));
                     }
-                    let left = self.left.unwrap().get_value()?;
-                    return Ok(fun(left));
-                    // } else {
-                    //     if self.right.is_none() {
-                    //         return Err(ParseError::UnexpectedFunction("No left".to_string()));
-                    //     }
-                    //     let left = self.left.unwrap().get_value()?;
-                    //     return Ok(fun(left));
-                    // }
+                    let left = self.left.as_ref().unwrap().get_value(env)?;
+                    return fun(left);
+                }
+                Token::Call {
+                    fun,
+                    arity: _,
+                    name: _,
+                } => {
+                    return fun(&self.args, env);
                 }
                 Token::Constant(value) => {
-                    return Ok(value);
+                    return Ok(value.clone());
+                }
+                Token::Variable(name) if self.is_defined_call() => {
+                    let args = self.args.iter().map(|arg| arg.get_value(env));
+                    let (inner, body) = env.call(name, args.collect::<Result<_, _>>()?)?;
+                    return body.get_value(&inner);
+                }
+                Token::Variable(name) => {
+                    return match env.get(name) {
+                        Some(value) => Ok(value.clone()),
+                        None => Err(EvalError::UnknownVariable(name.to_string())),
+                    };
+                }
+                Token::Keyword(name) => {
+                    // Only one of the branches is evaluated, so `if x != 0 then 1 / x else 0` is fine
+                    if let [cond, then, otherwise] = &self.args[..] {
+                        if cond.get_value(env)?.as_bool()? {
+                            return then.get_value(env);
+                        } else {
+                            return otherwise.get_value(env);
+                        }
+                    }
+                    return Err(EvalError::MalformedTree(format!("Bad {}", name)));
+                }
+                Token::LParen
+                | Token::RParen
+                | Token::LBracket
+                | Token::RBracket
+                | Token::Comma => {
+                    return Err(EvalError::MalformedTree("Punctuation in tree".to_string()));
                 }
-                Token::LParen => todo!("LParen"),
-                Token::RParen => todo!("RParen"),
-                Token::ParsedTree(..) => panic!("ParsedTree in ParsedTree"),
             }
         } else {
-            return Err(ParseError::InvalidNumber("No value".to_string()));
+            return Err(EvalError::MalformedTree("No value".to_string()));
         }
     }
 
-    pub fn eval(self) -> Result<f64, ParseError> {
-        return self.get_value();
+    pub fn eval(&self) -> Result<Value, EvalError> {
+        return self.get_value(&Environment::new());
     }
-}
 
-pub fn parse(vec: &Vec<Token>) -> Result<TreeNode, ParseError> {
-    let mut vec = vec.clone();
+    pub fn eval_with(&self, env: &Environment) -> Result<Value, EvalError> {
+        return self.get_value(env);
+    }
 
-    while vec.len() > 1 || !matches!(vec[0], Token::ParsedTree(_)) {
-        // TODO: Make this more efficient.
-        find_and_replace(&mut vec, 0)?;
+    /// The tree with every call to a defined function replaced by its body, with the
+    /// parameters replaced by the arguments. This is for things that work on the tree
+    /// instead of evaluating it, like `diff` and compiling.
+    pub fn inline_functions(&self, env: &Environment) -> Result<TreeNode, EvalError> {
+        return self.inline(env, &mut vec![]);
     }
 
-    let first = vec.pop();
+    /// `inline_functions`, where `calling` is the functions being inlined, to catch recursion
+    fn inline(&self, env: &Environment, calling: &mut Vec<String>) -> Result<TreeNode, EvalError> {
+        let mut tree = self.clone();
+        tree.left = self
+            .left
+            .as_ref()
+            .map(|t| t.inline(env, calling))
+            .transpose()?
+            .map(Box::new);
+        tree.right = self
+            .right
+            .as_ref()
+            .map(|t| t.inline(env, calling))
+            .transpose()?
+            .map(Box::new);
+        tree.args = self
+            .args
+            .iter()
+            .map(|t| t.inline(env, calling))
+            .collect::<Result<_, _>>()?;
 
-    if let Some(t) = first {
-        match t {
-            Token::ParsedTree(tree) => {
-                return Ok(*tree);
-            }
-            _ => {
-                return Err(ParseError::InvalidNumber("No value".to_string()));
+        let name = match &self.value {
+            Some(Token::Variable(name)) if self.is_defined_call() => name,
+            _ => return Ok(tree),
+        };
+        let function = match env.function(name) {
+            Some(function) => function,
+            None => return Err(EvalError::UnknownFunction(name.to_string())),
+        };
+        if calling.contains(name) {
+            return Err(EvalError::Domain(format!(
+                "{} calls itself, so it can't be written out",
+                name
+            )));
+        }
+        if function.params.len() != tree.args.len() {
+            return Err(EvalError::Domain(format!(
+                "{} takes {} arguments, not {}",
+                name,
+                function.params.len(),
+                tree.args.len()
+            )));
+        }
+        calling.push(name.to_string());
+        let body = function.body.substitute(&function.params, &tree.args);
+        let body = body.inline(env, calling);
+        calling.pop();
+        return body;
+    }
+
+    /// The tree with each variable in `names` replaced by the tree at the same index in `trees`
+    pub fn substitute(&self, names: &[String], trees: &[TreeNode]) -> TreeNode {
+        if let Some(Token::Variable(name)) = &self.value {
+            if let Some(i) = names.iter().position(|n| n == name) {
+                if !self.is_defined_call() {
+                    return trees[i].clone();
+                }
             }
         }
-    } else {
-        return Err(ParseError::InvalidNumber("No value".to_string()));
+        let mut tree = self.clone();
+        tree.left = self
+            .left
+            .as_ref()
+            .map(|t| Box::new(t.substitute(names, trees)));
+        tree.right = self
+            .right
+            .as_ref()
+            .map(|t| Box::new(t.substitute(names, trees)));
+        tree.args = self
+            .args
+            .iter()
+            .map(|t| t.substitute(names, trees))
+            .collect();
+        return tree;
     }
 }
 
-macro_rules! replace_stuff {
-    ($left:expr, $right:expr, $val:expr, $vec:expr, $at:expr) => {
-        let tree = TreeNode {
-            left: Some(Box::new(if let Token::ParsedTree(t) = $left {
-                *t.clone()
-            } else {
-                TreeNode {
-                    left: None,
-                    right: None,
-                    value: Some($left.clone()),
-                }
-            })),
-            right: Some(Box::new(if let Token::ParsedTree(t) = $right {
-                *t.clone()
-            } else {
-                TreeNode {
-                    left: None,
-                    right: None,
-                    value: Some($right.clone()),
-                }
-            })),
-            value: Some($val.clone()),
-        };
-        $vec.drain($at..$at + 2);
-        $vec[$at] = Token::ParsedTree(Box::new(tree));
-    };
-    ($left:expr, $val:expr, $vec:expr, $at:expr) => {
-        let tree = TreeNode {
-            left: Some(Box::new(if let Token::ParsedTree(t) = $left {
-                *t.clone()
-            } else {
-                TreeNode {
-                    left: None,
-                    right: None,
-                    value: Some($left.clone()),
-                }
-            })),
-            right: None,
-            value: Some($val.clone()),
+impl TreeNode {
+    /// The priority of the node when printed, used to know where parens are needed
+    fn print_priority(&self) -> i32 {
+        return match &self.value {
+            Some(Token::Operator { priority, .. }) => *priority,
+            Some(Token::Function {
+                priority,
+                after,
+                name,
+                ..
+            }) if *after || name == "not" => *priority,
+            Some(Token::Constant(Value::Number(n))) if *n < 0.0 => 1, // same as -n
+            Some(Token::Keyword(_)) => i32::MIN,
+            _ => i32::MAX,
         };
-        $vec.drain($at..$at + 1);
-        $vec[$at] = Token::ParsedTree(Box::new(tree));
-    };
+    }
+
+    fn fmt_child(&self, f: &mut std::fmt::Formatter<'_>, parens: bool) -> std::fmt::Result {
+        if parens {
+            return write!(f, "({})", self);
+        }
+        return write!(f, "{}", self);
+    }
+
+    fn is_zero(&self) -> bool {
+        return self.as_number() == Some(0.0);
+    }
 }
 
-/** find_and_replace
+impl Display for TreeNode {
+    /// Prints the tree back as an expression, with only the parens that are needed
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (left, right) = (self.left.as_deref(), self.right.as_deref());
+        let p = self.print_priority();
 
-Algorithm:
-1. If num op num found or fun with after as false and num found
-1.1. If next is an operator of strictly higher priority than op or fun or function with strictly higher priority and after is true
-     call find_and_replace at second num's index
-1.2. Else
-     Replace the tokens with ParsedToken
-2. If num and fun with after as true found
-2.1 Replace the tokens with ParsedToken
-
-num can be either a number, a lparen, or a ParsedToken. If it's an lparen, call replace_paren with position of lparen
-*/
-// TODO: DRY this up using macros probably. I have lots of repeated code
-// TODO: CLEAN THIS TF UP
-fn find_and_replace(vec: &mut Vec<Token>, at: usize) -> Result<(), ParseError> {
-    let val1 = vec.get(at);
-    let val2 = vec.get(at + 1);
-    let val3 = vec.get(at + 2);
-    let val4 = vec.get(at + 3);
-
-    if let Some(t1) = val1 {
-        match t1 {
-            Token::LParen => {
-                replace_paren(vec, at)?;
-                return Ok(());
-            }
-            Token::RParen => {
-                return Err(ParseError::UnexpectedRParen);
+        match &self.value {
+            Some(Token::Operator { name, .. }) => {
+                if let (Some(left), Some(right)) = (left, right) {
+                    // 0 - x is how the parser writes -x
+                    if name == "-" && left.is_zero() {
+                        write!(f, "-")?;
+                        return right.fmt_child(f, right.print_priority() <= p);
+                    }
+                    // Operators go from left to right, so only the right side needs parens
+                    // for the same priority (ex: `a - (b - c)`)
+                    left.fmt_child(f, left.print_priority() < p)?;
+                    // Powers are written tight, like `2^3 * 5`
+                    if name == "^" {
+                        write!(f, "^")?;
+                    } else {
+                        write!(f, " {} ", name)?;
+                    }
+                    return right.fmt_child(f, right.print_priority() <= p);
+                }
+                return write!(f, "{}", name);
             }
-            Token::Constant(_) | Token::ParsedTree(_) => {
-                if let Some(t2) = val2 {
-                    match t2 {
-                        Token::LParen => todo!("Handle implicit multiplication"),
-                        Token::RParen => {
-                            return Err(ParseError::UnexpectedRParen);
-                        }
-                        Token::Operator {
-                            fun: _,
-                            priority: p1,
-                            name: _,
-                        } => {
-                            if let Some(t3) = val3 {
-                                match t3 {
-                                    Token::LParen => {
-                                        replace_paren(vec, at + 2)?;
-                                        return Ok(()); // let the next iteration handle it
-                                    }
-                                    Token::RParen => {
-                                        return Err(ParseError::UnexpectedRParen);
-                                    }
-                                    Token::Constant(_) | Token::ParsedTree(_) => {
-                                        if let Some(t4) = val4 {
-                                            match t4 {
-                                                Token::LParen => {
-                                                    todo!("Handle implicit multiplication")
-                                                }
-                                                Token::RParen => {
-                                                    replace_stuff!(t1, t3, t2, vec, at);
-                                                    return Ok(());
-                                                }
-                                                Token::Operator {
-                                                    fun: _,
-                                                    priority: p2,
-                                                    name: _,
-                                                } => {
-                                                    if p2 > p1 {
-                                                        find_and_replace(vec, at + 2)?;
-                                                        return Ok(());
-                                                    } else {
-                                                        replace_stuff!(t1, t3, t2, vec, at);
-                                                        return Ok(());
-                                                    }
-                                                }
-                                                Token::Function {
-                                                    fun: _,
-                                                    priority: p2,
-                                                    after,
-                                                    name: _,
-                                                } => {
-                                                    if *after && p2 > p1 {
-                                                        find_and_replace(vec, at + 2)?;
-                                                        return Ok(());
-                                                    } else {
-                                                        replace_stuff!(t1, t3, t2, vec, at);
-                                                        return Ok(());
-                                                    }
-                                                }
-                                                Token::Constant(_) => {
-                                                    todo!("Handle implicit multiplication")
-                                                }
-                                                Token::ParsedTree(_) => {
-                                                    todo!("Handle implicit multiplication")
-                                                }
-                                            }
-                                        } else {
-                                            replace_stuff!(t1, t3, t2, vec, at);
-                                            return Ok(());
-                                        }
-                                    }
-                                    Token::Operator {
-                                        fun: _,
-                                        priority: _,
-                                        name: _,
-                                    } => todo!(),
-                                    Token::Function {
-                                        fun: _,
-                                        priority: _,
-                                        after: _,
-                                        name: _,
-                                    } => todo!(),
-                                }
-                            }
-                        }
-                        Token::Function {
-                            fun: _,
-                            priority: _,
-                            after,
-                            name: _,
-                        } => {
-                            if *after {
-                                // for ex 5!. So no need to check for next token
-                                let tree = TreeNode {
-                                    left: Some(Box::new(if let Token::ParsedTree(t) = t1 {
-                                        *t.clone()
-                                    } else {
-                                        TreeNode {
-                                            left: None,
-                                            right: None,
-                                            value: Some(t1.clone()),
-                                        }
-                                    })),
-                                    right: None,
-                                    value: Some(t2.clone()),
-                                };
-                                vec.drain(at..at + 1);
-                                vec[at] = Token::ParsedTree(Box::new(tree));
-                                return Ok(());
-                            } else {
-                                todo!("Handle implicit multiplication")
-                            }
-                        }
-                        Token::Constant(_) => todo!(),
-                        Token::ParsedTree(_) => todo!(),
+            Some(Token::Function { after, name, .. }) => {
+                if let Some(left) = left {
+                    if *after {
+                        left.fmt_child(f, left.print_priority() <= p)?;
+                        return write!(f, "{}", name);
+                    }
+                    if name == "not" {
+                        write!(f, "not ")?;
+                        return left.fmt_child(f, left.print_priority() <= p);
                     }
+                    return write!(f, "{}({})", name, left);
+                }
+                return write!(f, "{}", name);
+            }
+            Some(Token::Call { name, .. }) => {
+                // [1, 2, 3] is list(1, 2, 3)
+                let (open, close) = if name == "list" {
+                    ("[", "]")
                 } else {
-                    let tree = TreeNode {
-                        left: None,
-                        right: None,
-                        value: Some(t1.clone()),
-                    };
-                    vec[at] = Token::ParsedTree(Box::new(tree));
-                    return Ok(());
+                    write!(f, "{}", name)?;
+                    ("(", ")")
+                };
+                write!(f, "{}", open)?;
+                for (i, arg) in self.args.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", arg)?;
                 }
+                return write!(f, "{}", close);
+            }
+            Some(Token::Keyword(_)) => {
+                if let [cond, then, otherwise] = &self.args[..] {
+                    return write!(f, "if {} then {} else {}", cond, then, otherwise);
+                }
+                return write!(f, "if");
+            }
+            Some(Token::Constant(value)) => return write!(f, "{}", value),
+            Some(Token::Variable(name)) if self.is_defined_call() => {
+                let args = self.args.iter().map(|arg| arg.to_string());
+                return write!(f, "{}({})", name, args.collect::<Vec<_>>().join(", "));
             }
+            Some(Token::Variable(name)) => return write!(f, "{}", name),
+            Some(Token::LParen) => return write!(f, "("),
+            Some(Token::RParen) => return write!(f, ")"),
+            Some(Token::LBracket) => return write!(f, "["),
+            Some(Token::RBracket) => return write!(f, "]"),
+            Some(Token::Comma) => return write!(f, ","),
+            None => return Ok(()),
+        }
+    }
+}
+
+pub fn parse(vec: &[Token]) -> Result<TreeNode, ParseError> {
+    return parse_with_position(vec).map_err(|(error, _)| error);
+}
+
+/// Like `parse`, but errors also have the index of the token they're about
+/// (`vec.len()` if it's the end, like for `UnexpectedEnd`)
+pub fn parse_with_position(vec: &[Token]) -> Result<TreeNode, (ParseError, usize)> {
+    let mut peeker = Peeker::new(vec.to_vec());
+
+    let tree = parse_expression(&mut peeker, i32::MIN);
+    let tree = tree.map_err(|error| (error, peeker.position()))?;
+
+    if let Some(t) = peeker.peek(0) {
+        return Err((unexpected(t), peeker.position()));
+    }
+    return Ok(tree);
+}
+
+fn unexpected(t: &Token) -> ParseError {
+    return match t {
+        Token::LParen => ParseError::UnexpectedLParen,
+        Token::RParen => ParseError::UnexpectedRParen,
+        Token::LBracket => ParseError::UnexpectedLBracket,
+        Token::RBracket => ParseError::UnexpectedRBracket,
+        Token::Operator { name, .. } => ParseError::UnexpectedOperator(name.to_string()),
+        Token::Function { name, .. } | Token::Call { name, .. } => {
+            ParseError::UnexpectedFunction(name.to_string())
+        }
+        Token::Constant(value) => ParseError::UnexpectedValue(value.to_string()),
+        Token::Variable(name) => ParseError::UnexpectedValue(name.to_string()),
+        Token::Keyword(name) => ParseError::UnexpectedKeyword(name.to_string()),
+        Token::Comma => ParseError::UnexpectedComma,
+    };
+}
+
+// `c ? a : b` goes after everything else, even `or`
+const TERNARY_PRIORITY: i32 = -4;
+
+// `<`, `==` and the rest
+const COMPARISON_PRIORITY: i32 = 0;
+
+// Functions like `sqrt`, which a call without parens has too
+const FUNCTION_PRIORITY: i32 = 3;
+
+fn expect_keyword(peeker: &mut Peeker, keyword: &str) -> Result<(), ParseError> {
+    match peeker.peek(0) {
+        Some(Token::Keyword(k)) if k == keyword => {
+            peeker.next();
+            return Ok(());
+        }
+        _ => {
+            return Err(ParseError::MissingKeyword(keyword.to_string()));
+        }
+    }
+}
+
+/** parse_expression
+
+Algorithm:
+1. Parse a value (see `parse_value`)
+2. While the next token is an operator, or a function with after as true, of strictly higher priority than `min`
+   1. If it's an operator, parse the right side with the operator's priority as `min`
+      (so operators of the same priority go from left to right, and `2^3^2` is `(2^3)^2`),
+      unless it's a comparison right after another one, which would compare a boolean
+      (`1 < x < 3` is an error, but `(1 < x) == true` is fine)
+   2. Replace the left side with a node of the operator or function
+3. If `?` found, parse `a : b` after it and make it the same as `if left then a else b`
+4. Stop at anything else and let whoever called us deal with it (ex: the rparen in `replace_paren`)
+*/
+fn parse_expression(peeker: &mut Peeker, min: i32) -> Result<TreeNode, ParseError> {
+    let mut left = parse_value(peeker)?;
+    // Whether `left` is a comparison made in this loop (one in parens came from `parse_value`)
+    let mut compared = false;
+
+    while let Some(t) = peeker.peek(0) {
+        let t = t.clone();
+        match t {
             Token::Operator {
                 fun: _,
-                priority: p1,
-                name,
+                priority,
+                ref name,
             } => {
-                if name == "-" {
-                    if let Some(t2) = val2 {
-                        match t2 {
-                            Token::LParen => {
-                                replace_paren(vec, at + 1)?;
-                                return Ok(()); // let the next iteration handle it
-                            }
-                            Token::RParen => {
-                                return Err(ParseError::UnexpectedRParen);
-                            }
-                            Token::Operator {
-                                fun: _,
-                                priority: _,
-                                name,
-                            } => {
-                                return Err(ParseError::UnexpectedOperator(name.to_string()));
-                            }
-                            Token::Function {
-                                fun: _,
-                                priority: _,
-                                after: _,
-                                name,
-                            } => {
-                                return Err(ParseError::UnexpectedOperator(name.to_string()));
-                            }
-                            Token::Constant(_) | Token::ParsedTree(_) => {
-                                if let Some(t3) = val3 {
-                                    match t3 {
-                                        Token::LParen => {
-                                            replace_paren(vec, at + 2)?;
-                                            return Ok(()); // let the next iteration handle it
-                                        }
-                                        Token::RParen => {
-                                            return Err(ParseError::UnexpectedRParen);
-                                        }
-                                        Token::Operator {
-                                            fun: _,
-                                            priority: p2,
-                                            name: _,
-                                        } => {
-                                            if p2 > p1 {
-                                                find_and_replace(vec, at + 1)?;
-                                                return Ok(());
-                                            } else {
-                                                let tree = TreeNode {
-                                                    left: Some(Box::new(TreeNode {
-                                                        left: None,
-                                                        right: None,
-                                                        value: Some(Token::Constant(0.0)),
-                                                    })),
-                                                    right: Some(Box::new(TreeNode {
-                                                        left: None,
-                                                        right: None,
-                                                        value: Some(t2.clone()),
-                                                    })),
-                                                    value: Some(Token::Operator {
-                                                        fun: |a, b| a - b,
-                                                        priority: 2,
-                                                        name: "-".to_string(),
-                                                    }),
-                                                };
-                                                vec.drain(at..at + 1);
-                                                vec[at] = Token::ParsedTree(Box::new(tree));
-                                                return Ok(());
-                                            }
-                                        }
-                                        Token::Function {
-                                            fun: _,
-                                            priority: p2,
-                                            after,
-                                            name: _,
-                                        } => {
-                                            if *after && p2 > p1 {
-                                                find_and_replace(vec, at + 1)?;
-                                                return Ok(());
-                                            } else {
-                                                let tree = TreeNode {
-                                                    left: Some(Box::new(TreeNode {
-                                                        left: None,
-                                                        right: None,
-                                                        value: Some(Token::Constant(0.0)),
-                                                    })),
-                                                    right: Some(Box::new(TreeNode {
-                                                        left: None,
-                                                        right: None,
-                                                        value: Some(t2.clone()),
-                                                    })),
-                                                    value: Some(Token::Operator {
-                                                        fun: |a, b| a - b,
-                                                        priority: 2,
-                                                        name: "-".to_string(),
-                                                    }),
-                                                };
-                                                vec.drain(at..at + 1);
-                                                vec[at] = Token::ParsedTree(Box::new(tree));
-                                                return Ok(());
-                                            }
-                                        }
-                                        _ => {
-                                            let tree = TreeNode {
-                                                left: Some(Box::new(TreeNode {
-                                                    left: None,
-                                                    right: None,
-                                                    value: Some(Token::Constant(0.0)),
-                                                })),
-                                                right: Some(Box::new(TreeNode {
-                                                    left: None,
-                                                    right: None,
-                                                    value: Some(t2.clone()),
-                                                })),
-                                                value: Some(Token::Operator {
-                                                    fun: |a, b| a - b,
-                                                    priority: 2,
-                                                    name: "-".to_string(),
-                                                }),
-                                            };
-                                            vec.drain(at..at + 1);
-                                            vec[at] = Token::ParsedTree(Box::new(tree));
-                                            return Ok(());
-                                        }
-                                    }
-                                } else {
-                                    let tree = TreeNode {
-                                        left: Some(Box::new(TreeNode {
-                                            left: None,
-                                            right: None,
-                                            value: Some(Token::Constant(0.0)),
-                                        })),
-                                        right: Some(Box::new(
-                                            if let Token::ParsedTree(t) = t2 {
-                                                *t.clone()
-                                            } else {
-                                                TreeNode {
-                                                    left: None,
-                                                    right: None,
-                                                    value: Some(t2.clone()),
-                                                }
-                                            },
-                                        )),
-                                        value: Some(Token::Operator {
-                                            fun: |a, b| a - b,
-                                            priority: 2,
-                                            name: "-".to_string(),
-                                        }),
-                                    };
-                                    vec.drain(at..at + 1);
-                                    vec[at] = Token::ParsedTree(Box::new(tree));
-                                    return Ok(());
-                                }
-                            }
-                        }
-                    }
+                if priority <= min {
+                    break;
                 }
-                return Err(ParseError::UnexpectedOperator(name.to_string())); // TODO: Handle negative numbers
+                if compared && priority == COMPARISON_PRIORITY {
+                    return Err(ParseError::ChainedComparison(name.clone()));
+                }
+                peeker.next();
+                let right = parse_expression(peeker, priority)?;
+                compared = priority == COMPARISON_PRIORITY;
+                left = TreeNode::new(t, Some(left), Some(right));
             }
             Token::Function {
                 fun: _,
-                priority: p1,
-                after,
-                name,
+                priority,
+                after: true,
+                name: _,
             } => {
-                if !after {
-                    if let Some(t2) = val2 {
-                        match t2 {
-                            Token::LParen => {
-                                replace_paren(vec, at + 1)?;
-                                return Ok(()); // let the next iteration handle it
-                            }
-                            Token::RParen => {
-                                return Err(ParseError::UnexpectedRParen);
-                            }
-                            Token::Operator {
-                                fun: _,
-                                priority: _,
-                                name,
-                            } => {
-                                return Err(ParseError::UnexpectedOperator(name.to_string()));
-                                // TODO: Handle negative numbers
-                            }
-                            Token::Function {
-                                fun: _,
-                                priority: _,
-                                after: _,
-                                name: _,
-                            } => todo!(),
-                            Token::Constant(_) | Token::ParsedTree(_) => {
-                                if let Some(t3) = val3 {
-                                    match t3 {
-                                        Token::LParen => {
-                                            replace_paren(vec, at + 2)?;
-                                            return Ok(()); // let the next iteration handle it
-                                        }
-                                        Token::RParen => {
-                                            return Err(ParseError::UnexpectedRParen);
-                                        }
-                                        Token::Operator {
-                                            fun: _,
-                                            priority: p2,
-                                            name: _,
-                                        } => {
-                                            if p2 > p1 {
-                                                find_and_replace(vec, at + 2)?;
-                                                return Ok(());
-                                            } else {
-                                                replace_stuff!(t2, t1, vec, at);
-                                                return Ok(());
-                                            }
-                                        }
-                                        Token::Function {
-                                            fun: _,
-                                            priority: p2,
-                                            after,
-                                            name: _,
-                                        } => {
-                                            if *after && p2 > p1 {
-                                                find_and_replace(vec, at + 2)?;
-                                                return Ok(());
-                                            } else {
-                                                replace_stuff!(t2, t1, vec, at);
-                                                return Ok(());
-                                            }
-                                        }
-                                        Token::Constant(_) => {
-                                            todo!("Handle implicit multiplication")
-                                        }
-                                        Token::ParsedTree(_) => {
-                                            todo!("Handle implicit multiplication")
-                                        }
-                                    }
-                                } else {
-                                    replace_stuff!(t2, t1, vec, at);
-                                    return Ok(());
-                                }
-                            }
-                        }
-                    } else {
-                        return Err(ParseError::UnexpectedFunction(name.to_string()));
-                    }
-                } else {
-                    return Err(ParseError::UnexpectedFunction(name.to_string()));
+                if priority <= min {
+                    break;
+                }
+                peeker.next();
+                left = TreeNode::new(t, Some(left), None);
+            }
+            Token::Keyword(ref k) if k == "?" => {
+                if TERNARY_PRIORITY <= min {
+                    break;
                 }
+                peeker.next();
+                let then = parse_expression(peeker, i32::MIN)?;
+                expect_keyword(peeker, ":")?;
+                let otherwise = parse_expression(peeker, TERNARY_PRIORITY - 1)?;
+                left = TreeNode::with_args(
+                    Token::Keyword("if".to_string()),
+                    vec![left, then, otherwise],
+                );
             }
+            Token::RParen | Token::RBracket | Token::Comma | Token::Keyword(_) => break,
+            _ => return Err(unexpected(&t)),
         }
     }
-    Ok(())
+
+    return Ok(left);
 }
 
-/** replace_paren
+/** parse_value
 
 Algorithm:
-1. Make subvec from at+1 to the next rparen of same depth
-2. Call find_and_replace on subvec until its length is 1
-3. Replace everything from at to the next rparen with the ParsedToken which is the only element in the subvec
+1. If num or variable found, it's the value, unless the variable has parens after it,
+   which makes it a call to a defined function (see `parse_args`)
+2. If lparen found, call replace_paren
+   If lbracket found, parse the items of the list until the rbracket (see `parse_items`)
+3. If `-` found, parse the value after it with the priority of `-` as `min` and make it `0 - value`
+4. If fun with after as false found, parse the value after it with the function's priority as `min`,
+   or just the parens if it's followed by an lparen, so `sin(x)^2` is `(sin x)^2` but `sin x^2` is `sin(x^2)`
+5. If `if` found, parse `cond then a else b` after it
+6. If call found, parse the arguments in the parens after it (see `parse_args`),
+   which can be left out if it can be called without any, or be a single value like a function
 */
-fn replace_paren(vec: &mut Vec<Token>, at: usize) -> Result<(), ParseError> {
-    let mut subvec = Vec::new();
-    let mut i = at + 1;
-    let mut depth = 1;
+fn parse_value(peeker: &mut Peeker) -> Result<TreeNode, ParseError> {
+    let t = match peeker.peek(0) {
+        Some(t) => t.clone(),
+        None => return Err(ParseError::UnexpectedEnd),
+    };
+    peeker.next();
 
-    loop {
-        match vec[i] {
-            Token::LParen => {
-                depth += 1;
+    match t {
+        // A call to a function defined in the environment
+        Token::Variable(_) if matches!(peeker.peek(0), Some(Token::LParen)) => {
+            return Ok(TreeNode::with_args(t, parse_args(peeker)?));
+        }
+        Token::Constant(_) | Token::Variable(_) => {
+            return Ok(TreeNode::leaf(t));
+        }
+        Token::Keyword(ref k) if k == "if" => {
+            let cond = parse_expression(peeker, i32::MIN)?;
+            expect_keyword(peeker, "then")?;
+            let then = parse_expression(peeker, i32::MIN)?;
+            expect_keyword(peeker, "else")?;
+            let otherwise = parse_expression(peeker, i32::MIN)?;
+            return Ok(TreeNode::with_args(t, vec![cond, then, otherwise]));
+        }
+        Token::Call {
+            fun: _,
+            ref arity,
+            ref name,
+        } => {
+            let at = peeker.position() - 1;
+            // Calls without arguments don't need parens (ex: `now + 1h`), and neither do
+            // calls with one, which then work like functions (ex: `round 2.5`)
+            let args = if matches!(peeker.peek(0), Some(Token::LParen)) {
+                parse_args(peeker)?
+            } else if arity.contains(&0) {
+                vec![]
+            } else if arity.contains(&1) {
+                vec![parse_expression(peeker, FUNCTION_PRIORITY)?]
+            } else {
+                parse_args(peeker)?
+            };
+            if !arity.contains(&args.len()) {
+                peeker.rewind(at);
+                return Err(ParseError::WrongArgCount(name.to_string(), args.len()));
             }
-            Token::RParen => {
-                depth -= 1;
+            return Ok(TreeNode::with_args(t, args));
+        }
+        Token::LParen => {
+            return replace_paren(peeker);
+        }
+        Token::LBracket => {
+            let items = parse_items(peeker, true)?;
+            return Ok(TreeNode::with_args(get_thing("list").unwrap(), items));
+        }
+        Token::Operator {
+            fun: _,
+            priority,
+            ref name,
+        } if name == "-" || name == "+" => {
+            let right = parse_expression(peeker, priority)?;
+            if name == "+" {
+                return Ok(right);
             }
-            _ => {}
+            let zero = TreeNode::leaf(Token::Constant(Value::Number(0.0)));
+            return Ok(TreeNode::new(t, Some(zero), Some(right)));
+        }
+        Token::Function {
+            fun: _,
+            priority,
+            after: false,
+            name: _,
+        } => {
+            let left = if matches!(peeker.peek(0), Some(Token::LParen)) {
+                peeker.next();
+                replace_paren(peeker)?
+            } else {
+                parse_expression(peeker, priority)?
+            };
+            return Ok(TreeNode::new(t, Some(left), None));
+        }
+        _ => {
+            peeker.rewind(peeker.position() - 1);
+            return Err(unexpected(&t));
+        }
+    }
+}
+
+/** replace_paren
+
+Algorithm:
+1. Parse everything after the lparen
+2. Make sure it ends with the matching rparen
+*/
+fn replace_paren(peeker: &mut Peeker) -> Result<TreeNode, ParseError> {
+    let tree = parse_expression(peeker, i32::MIN)?;
+
+    match peeker.peek(0) {
+        Some(Token::RParen) => {
+            peeker.next();
+            return Ok(tree);
+        }
+        Some(t) => {
+            return Err(unexpected(t));
         }
-        if depth == 0 {
-            break;
+        None => {
+            return Err(ParseError::UnclosedLParen);
         }
-        subvec.push(vec[i].clone());
-        i += 1;
     }
+}
+
+/** parse_args
 
-    while subvec.len() > 1 || !matches!(subvec[0], Token::ParsedTree(_)) {
-        find_and_replace(&mut subvec, 0)?;
+Algorithm:
+1. Expect an lparen
+2. Parse the arguments until the rparen (see `parse_items`)
+*/
+fn parse_args(peeker: &mut Peeker) -> Result<Vec<TreeNode>, ParseError> {
+    match peeker.peek(0) {
+        Some(Token::LParen) => {
+            peeker.next();
+        }
+        Some(t) => return Err(unexpected(t)),
+        None => return Err(ParseError::UnexpectedEnd),
     }
+    return parse_items(peeker, false);
+}
 
-    let first = subvec.pop();
+/** parse_items
 
-    if let Some(t) = first {
-        match t {
-            Token::ParsedTree(tree) => {
-                vec[at] = Token::ParsedTree(tree);
-                vec.drain(at + 1..i + 1);
-                return Ok(());
+Algorithm:
+1. Parse expressions separated by commas until the rparen, or the rbracket if it's a list
+*/
+fn parse_items(peeker: &mut Peeker, list: bool) -> Result<Vec<TreeNode>, ParseError> {
+    let closes = |t: &Token| {
+        if list {
+            matches!(t, Token::RBracket)
+        } else {
+            matches!(t, Token::RParen)
+        }
+    };
+
+    let mut items = vec![];
+    if peeker.peek(0).is_some_and(closes) {
+        peeker.next();
+        return Ok(items);
+    }
+
+    loop {
+        items.push(parse_expression(peeker, i32::MIN)?);
+        match peeker.peek(0) {
+            Some(Token::Comma) => {
+                peeker.next();
             }
-            _ => {
-                return Err(ParseError::InvalidNumber("No value".to_string()));
+            Some(t) if closes(t) => {
+                peeker.next();
+                return Ok(items);
             }
+            Some(t) => return Err(unexpected(t)),
+            None if list => return Err(ParseError::UnclosedLBracket),
+            None => return Err(ParseError::UnclosedLParen),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{error::parse_str, value::Value};
+
+    fn eval(input: &str) -> Value {
+        return parse_str(input).unwrap().eval().unwrap();
+    }
+
+    fn number(input: &str) -> f64 {
+        return eval(input).as_number().unwrap();
+    }
+
+    #[test]
+    fn powers_go_left_to_right() {
+        assert_eq!(number("2^3^2"), 64.0);
+        assert_eq!(number("2^(3^2)"), 512.0);
+        assert_eq!(parse_str("2^(3^2)").unwrap().to_string(), "2^(3^2)");
+        assert_eq!(parse_str("(2^3)^2").unwrap().to_string(), "2^3^2");
+        assert_eq!(number("-2^2"), -4.0);
+    }
+
+    #[test]
+    fn parens_after_a_function_are_its_argument() {
+        assert_eq!(number("ln(e)^2"), 1.0);
+        assert_eq!(number("sin(1)^2"), 1f64.sin().powi(2));
+        assert_eq!(number("sin 1^2"), 1f64.sin());
+        assert_eq!(parse_str("sin(x)^2").unwrap().to_string(), "sin(x)^2");
+    }
+
+    #[test]
+    fn comparisons_and_logic() {
+        assert_eq!(eval("1 < 2").to_string(), "true");
+        assert_eq!(eval("2 <= 1").to_string(), "false");
+        assert_eq!(eval("1 + 1 == 2 and not 3 != 3").to_string(), "true");
+        assert_eq!(eval("false or 2 > 1").to_string(), "true");
+        // The right side isn't evaluated once the answer is known
+        assert_eq!(eval("false and 1 / 0 > 0").to_string(), "false");
+        // `1 < 2 < 3` would be `true < 3`, so it has to be written out
+        for input in ["1 < 2 < 3", "3 > 2 > 1", "1 == 1 == 1"] {
+            let error = parse_str(input).unwrap_err();
+            assert_eq!(error.kind(), "chained_comparison", "{}", input);
         }
-    } else {
-        return Err(ParseError::InvalidNumber("No value".to_string()));
+        assert_eq!(eval("1 < 2 and 2 < 3").to_string(), "true");
+        assert_eq!(eval("(1 < 2) == (2 < 3)").to_string(), "true");
+        // Booleans are 1 and 0 in arithmetic, so they can be counted
+        assert_eq!(number("(1 < 2) + (2 < 3)"), 2.0);
+    }
+
+    #[test]
+    fn conditionals() {
+        assert_eq!(number("if 2 > 1 then 10 else 20"), 10.0);
+        assert_eq!(number("1 > 2 ? 10 : 20"), 20.0);
+        assert_eq!(number("piecewise(-3 < 0, -1, -3 == 0, 0, 1)"), -1.0);
+        assert_eq!(number("piecewise(false, 1, 2)"), 2.0);
+        // Only the chosen branch is evaluated
+        assert_eq!(number("if true then 1 else 1 / 0"), 1.0);
+        assert_eq!(
+            parse_str("if true then 1").unwrap_err().kind(),
+            "missing_keyword"
+        );
+        let error = parse_str("piecewise(false, 1)")
+            .unwrap()
+            .eval()
+            .unwrap_err();
+        assert_eq!(error.kind(), "no_matching_piece");
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(parse_str("(1 + 2").unwrap_err().kind(), "unclosed_lparen");
+        assert_eq!(parse_str("1 +").unwrap_err().kind(), "unexpected_end");
+        assert_eq!(parse_str("1 2").unwrap_err().kind(), "unexpected_value");
     }
 }
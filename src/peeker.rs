@@ -1,11 +1,17 @@
+use crate::tokeniser::Token;
+
 #[derive(Debug)]
-struct Peeker {
+pub struct Peeker {
     vec: Vec<Token>,
     at: usize,
 }
 
 impl Peeker {
-    fn next(&mut self) -> Option<&Token> {
+    pub fn new(vec: Vec<Token>) -> Peeker {
+        return Peeker { vec, at: 0 };
+    }
+
+    pub fn next(&mut self) -> Option<&Token> {
         if self.at >= self.vec.len() {
             return None;
         }
@@ -13,7 +19,17 @@ impl Peeker {
         return self.vec.get(self.at);
     }
 
-    fn peek(&self, num: usize) -> Option<&Token> {
+    pub fn peek(&self, num: usize) -> Option<&Token> {
         return self.vec.get(self.at + num);
     }
-}
\ No newline at end of file
+
+    /// The index of the next token
+    pub fn position(&self) -> usize {
+        return self.at;
+    }
+
+    /// Goes back to an earlier token (ex: so an error can point at it)
+    pub fn rewind(&mut self, to: usize) {
+        self.at = to.min(self.at);
+    }
+}
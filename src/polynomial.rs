@@ -0,0 +1,515 @@
+//! Polynomials in one variable, for `coeffs`, `expand`, `poly_div` and `poly_roots`.
+
+use std::{
+    f64::consts::PI,
+    ops::{Add, Div, Mul, Sub},
+};
+
+use crate::{
+    environment::Environment,
+    format,
+    parser::TreeNode,
+    tokeniser::Token,
+    value::{EvalError, Value},
+};
+
+/// The biggest power `expand` will work out, so `(x + 1)^1e9` doesn't run forever
+const MAX_DEGREE: f64 = 1000.0;
+
+/// How many times the roots can be improved before they're as good as they'll get
+const MAX_ITERATIONS: usize = 500;
+
+/// Roots closer than this (relative to their size) might be the same root
+const CLUSTER: f64 = 1e-2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Complex {
+    pub re: f64,
+    pub im: f64,
+}
+
+impl Complex {
+    fn real(re: f64) -> Complex {
+        return Complex { re, im: 0.0 };
+    }
+
+    fn abs(self) -> f64 {
+        return self.re.hypot(self.im);
+    }
+}
+
+impl Add for Complex {
+    type Output = Complex;
+    fn add(self, other: Complex) -> Complex {
+        return Complex {
+            re: self.re + other.re,
+            im: self.im + other.im,
+        };
+    }
+}
+
+impl Sub for Complex {
+    type Output = Complex;
+    fn sub(self, other: Complex) -> Complex {
+        return Complex {
+            re: self.re - other.re,
+            im: self.im - other.im,
+        };
+    }
+}
+
+impl Mul for Complex {
+    type Output = Complex;
+    fn mul(self, other: Complex) -> Complex {
+        return Complex {
+            re: self.re * other.re - self.im * other.im,
+            im: self.re * other.im + self.im * other.re,
+        };
+    }
+}
+
+impl Div for Complex {
+    type Output = Complex;
+    fn div(self, other: Complex) -> Complex {
+        let d = other.re * other.re + other.im * other.im;
+        return Complex {
+            re: (self.re * other.re + self.im * other.im) / d,
+            im: (self.im * other.re - self.re * other.im) / d,
+        };
+    }
+}
+
+impl From<Complex> for Value {
+    /// A real root is a number, and any other one is an expression like `1 - 2 * i`
+    fn from(z: Complex) -> Value {
+        if z.im == 0.0 {
+            return Value::Number(z.re);
+        }
+        let i = TreeNode::leaf(Token::Variable("i".to_string()));
+        let imaginary = match z.im.abs() {
+            1.0 => i,
+            im => TreeNode::operator("*", TreeNode::number(im), i),
+        };
+        let sign = if z.im < 0.0 { "-" } else { "+" };
+        // 0 - i is how the parser writes -i
+        if z.re == 0.0 && z.im > 0.0 {
+            return Value::Expression(Box::new(imaginary));
+        }
+        return Value::Expression(Box::new(TreeNode::operator(
+            sign,
+            TreeNode::number(z.re),
+            imaginary,
+        )));
+    }
+}
+
+/// A polynomial, with the coefficient of x^i at `coeffs[i]`
+#[derive(Debug, Clone)]
+pub struct Polynomial {
+    coeffs: Vec<f64>,
+}
+
+fn not_polynomial(tree: &TreeNode, var: &str) -> EvalError {
+    return EvalError::NotPolynomial(format!("{} isn't a polynomial in {}", tree, var));
+}
+
+impl Polynomial {
+    /// Gets rid of the zeros at the end, but always keeps at least one coefficient
+    fn new(mut coeffs: Vec<f64>) -> Polynomial {
+        while coeffs.len() > 1 && coeffs.last() == Some(&0.0) {
+            coeffs.pop();
+        }
+        if coeffs.is_empty() {
+            coeffs.push(0.0);
+        }
+        return Polynomial { coeffs };
+    }
+
+    fn degree(&self) -> usize {
+        return self.coeffs.len() - 1;
+    }
+
+    fn is_zero(&self) -> bool {
+        return self.coeffs == [0.0];
+    }
+
+    fn add(&self, other: &Polynomial, sign: f64) -> Polynomial {
+        let len = self.coeffs.len().max(other.coeffs.len());
+        let coeffs = (0..len).map(|i| {
+            self.coeffs.get(i).unwrap_or(&0.0) + sign * other.coeffs.get(i).unwrap_or(&0.0)
+        });
+        return Polynomial::new(coeffs.collect());
+    }
+
+    fn multiply(&self, other: &Polynomial) -> Polynomial {
+        let mut coeffs = vec![0.0; self.coeffs.len() + other.coeffs.len() - 1];
+        for (i, a) in self.coeffs.iter().enumerate() {
+            for (j, b) in other.coeffs.iter().enumerate() {
+                coeffs[i + j] += a * b;
+            }
+        }
+        return Polynomial::new(coeffs);
+    }
+
+    fn scale(&self, c: f64) -> Polynomial {
+        return Polynomial::new(self.coeffs.iter().map(|a| a * c).collect());
+    }
+
+    fn derivative(&self) -> Polynomial {
+        let coeffs = self.coeffs.iter().enumerate().skip(1);
+        return Polynomial::new(coeffs.map(|(i, c)| c * i as f64).collect());
+    }
+
+    fn at(&self, z: Complex) -> Complex {
+        let mut result = Complex::real(0.0);
+        for c in self.coeffs.iter().rev() {
+            result = result * z + Complex::real(*c);
+        }
+        return result;
+    }
+
+    /// How big rounding errors in `at(z)` can get
+    fn rounding(&self, z: Complex) -> f64 {
+        let mut result = 0.0;
+        for c in self.coeffs.iter().rev() {
+            result = result * z.abs() + c.abs();
+        }
+        return result * f64::EPSILON;
+    }
+
+    /** from_tree
+
+    Reads an expression as a polynomial in `var`, where anything without `var` in it is
+    evaluated as a number.
+
+    Only `+`, `-`, `*`, division by something without `var`, and whole powers of at
+    least 0 can have `var` in them, so `(x + 1)^3 / 2` works but `1 / x` and `sin(x)` don't.
+    */
+    pub fn from_tree(
+        tree: &TreeNode,
+        var: &str,
+        env: &Environment,
+    ) -> Result<Polynomial, EvalError> {
+        if !tree.contains_variable(var) {
+            return Ok(Polynomial::new(vec![tree.eval_with(env)?.as_number()?]));
+        }
+        let (left, right) = match (&tree.value, &tree.left, &tree.right) {
+            (Some(Token::Variable(_)), _, _) if !tree.is_defined_call() => {
+                return Ok(Polynomial::new(vec![0.0, 1.0]))
+            }
+            (Some(Token::Operator { .. }), Some(left), Some(right)) => (left, right),
+            _ => return Err(not_polynomial(tree, var)),
+        };
+        let polynomial = |t: &TreeNode| Polynomial::from_tree(t, var, env);
+        let number = |t: &TreeNode| {
+            if t.contains_variable(var) {
+                return Err(not_polynomial(tree, var));
+            }
+            return t.eval_with(env)?.as_number();
+        };
+
+        return match tree.name() {
+            Some("+") => Ok(polynomial(left)?.add(&polynomial(right)?, 1.0)),
+            Some("-") => Ok(polynomial(left)?.add(&polynomial(right)?, -1.0)),
+            Some("*") => Ok(polynomial(left)?.multiply(&polynomial(right)?)),
+            Some("/") => {
+                let divisor = number(right)?;
+                if divisor == 0.0 {
+                    return Err(EvalError::DivisionByZero);
+                }
+                Ok(polynomial(left)?.scale(1.0 / divisor))
+            }
+            Some("^") => {
+                let n = number(right)?;
+                if n < 0.0 || n.fract() != 0.0 {
+                    return Err(not_polynomial(tree, var));
+                }
+                let base = polynomial(left)?;
+                if n.max(n * base.degree() as f64) > MAX_DEGREE {
+                    return Err(EvalError::Domain(format!(
+                        "{} has a degree over {}",
+                        tree, MAX_DEGREE
+                    )));
+                }
+                let mut result = Polynomial::new(vec![1.0]);
+                for _ in 0..n as usize {
+                    result = result.multiply(&base);
+                }
+                Ok(result)
+            }
+            _ => Err(not_polynomial(tree, var)),
+        };
+    }
+
+    /// Writes the polynomial out from the highest power down (ex: `x^3 + 3 * x^2 - 1`)
+    pub fn to_tree(&self, var: &str) -> TreeNode {
+        let mut tree: Option<TreeNode> = None;
+        for (i, c) in self.coeffs.iter().enumerate().rev() {
+            if *c == 0.0 {
+                continue;
+            }
+            let x = TreeNode::leaf(Token::Variable(var.to_string()));
+            let power = match i {
+                0 => None,
+                1 => Some(x),
+                _ => Some(TreeNode::operator("^", x, TreeNode::number(i as f64))),
+            };
+            let term = match power {
+                None => TreeNode::number(c.abs()),
+                Some(power) if c.abs() == 1.0 => power,
+                Some(power) => TreeNode::operator("*", TreeNode::number(c.abs()), power),
+            };
+            let sign = if *c < 0.0 { "-" } else { "+" };
+            tree = Some(match tree {
+                // 0 - x is how the parser writes -x
+                None if *c < 0.0 => TreeNode::operator("-", TreeNode::number(0.0), term),
+                None => term,
+                Some(tree) => TreeNode::operator(sign, tree, term),
+            });
+        }
+        return tree.unwrap_or(TreeNode::number(0.0));
+    }
+
+    /// The coefficients from the highest power down, so `x^2 - 1` is `[1, 0, -1]`
+    pub fn coeffs(&self) -> Vec<f64> {
+        return self.coeffs.iter().rev().copied().collect();
+    }
+
+    /// Long division, which gives the quotient and the remainder
+    pub fn divide(&self, divisor: &Polynomial) -> Result<(Polynomial, Polynomial), EvalError> {
+        if divisor.is_zero() {
+            return Err(EvalError::DivisionByZero);
+        }
+        let n = divisor.degree();
+        if self.degree() < n {
+            return Ok((Polynomial::new(vec![0.0]), self.clone()));
+        }
+
+        let lead = divisor.coeffs[n];
+        let mut remainder = self.coeffs.clone();
+        let mut quotient = vec![0.0; remainder.len() - n];
+        for i in (0..quotient.len()).rev() {
+            let q = remainder[i + n] / lead;
+            quotient[i] = q;
+            for (j, d) in divisor.coeffs.iter().enumerate() {
+                remainder[i + j] -= q * d;
+            }
+            // It cancels out exactly, even if rounding says otherwise
+            remainder[i + n] = 0.0;
+        }
+        return Ok((Polynomial::new(quotient), Polynomial::new(remainder)));
+    }
+
+    /** roots
+
+    Every root, real or complex, as many times as it's repeated, sorted by the real part.
+
+    Algorithm:
+    1. Take out the roots at 0, which are exact
+    2. Find the rest together with `aberth`
+    3. Roots that are very close together might be a repeated root, which can only be found
+       roughly. Their average is much closer, and it's the root of one of the derivatives
+       that isn't repeated, so Newton's method on that derivative finds it exactly.
+       It's only kept if the polynomial is 0 there (so two roots that are just very close
+       aren't mixed up with a repeated one)
+    4. Roots that are only complex because of rounding errors are made real, and the parts
+       are rounded to 15 significant figures (so 3.0000000000000004 is 3)
+    */
+    pub fn roots(&self) -> Result<Vec<Complex>, EvalError> {
+        if self.is_zero() {
+            return Err(EvalError::Domain("Every number is a root of 0".to_string()));
+        }
+        let zeros = self.coeffs.iter().take_while(|c| **c == 0.0).count();
+        let rest = Polynomial::new(self.coeffs[zeros..].to_vec());
+        let mut found = aberth(&rest);
+
+        let mut roots = vec![Complex::real(0.0); zeros];
+        while let Some(z) = found.pop() {
+            // The closest roots go at the end, so the biggest group that works can be taken off
+            let distance = |w: &Complex| (*w - z).abs();
+            found.sort_by(|a, b| distance(b).total_cmp(&distance(a)));
+            let close = found
+                .iter()
+                .rev()
+                .take_while(|w| distance(w) <= CLUSTER * z.abs().max(1.0))
+                .count();
+            let mut count = 1;
+            let mut root = z;
+            for k in (1..=close).rev() {
+                let mut cluster = found[found.len() - k..].to_vec();
+                cluster.push(z);
+                if let Some(repeated) = repeated_root(&rest, &cluster) {
+                    found.truncate(found.len() - k);
+                    (count, root) = (k + 1, repeated);
+                    break;
+                }
+            }
+            roots.extend(vec![root; count]);
+        }
+
+        for z in &mut roots {
+            let tiny = 1e-10 * z.abs().max(1.0);
+            if z.re.abs() <= tiny {
+                z.re = 0.0;
+            }
+            if z.im.abs() <= tiny {
+                z.im = 0.0;
+            }
+            z.re = format::round_sig(z.re, 15);
+            z.im = format::round_sig(z.im, 15);
+        }
+        roots.sort_by(|a, b| a.re.total_cmp(&b.re).then(a.im.total_cmp(&b.im)));
+        return Ok(roots);
+    }
+}
+
+/** aberth
+
+The roots of a polynomial that isn't 0 at 0, with the Aberth–Ehrlich method.
+
+Algorithm:
+1. Start with guesses spread around a circle as big as the roots are on average
+2. Move each guess by Newton's method, but also pushed away from the other guesses
+   so they don't all find the same root
+3. Stop once none of them move (or they've had enough tries, for repeated roots)
+*/
+fn aberth(p: &Polynomial) -> Vec<Complex> {
+    let n = p.degree();
+    let dp = p.derivative();
+    let radius = (p.coeffs[0] / p.coeffs[n]).abs().powf(1.0 / n as f64);
+    let mut z: Vec<Complex> = (0..n)
+        .map(|k| {
+            // Off the real line, so conjugate roots don't start out the same
+            let angle = 2.0 * PI * k as f64 / n as f64 + 0.4;
+            Complex {
+                re: radius * angle.cos(),
+                im: radius * angle.sin(),
+            }
+        })
+        .collect();
+
+    for _ in 0..MAX_ITERATIONS {
+        let mut moved = false;
+        for k in 0..n {
+            let value = p.at(z[k]);
+            if value.abs() <= p.rounding(z[k]) {
+                continue;
+            }
+            let mut repulsion = Complex::real(0.0);
+            for (j, w) in z.iter().enumerate() {
+                if j != k {
+                    repulsion = repulsion + Complex::real(1.0) / (z[k] - *w);
+                }
+            }
+            // Newton's step would be value / p'(z), and this is that step with the push added
+            let step = Complex::real(1.0) / (dp.at(z[k]) / value - repulsion);
+            if step.abs() > f64::EPSILON * z[k].abs() {
+                moved = true;
+            }
+            z[k] = z[k] - step;
+        }
+        if !moved {
+            break;
+        }
+    }
+    return z;
+}
+
+/// If `cluster` is one root repeated, that root
+fn repeated_root(p: &Polynomial, cluster: &[Complex]) -> Option<Complex> {
+    let mut sum = Complex::real(0.0);
+    for z in cluster {
+        sum = sum + *z;
+    }
+    let mut z = sum / Complex::real(cluster.len() as f64);
+
+    // The root is only repeated k times in p, so it's a normal root of the (k - 1)th derivative
+    let mut f = p.clone();
+    for _ in 1..cluster.len() {
+        f = f.derivative();
+    }
+    let df = f.derivative();
+    for _ in 0..MAX_ITERATIONS {
+        let step = f.at(z) / df.at(z);
+        if step.abs() <= f64::EPSILON * z.abs() || step.abs().is_nan() {
+            break;
+        }
+        z = z - step;
+    }
+
+    // Some slack, because the rounding errors are bigger near a repeated root
+    if p.at(z).abs() > 1000.0 * p.rounding(z) {
+        return None;
+    }
+    return Some(z);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::parse_str;
+
+    fn polynomial(input: &str) -> Result<Polynomial, EvalError> {
+        return Polynomial::from_tree(&parse_str(input).unwrap(), "x", &Environment::new());
+    }
+
+    fn eval(input: &str) -> String {
+        return parse_str(input).unwrap().eval().unwrap().to_string();
+    }
+
+    #[test]
+    fn reading() {
+        assert_eq!(
+            polynomial("(x + 1)^3 / 2").unwrap().coeffs(),
+            [0.5, 1.5, 1.5, 0.5]
+        );
+        assert_eq!(
+            polynomial("3 * x^2 - 1").unwrap().coeffs(),
+            [3.0, 0.0, -1.0]
+        );
+        for input in ["1 / x", "x^2.5", "x^-1", "sin(x)"] {
+            assert!(matches!(
+                polynomial(input),
+                Err(EvalError::NotPolynomial(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn writing() {
+        assert_eq!(eval("expand((x + 1)^3, x)"), "x^3 + 3 * x^2 + 3 * x + 1");
+        assert_eq!(eval("expand((x - 1) * (x + 1), x)"), "x^2 - 1");
+    }
+
+    #[test]
+    fn division() {
+        let (quotient, remainder) = polynomial("x^3 + 2")
+            .unwrap()
+            .divide(&polynomial("x^2").unwrap())
+            .unwrap();
+        assert_eq!(quotient.coeffs(), [1.0, 0.0]);
+        assert_eq!(remainder.coeffs(), [2.0]);
+        assert_eq!(eval("poly_div(x^2 - 1, x - 1, x)"), "[x + 1, 0]");
+        assert!(polynomial("x")
+            .unwrap()
+            .divide(&polynomial("0").unwrap())
+            .is_err());
+    }
+
+    #[test]
+    fn roots() {
+        let roots = polynomial("x^2 - 3 * x + 2").unwrap().roots().unwrap();
+        assert_eq!(roots.len(), 2);
+        for (root, expected) in roots.iter().zip([1.0, 2.0]) {
+            assert!((root.re - expected).abs() < 1e-12 && root.im == 0.0);
+        }
+        assert_eq!(eval("poly_roots(x^2 + 1, x)"), "[-i, i]");
+        assert_eq!(eval("poly_roots(5, x)"), "[]");
+        assert_eq!(eval("poly_roots(5)"), "[]");
+        assert!(parse_str("poly_roots(0)").unwrap().eval().is_err());
+        // Without the noise of floats
+        assert_eq!(eval("poly_roots(x^3 - 6 * x^2 + 11 * x - 6)"), "[1, 2, 3]");
+        assert_eq!(eval("poly_roots(2 * x^2 - 5 * x - 3)"), "[-0.5, 3]");
+        assert_eq!(eval("poly_roots((x - 0.1) * (x - 0.3))"), "[0.1, 0.3]");
+    }
+}
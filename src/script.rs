@@ -0,0 +1,243 @@
+//! Files of statements, like the `.calc` files `calculator run` and the language server read.
+//!
+//! Statements are on their own lines or separated by `;`, and `#` starts a comment that
+//! goes to the end of the line:
+//! - `name = expression` sets a variable
+//! - `name(x, y) = expression` defines a function
+//! - `print expression` prints the value
+//! - `assert expression` fails if the value is false or 0
+//! - anything else is an expression to evaluate
+//!
+//! So a line can't be an equation like `x = 3`, but it still can be inside a function
+//! (ex: `solve(x^2 = 2, x)`).
+
+use crate::{
+    environment::Environment,
+    error::{parse_str, Error},
+    parser::TreeNode,
+    tokeniser::Token,
+    value::{EvalError, Value},
+};
+
+/// The text of one statement and where it is in the file
+#[derive(Debug, Clone)]
+pub struct Source {
+    /// The line it's on, from 0
+    pub line: usize,
+    /// Where it starts on the line, in characters
+    pub column: usize,
+    pub text: String,
+}
+
+/// The statements in the file, without comments or empty statements
+pub fn split(script: &str) -> Vec<Source> {
+    let mut sources = vec![];
+    for (line, text) in script.lines().enumerate() {
+        let code = text.split('#').next().unwrap_or_default();
+        let mut column = 0;
+        for statement in code.split(';') {
+            let trimmed = statement.trim();
+            if !trimmed.is_empty() {
+                let indent = statement.chars().take_while(|c| c.is_whitespace()).count();
+                sources.push(Source {
+                    line,
+                    column: column + indent,
+                    text: trimmed.to_string(),
+                });
+            }
+            // And one for the `;`
+            column += statement.chars().count() + 1;
+        }
+    }
+    return sources;
+}
+
+/// The rest of the statement if it starts with the keyword, with the keyword replaced by
+/// spaces so the spans of errors in it are still right
+fn after_keyword(text: &str, keyword: &str) -> Option<String> {
+    let rest = text.strip_prefix(keyword)?;
+    if rest.starts_with(|c: char| c.is_alphanumeric() || c == '_') {
+        return None;
+    }
+    return Some(" ".repeat(keyword.len()) + rest);
+}
+
+#[derive(Debug, Clone)]
+pub enum Statement {
+    /// `name = value`
+    Assign {
+        name: String,
+        value: TreeNode,
+    },
+    /// `name(params) = body`
+    Define {
+        name: String,
+        params: Vec<String>,
+        body: TreeNode,
+    },
+    /// `print expression`
+    Print(TreeNode),
+    /// `assert expression`
+    Assert(TreeNode),
+    Expression(TreeNode),
+}
+
+impl Statement {
+    /// Parses the statement, which is an assignment or definition if it's `=` with a name
+    /// (or a call with only names as its arguments) on the left
+    pub fn parse(text: &str) -> Result<Statement, Error> {
+        if let Some(rest) = after_keyword(text, "print") {
+            return Ok(Statement::Print(parse_str(&rest)?));
+        }
+        if let Some(rest) = after_keyword(text, "assert") {
+            return Ok(Statement::Assert(parse_str(&rest)?));
+        }
+
+        let tree = parse_str(text)?;
+        let (lhs, rhs) = match (tree.name(), &tree.left, &tree.right) {
+            (Some("="), Some(lhs), Some(rhs)) => (lhs, rhs),
+            _ => return Ok(Statement::Expression(tree)),
+        };
+        let name = match &lhs.value {
+            Some(Token::Variable(name)) => name.clone(),
+            _ => return Ok(Statement::Expression(tree)),
+        };
+        if !lhs.is_defined_call() {
+            return Ok(Statement::Assign {
+                name,
+                value: *rhs.clone(),
+            });
+        }
+
+        let mut params = vec![];
+        for arg in &lhs.args {
+            match &arg.value {
+                Some(Token::Variable(param)) if !arg.is_defined_call() => {
+                    params.push(param.clone())
+                }
+                _ => return Ok(Statement::Expression(tree)),
+            }
+        }
+        return Ok(Statement::Define {
+            name,
+            params,
+            body: *rhs.clone(),
+        });
+    }
+
+    /// The variable or function it sets, if it sets one
+    pub fn name(&self) -> Option<&str> {
+        return match self {
+            Statement::Assign { name, .. } | Statement::Define { name, .. } => Some(name),
+            _ => None,
+        };
+    }
+
+    /// The expression it evaluates, if it evaluates one when it's run
+    pub fn tree(&self) -> Option<&TreeNode> {
+        return match self {
+            Statement::Assign { value: tree, .. }
+            | Statement::Print(tree)
+            | Statement::Assert(tree)
+            | Statement::Expression(tree) => Some(tree),
+            Statement::Define { .. } => None,
+        };
+    }
+
+    /// Runs the statement, and gives its value (nothing for a definition). Printing is
+    /// left to whoever runs it.
+    pub fn run(&self, env: &mut Environment) -> Result<Option<Value>, EvalError> {
+        return match self {
+            Statement::Assign { name, value } => {
+                let value = value.eval_with(env)?;
+                env.undefine(name);
+                env.set(name, value.clone());
+                Ok(Some(value))
+            }
+            Statement::Define { name, params, body } => {
+                env.remove(name);
+                env.define(name, params.clone(), body.clone());
+                Ok(None)
+            }
+            Statement::Assert(tree) => {
+                let value = tree.eval_with(env)?;
+                if !value.is_truthy() {
+                    return Err(EvalError::AssertionFailed(tree.to_string()));
+                }
+                Ok(Some(value))
+            }
+            Statement::Print(tree) | Statement::Expression(tree) => Ok(Some(tree.eval_with(env)?)),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn statement(text: &str) -> Statement {
+        return Statement::parse(text).unwrap();
+    }
+
+    #[test]
+    fn splitting() {
+        let sources = split("x = 1; y = 2 # two\n\n  # only a comment\n   print x;;");
+        let places = sources
+            .iter()
+            .map(|s| (s.line, s.column, s.text.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            places,
+            vec![(0, 0, "x = 1"), (0, 7, "y = 2"), (3, 3, "print x")]
+        );
+    }
+
+    #[test]
+    fn keywords() {
+        assert_eq!(after_keyword("print x", "print").unwrap(), "      x");
+        assert!(after_keyword("printer + 1", "print").is_none());
+        assert!(after_keyword("x", "print").is_none());
+    }
+
+    #[test]
+    fn parsing() {
+        assert!(matches!(statement("x = 2 + 3"), Statement::Assign { name, .. } if name == "x"));
+        let define = statement("f(x, y) = x * y");
+        assert!(
+            matches!(&define, Statement::Define { name, params, .. } if name == "f" && params == &["x", "y"])
+        );
+        assert!(define.tree().is_none());
+        assert!(matches!(statement("print 1"), Statement::Print(_)));
+        assert!(matches!(statement("assert 1 < 2"), Statement::Assert(_)));
+        // Not a name or a call with names on the left
+        assert!(matches!(statement("2 = x"), Statement::Expression(_)));
+        assert!(matches!(statement("f(2) = 4"), Statement::Expression(_)));
+        assert!(matches!(statement("sin(x) = 1"), Statement::Expression(_)));
+        // Errors after a keyword are where they are in the statement
+        let error = Statement::parse("print (1").unwrap_err();
+        assert!(error.span().is_some_and(|span| span.start >= 6));
+    }
+
+    #[test]
+    fn running() {
+        let mut env = Environment::new();
+        let run = |text: &str, env: &mut Environment| statement(text).run(env);
+        assert_eq!(
+            run("x = 3", &mut env).unwrap().unwrap().as_number().ok(),
+            Some(3.0)
+        );
+        assert!(run("f(y) = x * y", &mut env).unwrap().is_none());
+        let value = run("print f(2)", &mut env).unwrap().unwrap();
+        assert_eq!(value.as_number().ok(), Some(6.0));
+        assert!(run("assert f(1) == 3", &mut env).is_ok());
+        assert!(matches!(
+            run("assert f(1) == 4", &mut env),
+            Err(EvalError::AssertionFailed(_))
+        ));
+        // Setting a variable replaces a function of the same name, and the other way around
+        run("f = 1", &mut env).unwrap();
+        assert!(env.function("f").is_none());
+        run("x(a) = a", &mut env).unwrap();
+        assert!(env.get("x").is_none());
+    }
+}
@@ -0,0 +1,525 @@
+//! A JSON-RPC 2.0 server, for editors and other programs that keep the calculator running.
+//!
+//! Requests and responses are JSON objects, one per line. Every method takes an optional
+//! `session` param (a name, `""` if it isn't given), and each session has its own variables
+//! and functions. Sessions belong to the connection, so two clients never share one.
+//!
+//! Methods (params, then result):
+//! - `evaluate` `{ expression }`, `{ expression, result, type, formatted }` like `--format json`
+//! - `tokenise` `{ expression }`, `[{ kind, text, span }]`
+//! - `parse` `{ expression }`, `{ tree, text }` (see `json::tree`), with `text` the expression
+//!   printed back from the tree
+//! - `define` `{ name, value }` sets a variable to the value of an expression, and
+//!   `{ name, params, body }` defines a function (ex: `{ "name": "f", "params": ["x"], "body": "x^2" }`)
+//! - `undefine` `{ name }` removes a variable or function
+//! - `complete` `{ expression, position }`, `{ span, items: [{ label, kind, detail }] }` for
+//!   the name before `position` (in characters, the end if it isn't given)
+//! - `cancel` `{ id }` stops the request with that id, which then fails with code -32800
+//!
+//! Errors from the calculator have the code -32000, and `{ kind, message, span }` as their data.
+
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex, MutexGuard, PoisonError,
+    },
+    thread,
+};
+
+use serde_json::{json, Value as Json};
+
+use crate::{
+    environment::Environment,
+    error::{eval_str, parse_str, Error},
+    format::Format,
+    json,
+    tokeniser::{get_thing, tokenise_with_spans, Token, NAMES},
+    value::EvalError,
+};
+
+pub(crate) const PARSE_ERROR: i64 = -32700;
+pub(crate) const INVALID_REQUEST: i64 = -32600;
+pub(crate) const METHOD_NOT_FOUND: i64 = -32601;
+pub(crate) const INVALID_PARAMS: i64 = -32602;
+/// A bug in the calculator, which panicked while answering the request
+const INTERNAL_ERROR: i64 = -32603;
+/// An error from the calculator itself, like a syntax error in the expression
+const CALCULATOR_ERROR: i64 = -32000;
+/// The same code as the Language Server Protocol uses
+const REQUEST_CANCELLED: i64 = -32800;
+
+/// A JSON-RPC error object
+pub struct RpcError {
+    code: i64,
+    message: String,
+    data: Option<Json>,
+}
+
+impl RpcError {
+    pub(crate) fn new(code: i64, message: String) -> RpcError {
+        return RpcError {
+            code,
+            message,
+            data: None,
+        };
+    }
+
+    fn to_json(&self) -> Json {
+        let mut error = json!({ "code": self.code, "message": self.message });
+        if let Some(data) = &self.data {
+            error["data"] = data.clone();
+        }
+        return error;
+    }
+}
+
+impl From<Error> for RpcError {
+    fn from(e: Error) -> RpcError {
+        if matches!(e, Error::Eval(EvalError::Cancelled, _)) {
+            return RpcError::new(REQUEST_CANCELLED, e.to_string());
+        }
+        return RpcError {
+            code: CALCULATOR_ERROR,
+            message: e.to_string(),
+            data: Some(json::error(&e)),
+        };
+    }
+}
+
+impl From<EvalError> for RpcError {
+    fn from(e: EvalError) -> RpcError {
+        return RpcError::from(Error::from(e));
+    }
+}
+
+pub(crate) fn response(id: &Json, result: Result<Json, RpcError>) -> Json {
+    return match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(error) => json!({ "jsonrpc": "2.0", "id": id, "error": error.to_json() }),
+    };
+}
+
+fn string_param<'a>(params: &'a Json, name: &str) -> Result<&'a str, RpcError> {
+    return match params.get(name) {
+        Some(Json::String(s)) => Ok(s),
+        _ => Err(RpcError::new(
+            INVALID_PARAMS,
+            format!("{} has to be a string", name),
+        )),
+    };
+}
+
+/// Whether `name` can be used for a variable or function, so it isn't a built-in name
+/// and it's read as a single variable
+fn check_name(name: &str) -> Result<(), RpcError> {
+    let tokens = tokenise_with_spans(name).map(|(tokens, _)| tokens);
+    if let Ok([Token::Variable(_)]) = tokens.as_deref() {
+        return Ok(());
+    }
+    return Err(RpcError::new(
+        INVALID_PARAMS,
+        format!("{} can't be the name of a variable or function", name),
+    ));
+}
+
+/// The variables and functions of each session of one connection
+#[derive(Default)]
+pub struct Server {
+    sessions: HashMap<String, Environment>,
+}
+
+impl Server {
+    pub fn new() -> Server {
+        return Server::default();
+    }
+
+    /// Handles one request, and gives the response (or `None` for a notification, which
+    /// has no id). Evaluating stops early with a `REQUEST_CANCELLED` error if `cancel` is set.
+    pub fn handle(&mut self, request: &Json, cancel: Option<Arc<AtomicBool>>) -> Option<Json> {
+        let id = request.get("id").cloned();
+        let method = request.get("method").and_then(|m| m.as_str());
+        let result = match method {
+            Some(_) if cancel.as_ref().is_some_and(|c| c.load(Ordering::Relaxed)) => {
+                Err(RpcError::from(EvalError::Cancelled))
+            }
+            Some(method) => {
+                let params = request.get("params").cloned().unwrap_or(json!({}));
+                self.call(method, &params, cancel)
+            }
+            None => Err(RpcError::new(
+                INVALID_REQUEST,
+                "A request needs a method".to_string(),
+            )),
+        };
+        return id.map(|id| response(&id, result));
+    }
+
+    fn call(
+        &mut self,
+        method: &str,
+        params: &Json,
+        cancel: Option<Arc<AtomicBool>>,
+    ) -> Result<Json, RpcError> {
+        let session = match params.get("session") {
+            Some(Json::String(session)) => session.clone(),
+            _ => String::new(),
+        };
+        let env = self.sessions.entry(session).or_default();
+        env.set_cancel(cancel);
+        let result = match method {
+            "evaluate" => evaluate(env, params),
+            "tokenise" => tokenise(params),
+            "parse" => parse(params),
+            "define" => define(env, params),
+            "undefine" => string_param(params, "name").map(|name| {
+                env.remove(name);
+                env.undefine(name);
+                json!({ "name": name })
+            }),
+            "complete" => complete(env, params),
+            _ => Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("There's no method called {}", method),
+            )),
+        };
+        env.set_cancel(None);
+        return result;
+    }
+}
+
+fn evaluate(env: &Environment, params: &Json) -> Result<Json, RpcError> {
+    let expression = string_param(params, "expression")?;
+    let result = eval_str(expression, env)?;
+    return Ok(json::result(expression, &result, &Format::default()));
+}
+
+fn tokenise(params: &Json) -> Result<Json, RpcError> {
+    let expression = string_param(params, "expression")?;
+    let (tokens, spans) = tokenise_with_spans(expression).map_err(Error::Token)?;
+    return Ok(json::tokens(expression, &tokens, &spans));
+}
+
+fn parse(params: &Json) -> Result<Json, RpcError> {
+    let tree = parse_str(string_param(params, "expression")?)?;
+    return Ok(json!({ "tree": json::tree(&tree), "text": tree.to_string() }));
+}
+
+fn define(env: &mut Environment, params: &Json) -> Result<Json, RpcError> {
+    let name = string_param(params, "name")?;
+    check_name(name)?;
+
+    if let Some(value) = params.get("value") {
+        let expression = value
+            .as_str()
+            .ok_or_else(|| RpcError::new(INVALID_PARAMS, "value has to be a string".to_string()))?;
+        let value = eval_str(expression, env)?;
+        env.undefine(name);
+        env.set(name, value.clone());
+        return Ok(json::result(expression, &value, &Format::default()));
+    }
+
+    let params_error = || {
+        RpcError::new(
+            INVALID_PARAMS,
+            "params has to be a list of names".to_string(),
+        )
+    };
+    let names = params.get("params").and_then(|p| p.as_array());
+    let mut names = names.ok_or_else(params_error)?.iter();
+    let names = names.try_fold(vec![], |mut names, name| {
+        let name = name.as_str().ok_or_else(params_error)?;
+        check_name(name)?;
+        names.push(name.to_string());
+        return Ok::<_, RpcError>(names);
+    })?;
+    let body = parse_str(string_param(params, "body")?)?;
+    let text = format!("{}({}) = {}", name, names.join(", "), body);
+    env.remove(name);
+    env.define(name, names, body);
+    return Ok(json!({ "name": name, "text": text }));
+}
+
+/// Where the name that ends at `end` starts
+pub(crate) fn word_start(chars: &[char], end: usize) -> usize {
+    let mut start = end;
+    while start > 0 && (chars[start - 1].is_alphanumeric() || chars[start - 1] == '_') {
+        start -= 1;
+    }
+    return start;
+}
+
+/// Every built-in name, variable and function that starts with `prefix`, in alphabetical
+/// order, as `(name, kind, detail)`
+pub(crate) fn completions(env: &Environment, prefix: &str) -> Vec<(String, &'static str, String)> {
+    let mut items = vec![];
+    for name in NAMES {
+        if let Ok(token) = get_thing(name) {
+            let detail = match &token {
+                Token::Constant(value) => value.to_string(),
+                _ => String::new(),
+            };
+            items.push((name.to_string(), token.kind(), detail));
+        }
+    }
+    for (name, value) in env.variables() {
+        items.push((name.clone(), "variable", value.to_string()));
+    }
+    for (name, function) in env.functions() {
+        let detail = format!(
+            "{}({}) = {}",
+            name,
+            function.params.join(", "),
+            function.body
+        );
+        items.push((name.clone(), "function", detail));
+    }
+    items.retain(|(name, ..)| name.starts_with(prefix));
+    items.sort();
+    return items;
+}
+
+/** complete
+
+The names that could go where the name before `position` is, as `{ span, items }`,
+where `span` is the part of the expression they'd replace.
+
+Algorithm:
+1. Go back from `position` while there are letters, digits or `_` to find the start of the name
+2. Give every built-in name, variable and function that starts with it (see `completions`)
+*/
+fn complete(env: &Environment, params: &Json) -> Result<Json, RpcError> {
+    let chars = string_param(params, "expression")?
+        .chars()
+        .collect::<Vec<_>>();
+    let end = match params.get("position") {
+        Some(position) => position
+            .as_u64()
+            .map(|p| p as usize)
+            .filter(|p| *p <= chars.len()),
+        None => Some(chars.len()),
+    };
+    let end = end.ok_or_else(|| {
+        RpcError::new(
+            INVALID_PARAMS,
+            "position has to be in the expression".to_string(),
+        )
+    })?;
+    let start = word_start(&chars, end);
+    let prefix = chars[start..end].iter().collect::<String>();
+    let items = completions(env, &prefix);
+
+    let items = items
+        .into_iter()
+        .map(|(label, kind, detail)| json!({ "label": label, "kind": kind, "detail": detail }));
+    return Ok(json!({
+        "span": { "start": start, "end": end },
+        "items": items.collect::<Vec<_>>(),
+    }));
+}
+
+/// Locks the mutex, even if a thread panicked while it had it. Everything behind these
+/// locks is changed in one step, so it's never left half done.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    return mutex.lock().unwrap_or_else(PoisonError::into_inner);
+}
+
+/** serve
+
+Answers the requests from `input` on `output` until `input` ends.
+
+Algorithm:
+1. Requests are run one at a time on another thread, so this one can keep reading
+2. Each request gets a flag, which `cancel` sets (it's answered here, without waiting)
+3. The evaluation checks the flag at every node, and stops with `REQUEST_CANCELLED`
+4. A request that panics is answered with `INTERNAL_ERROR`, and the session keeps going
+*/
+pub fn serve(input: impl BufRead, output: impl Write + Send) -> std::io::Result<()> {
+    let output = Mutex::new(output);
+    let write = |response: &Json| -> std::io::Result<()> {
+        let mut output = lock(&output);
+        output.write_all(format!("{}\n", response).as_bytes())?;
+        return output.flush();
+    };
+    let pending: Mutex<HashMap<String, Arc<AtomicBool>>> = Mutex::new(HashMap::new());
+
+    return thread::scope(|scope| {
+        let (sender, receiver) = mpsc::channel::<(Json, Arc<AtomicBool>)>();
+        let worker = scope.spawn(|| {
+            let mut server = Server::new();
+            for (request, cancel) in receiver {
+                let handled = panic::catch_unwind(AssertUnwindSafe(|| {
+                    return server.handle(&request, Some(cancel));
+                }));
+                let response = handled.unwrap_or_else(|_| {
+                    let error = RpcError::new(
+                        INTERNAL_ERROR,
+                        "The calculator crashed while answering this".to_string(),
+                    );
+                    request.get("id").map(|id| response(id, Err(error)))
+                });
+                if let Some(id) = request.get("id") {
+                    lock(&pending).remove(&id.to_string());
+                }
+                if let Some(response) = response {
+                    write(&response)?;
+                }
+            }
+            return Ok(());
+        });
+
+        for line in input.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let request = match serde_json::from_str::<Json>(&line) {
+                Ok(request) if request.is_object() => request,
+                Ok(_) => {
+                    let error =
+                        RpcError::new(INVALID_REQUEST, "A request has to be an object".to_string());
+                    write(&response(&Json::Null, Err(error)))?;
+                    continue;
+                }
+                Err(e) => {
+                    let error = RpcError::new(PARSE_ERROR, e.to_string());
+                    write(&response(&Json::Null, Err(error)))?;
+                    continue;
+                }
+            };
+
+            if request.get("method").and_then(|m| m.as_str()) == Some("cancel") {
+                let target = request.get("params").and_then(|p| p.get("id"));
+                let target = target.map(|id| id.to_string()).unwrap_or_default();
+                let cancel = lock(&pending).get(&target).cloned();
+                if let Some(cancel) = &cancel {
+                    cancel.store(true, Ordering::Relaxed);
+                }
+                if let Some(id) = request.get("id") {
+                    write(&response(id, Ok(json!({ "cancelled": cancel.is_some() }))))?;
+                }
+                continue;
+            }
+
+            let cancel = Arc::new(AtomicBool::new(false));
+            if let Some(id) = request.get("id") {
+                lock(&pending).insert(id.to_string(), cancel.clone());
+            }
+            if sender.send((request, cancel)).is_err() {
+                break;
+            }
+        }
+        drop(sender);
+        return worker
+            .join()
+            .unwrap_or_else(|_| Err(std::io::Error::other("The server stopped unexpectedly")));
+    });
+}
+
+/// Serves a connection on its own thread, and reports on stderr if it fails, since
+/// there's no one else to tell
+fn serve_connection<S>(stream: std::io::Result<S>, clone: fn(&S) -> std::io::Result<S>)
+where
+    S: Read + Write + Send + 'static,
+{
+    let stream = match stream {
+        Ok(stream) => stream,
+        Err(e) => {
+            eprintln!("Couldn't accept a connection: {}", e);
+            return;
+        }
+    };
+    thread::spawn(move || {
+        let result = clone(&stream).and_then(|input| serve(BufReader::new(input), stream));
+        if let Err(e) = result {
+            eprintln!("A connection stopped: {}", e);
+        }
+    });
+}
+
+/// Serves each connection to the TCP address (ex: `127.0.0.1:7700`) on its own thread
+pub fn serve_tcp(address: &str) -> std::io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    for stream in listener.incoming() {
+        serve_connection(stream, std::net::TcpStream::try_clone);
+    }
+    return Ok(());
+}
+
+/// Serves each connection to the Unix socket at `path` on its own thread
+#[cfg(unix)]
+pub fn serve_unix(path: &str) -> std::io::Result<()> {
+    let listener = std::os::unix::net::UnixListener::bind(path)?;
+    for stream in listener.incoming() {
+        serve_connection(stream, std::os::unix::net::UnixStream::try_clone);
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(server: &mut Server, method: &str, params: Json) -> Json {
+        let request = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+        return server.handle(&request, None).unwrap();
+    }
+
+    #[test]
+    fn sessions() {
+        let mut server = Server::new();
+        let response = request(&mut server, "define", json!({ "name": "x", "value": "2" }));
+        assert_eq!(response["result"]["result"], json!(2.0));
+        let params = json!({ "name": "f", "params": ["y"], "body": "y^2" });
+        request(&mut server, "define", params);
+        let response = request(&mut server, "evaluate", json!({ "expression": "f(x) + 1" }));
+        assert_eq!(response["result"]["result"], json!(5.0));
+        // Another session has its own variables
+        let params = json!({ "expression": "x", "session": "other" });
+        let response = request(&mut server, "evaluate", params);
+        assert_eq!(response["error"]["data"]["kind"], json!("unknown_variable"));
+        assert_eq!(
+            response["error"]["data"]["span"],
+            json!({ "start": 0, "end": 1 })
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let mut server = Server::new();
+        let response = request(&mut server, "nothing", json!({}));
+        assert_eq!(response["error"]["code"], json!(METHOD_NOT_FOUND));
+        let response = request(&mut server, "evaluate", json!({}));
+        assert_eq!(response["error"]["code"], json!(INVALID_PARAMS));
+        let response = request(&mut server, "evaluate", json!({ "expression": "1 +" }));
+        assert_eq!(response["error"]["code"], json!(CALCULATOR_ERROR));
+        // Notifications have no response
+        let notification = json!({ "jsonrpc": "2.0", "method": "evaluate" });
+        assert!(server.handle(&notification, None).is_none());
+    }
+
+    #[test]
+    fn cancelled() {
+        let mut server = Server::new();
+        let request = json!({ "id": 1, "method": "evaluate", "params": { "expression": "1" } });
+        let response = server.handle(&request, Some(Arc::new(AtomicBool::new(true))));
+        assert_eq!(response.unwrap()["error"]["code"], json!(REQUEST_CANCELLED));
+    }
+
+    #[test]
+    fn poisoned_locks() {
+        let mutex = Arc::new(Mutex::new(1));
+        let poisoner = mutex.clone();
+        let _ = thread::spawn(move || {
+            let _guard = poisoner.lock().unwrap();
+            panic!("poisoned");
+        })
+        .join();
+        assert!(mutex.is_poisoned());
+        *lock(&mutex) += 1;
+        assert_eq!(*lock(&mutex), 2);
+    }
+}
@@ -0,0 +1,560 @@
+use crate::{environment::Environment, parser::TreeNode, tokeniser::Token, value::Value};
+
+fn num(n: f64) -> TreeNode {
+    return TreeNode::number(n);
+}
+
+fn neg(a: TreeNode) -> TreeNode {
+    return TreeNode::operator("-", num(0.0), a);
+}
+
+/// A key that's the same for trees that are written the same
+fn key(t: &TreeNode) -> String {
+    return t.to_string();
+}
+
+fn is_operator(t: &TreeNode, names: &[&str]) -> bool {
+    return matches!(&t.value, Some(Token::Operator { name, .. }) if names.contains(&name.as_str()));
+}
+
+fn is_negation(t: &TreeNode) -> bool {
+    return is_operator(t, &["-"]) && t.left.as_ref().is_some_and(|l| l.as_number() == Some(0.0));
+}
+
+/// Whether it's a percentage like `10%`, which `+` and `-` treat differently from a number
+fn is_percent(t: &TreeNode) -> bool {
+    if matches!(&t.value, Some(Token::Function { name, .. }) if name == "%")
+        || matches!(&t.value, Some(Token::Constant(Value::Percent(_))))
+    {
+        return true;
+    }
+    return is_operator(t, &["+", "-"])
+        && is_percent(children(t).1)
+        && (is_negation(t) || is_percent(children(t).0));
+}
+
+fn children(t: &TreeNode) -> (&TreeNode, &TreeNode) {
+    return (t.left.as_deref().unwrap(), t.right.as_deref().unwrap());
+}
+
+impl TreeNode {
+    /// Returns an equivalent, smaller tree.
+    ///
+    /// This folds constants (`2 * 3` is `6`), removes things that do nothing
+    /// (`x * 1`, `x + 0`, `x ^ 1`), combines like terms (`2x + 3x` is `5x`)
+    /// and moves numbers to the front of products (`x * 2` is `2 * x`).
+    /// Variables are taken to be numbers, so products are put in order (`y * x` is `x * y`)
+    /// and factors cancel out (`x / x` is 1).
+    pub fn simplify(&self) -> TreeNode {
+        return self.simplify_with(&Environment::new());
+    }
+
+    /// Like [`TreeNode::simplify`], but products with a list or matrix in `env` (or a
+    /// function from it, which could give one) stay in the same order, since `*` doesn't
+    /// commute for them
+    pub fn simplify_with(&self, env: &Environment) -> TreeNode {
+        let node = self.simplify_children(env);
+
+        if let Some(folded) = node.fold() {
+            return folded;
+        }
+
+        match &node.value {
+            Some(Token::Operator { name, .. }) if node.left.is_some() && node.right.is_some() => {
+                return match name.as_str() {
+                    "+" | "-" => simplify_sum(&node),
+                    "*" | "/" => simplify_product(&node, env).unwrap_or(node),
+                    "^" => simplify_power(&node),
+                    _ => node,
+                };
+            }
+            Some(Token::Function { name, .. }) if node.left.is_some() => {
+                return simplify_function(name, &node);
+            }
+            Some(Token::Keyword(_)) => {
+                // if true then a else b is just a
+                if let [cond, then, otherwise] = &node.args[..] {
+                    if let Some(Token::Constant(c)) = &cond.value {
+                        if let Ok(b) = c.as_bool() {
+                            return if b { then.clone() } else { otherwise.clone() };
+                        }
+                    }
+                }
+                return node;
+            }
+            _ => {
+                return node;
+            }
+        }
+    }
+
+    fn simplify_children(&self, env: &Environment) -> TreeNode {
+        let mut node = self.clone();
+        node.left = self.left.as_ref().map(|t| Box::new(t.simplify_with(env)));
+        node.right = self.right.as_ref().map(|t| Box::new(t.simplify_with(env)));
+        node.args = self.args.iter().map(|t| t.simplify_with(env)).collect();
+        return node;
+    }
+
+    /// Evaluates operators and functions of constants, but only when the
+    /// result is exact enough to be worth it (`2 * 3` but not `ln(2)`)
+    fn fold(&self) -> Option<TreeNode> {
+        if !matches!(
+            self.value,
+            Some(Token::Operator { .. }) | Some(Token::Function { .. })
+        ) {
+            return None;
+        }
+        let all_constant = self
+            .left
+            .iter()
+            .chain(self.right.iter())
+            .all(|t| matches!(t.value, Some(Token::Constant(_))));
+        if !all_constant {
+            return None;
+        }
+
+        // Things like 1 / 0 are left for the evaluator to complain about
+        let value = self.eval_with(&Environment::new()).ok()?;
+        return match value {
+            Value::Number(n) => {
+                if is_operator(self, &["+", "-", "*"]) || n.fract() == 0.0 {
+                    Some(num(n))
+                } else {
+                    None
+                }
+            }
+            Value::Bool(_) => Some(TreeNode::leaf(Token::Constant(value))),
+            _ => None,
+        };
+    }
+}
+
+/// Splits `3 * x * y` into 3 and `x * y`
+fn split_coefficient(t: &TreeNode) -> (f64, TreeNode) {
+    if is_operator(t, &["*", "/"]) {
+        let (left, right) = children(t);
+        let name = t.name().unwrap().to_string();
+        if let Some(n) = left.as_number() {
+            if name == "*" {
+                return (n, right.clone());
+            }
+            return (n, TreeNode::operator("/", num(1.0), right.clone()));
+        }
+        let (c, rest) = split_coefficient(left);
+        if c != 1.0 {
+            return (c, TreeNode::operator(&name, rest, right.clone()));
+        }
+    }
+    return (1.0, t.clone());
+}
+
+/// Puts a number in front of a product (`2` and `x * y` is `2 * x * y`)
+fn with_coefficient(c: f64, t: TreeNode) -> TreeNode {
+    if c == 1.0 {
+        return t;
+    }
+    if let Some(n) = t.as_number() {
+        return num(c * n);
+    }
+    if is_operator(&t, &["*", "/"]) {
+        let (left, right) = children(&t);
+        let name = t.name().unwrap().to_string();
+        return TreeNode::operator(&name, with_coefficient(c, left.clone()), right.clone());
+    }
+    return TreeNode::operator("*", num(c), t);
+}
+
+fn collect_terms(t: &TreeNode, sign: f64, terms: &mut Vec<(f64, Option<TreeNode>)>) {
+    // `x + 10%` is x made 10% bigger, so it stays together as one term
+    if is_operator(t, &["+", "-"]) && !is_percent(children(t).1) {
+        let (left, right) = children(t);
+        let right_sign = if is_operator(t, &["-"]) { -sign } else { sign };
+        collect_terms(left, sign, terms);
+        collect_terms(right, right_sign, terms);
+        return;
+    }
+    if let Some(n) = t.as_number() {
+        terms.push((sign * n, None));
+        return;
+    }
+    let (c, rest) = split_coefficient(t);
+    terms.push((sign * c, Some(rest)));
+}
+
+/** simplify_sum
+
+Algorithm:
+1. Flatten the `+` and `-` into a list of terms like `3 * x` or `-2`, except for adding
+   or subtracting a percentage, which stays as one term
+2. Add up the numbers in front of terms that are the same (`2x - x` is `x`)
+3. Add up the constants and put them at the end
+4. Build it back, using `-` for negative terms
+*/
+fn simplify_sum(t: &TreeNode) -> TreeNode {
+    let mut terms = vec![];
+    collect_terms(t, 1.0, &mut terms);
+
+    let mut constant = 0.0;
+    let mut like: Vec<(String, f64, TreeNode)> = vec![];
+    for (c, term) in terms {
+        match term {
+            None => constant += c,
+            Some(term) => {
+                let k = key(&term);
+                match like.iter_mut().find(|(other, _, _)| *other == k) {
+                    Some((_, total, _)) => *total += c,
+                    None => like.push((k, c, term)),
+                }
+            }
+        }
+    }
+
+    let mut result: Option<TreeNode> = None;
+    let mut push = |c: f64, term: Option<TreeNode>| {
+        let magnitude = match term {
+            Some(term) => with_coefficient(c.abs(), term),
+            None => num(c.abs()),
+        };
+        result = Some(match result.take() {
+            None if c < 0.0 => neg(magnitude),
+            None => magnitude,
+            Some(acc) if c < 0.0 => TreeNode::operator("-", acc, magnitude),
+            Some(acc) => TreeNode::operator("+", acc, magnitude),
+        });
+    };
+
+    for (_, c, term) in like {
+        if c != 0.0 {
+            push(c, Some(term));
+        }
+    }
+    if constant != 0.0 {
+        push(constant, None);
+    }
+
+    return result.unwrap_or(num(0.0));
+}
+
+/// Where a factor is in a product, which decides how much of it can be flattened
+#[derive(Clone, Copy, PartialEq)]
+enum Place {
+    /// On the left of everything else, so `(x / y) * z` is just `x`, `/ y`, `* z`
+    Start,
+    /// On the right of a `*`, where only more `*`s can be flattened
+    Multiplied,
+    /// On the right of a `/`, where nothing can be
+    Divided,
+}
+
+/// Returns false if something was divided by 0
+fn collect_factors(
+    t: &TreeNode,
+    place: Place,
+    coefficient: &mut f64,
+    factors: &mut Vec<(bool, TreeNode)>,
+) -> bool {
+    if is_negation(t) {
+        *coefficient = -*coefficient;
+        return collect_factors(children(t).1, place, coefficient, factors);
+    }
+    if let Some(n) = t.as_number() {
+        if place == Place::Divided {
+            if n == 0.0 {
+                return false;
+            }
+            *coefficient /= n;
+        } else {
+            *coefficient *= n;
+        }
+        return true;
+    }
+    let flatten = match place {
+        Place::Start => is_operator(t, &["*", "/"]),
+        Place::Multiplied => is_operator(t, &["*"]),
+        Place::Divided => false,
+    };
+    if flatten {
+        let (left, right) = children(t);
+        let right_place = if is_operator(t, &["/"]) {
+            Place::Divided
+        } else {
+            Place::Multiplied
+        };
+        return collect_factors(left, place, coefficient, factors)
+            && collect_factors(right, right_place, coefficient, factors);
+    }
+    factors.push((place == Place::Divided, t.clone()));
+    return true;
+}
+
+/// Like `collect_factors`, but for numbers, where every `*` and `/` can be flattened
+/// (`x / (y / z)` is `x`, `/ y`, `* z`)
+fn collect_number_factors(
+    t: &TreeNode,
+    divides: bool,
+    coefficient: &mut f64,
+    factors: &mut Vec<(bool, TreeNode)>,
+) -> bool {
+    if is_negation(t) {
+        *coefficient = -*coefficient;
+        return collect_number_factors(children(t).1, divides, coefficient, factors);
+    }
+    if let Some(n) = t.as_number() {
+        if divides {
+            if n == 0.0 {
+                return false;
+            }
+            *coefficient /= n;
+        } else {
+            *coefficient *= n;
+        }
+        return true;
+    }
+    if is_operator(t, &["*", "/"]) {
+        let (left, right) = children(t);
+        let right_divides = divides != is_operator(t, &["/"]);
+        return collect_number_factors(left, divides, coefficient, factors)
+            && collect_number_factors(right, right_divides, coefficient, factors);
+    }
+    factors.push((divides, t.clone()));
+    return true;
+}
+
+/// Whether it can't be a list or matrix, so it commutes in products. Names that aren't set
+/// in `env` are numbers.
+fn is_scalar(t: &TreeNode, env: &Environment) -> bool {
+    let scalar = match &t.value {
+        Some(Token::Call { name, .. }) => name != "list",
+        Some(Token::Constant(value)) => !matches!(value, Value::List(_)),
+        Some(Token::Variable(name)) if t.is_defined_call() => env.function(name).is_none(),
+        Some(Token::Variable(name)) => {
+            !matches!(env.get(name), Some(Value::List(_) | Value::Expression(_)))
+        }
+        _ => true,
+    };
+    let mut children = t.left.iter().chain(t.right.iter()).map(|t| t.as_ref());
+    return scalar
+        && children.all(|t| is_scalar(t, env))
+        && t.args.iter().all(|t| is_scalar(t, env));
+}
+
+/// Where a factor goes in a product of numbers: names, then powers of names, then everything
+/// else, each in alphabetical order (so `sin(x) * x^2 * y * x` is `x * y * x^2 * sin(x)`)
+fn factor_order(t: &TreeNode) -> (u8, String) {
+    if let (Some(Token::Variable(name)), false) = (&t.value, t.is_defined_call()) {
+        return (0, name.clone());
+    }
+    if is_operator(t, &["^"]) && matches!(children(t).0.value, Some(Token::Variable(_))) {
+        return (1, key(t));
+    }
+    return (2, key(t));
+}
+
+/// Takes out factors that are both multiplied and divided (`x * y / x` is `y`)
+fn cancel(factors: Vec<(bool, TreeNode)>) -> Vec<(bool, TreeNode)> {
+    let mut kept: Vec<(bool, TreeNode)> = vec![];
+    for (divides, factor) in factors {
+        let k = key(&factor);
+        match kept.iter().position(|(d, f)| *d != divides && key(f) == k) {
+            Some(i) => {
+                kept.remove(i);
+            }
+            None => kept.push((divides, factor)),
+        }
+    }
+    return kept;
+}
+
+fn power(base: TreeNode, exponent: TreeNode) -> TreeNode {
+    if exponent.as_number() == Some(1.0) {
+        return base;
+    }
+    return TreeNode::operator("^", base, exponent);
+}
+
+/** simplify_product
+
+A list or matrix in `env` is where `*` doesn't commute and `/` and `^` work on each element,
+so if there's one, the factors stay as they are and in the same order.
+
+Algorithm:
+1. Flatten the `*` and `/` into a number and a list of the other factors, with whether each
+   one divides. If it's all numbers (see `is_scalar`), every `*` and `/` is flattened,
+   otherwise only the ones `collect_factors` can
+2. If it's all numbers, cancel the factors that are both multiplied and divided, and sort
+   them with the multiplied ones first (see `factor_order`)
+3. Build it back as `number * factors`, or `number / factor ...` if the first one divides
+*/
+fn simplify_product(t: &TreeNode, env: &Environment) -> Option<TreeNode> {
+    let numbers = is_scalar(t, env);
+    let mut coefficient = 1.0;
+    let mut factors = vec![];
+    let collected = match numbers {
+        true => collect_number_factors(t, false, &mut coefficient, &mut factors),
+        false => collect_factors(t, Place::Start, &mut coefficient, &mut factors),
+    };
+    if !collected {
+        return None;
+    }
+    if coefficient == 0.0 {
+        return Some(num(0.0));
+    }
+    if numbers {
+        factors = cancel(factors);
+        factors.sort_by_cached_key(|(divides, factor)| (*divides, factor_order(factor)));
+    }
+
+    let starts_divided = factors.first().is_some_and(|(divides, _)| *divides);
+    let mut result: Option<TreeNode> = None;
+    for (divides, factor) in factors {
+        result = Some(match result {
+            Some(acc) if divides => TreeNode::operator("/", acc, factor),
+            Some(acc) => TreeNode::operator("*", acc, factor),
+            None if divides => TreeNode::operator("/", num(coefficient.abs()), factor),
+            None => factor,
+        });
+    }
+    let mut result = match result {
+        Some(result) if starts_divided => result,
+        Some(result) => with_coefficient(coefficient.abs(), result),
+        None => num(coefficient.abs()),
+    };
+    if coefficient < 0.0 {
+        result = neg(result);
+    }
+    return Some(result);
+}
+
+/** simplify_power
+
+1. `x ^ 0` is 1, `x ^ 1` is x and `1 ^ x` is 1
+2. `(x ^ a) ^ n` is `x ^ (a * n)` when n is a whole number
+*/
+fn simplify_power(t: &TreeNode) -> TreeNode {
+    let (base, exponent) = children(t);
+    if exponent.as_number() == Some(0.0) || base.as_number() == Some(1.0) {
+        return num(1.0);
+    }
+    if exponent.as_number() == Some(1.0) {
+        return base.clone();
+    }
+    if let Some(n) = exponent.as_number() {
+        if n.fract() == 0.0 && is_operator(base, &["^"]) {
+            let (inner_base, inner_exponent) = children(base);
+            let exponent = TreeNode::operator("*", inner_exponent.clone(), num(n)).simplify();
+            return power(inner_base.clone(), exponent);
+        }
+    }
+    return t.clone();
+}
+
+/// Functions that undo each other (`ln(exp(x))` is `x`)
+fn simplify_function(name: &str, t: &TreeNode) -> TreeNode {
+    let arg = t.left.as_deref().unwrap();
+    let inner = arg.left.as_deref();
+    return match (name, arg.name(), inner) {
+        ("ln", Some("exp"), Some(inner)) => inner.clone(),
+        ("exp", Some("ln"), Some(inner)) => inner.clone(),
+        ("abs", Some("abs"), Some(_)) => arg.clone(),
+        ("sqrt", Some("^"), _) if arg.right.as_ref().and_then(|r| r.as_number()) == Some(2.0) => {
+            TreeNode::function("abs", inner.unwrap().clone())
+        }
+        _ => t.clone(),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{environment::Environment, error::parse_str};
+
+    fn simplify(input: &str) -> String {
+        return parse_str(input).unwrap().simplify().to_string();
+    }
+
+    #[test]
+    fn identities() {
+        assert_eq!(simplify("x + 0"), "x");
+        assert_eq!(simplify("x * 1"), "x");
+        assert_eq!(simplify("x / 1"), "x");
+        assert_eq!(simplify("(x + 1) * 0"), "0");
+        assert_eq!(simplify("x^0"), "1");
+        assert_eq!(simplify("x^1"), "x");
+        assert_eq!(simplify("--x"), "x");
+    }
+
+    #[test]
+    fn like_terms() {
+        assert_eq!(simplify("2 * x + 3 * x"), "5 * x");
+        assert_eq!(simplify("3 * x - x"), "2 * x");
+        assert_eq!(simplify("(x + 1) - (x + 1)"), "0");
+        assert_eq!(simplify("2 + 3 * x - 2"), "3 * x");
+        // Products are like terms whatever order they're in
+        assert_eq!(simplify("x * y - y * x"), "0");
+        assert_eq!(simplify("2 * x * y + y * x"), "3 * x * y");
+        assert_eq!(simplify("sin(x) * cos(x) - cos(x) * sin(x)"), "0");
+    }
+
+    #[test]
+    fn constants_are_folded() {
+        assert_eq!(simplify("2 * 3 + x"), "x + 6");
+        assert_eq!(simplify("sin(0) + x"), "x");
+        assert_eq!(simplify("ln(e)"), "1");
+    }
+
+    #[test]
+    fn products_are_in_order() {
+        assert_eq!(simplify("y * x"), "x * y");
+        assert_eq!(simplify("sin(x) * x^2 * y * x"), "x * y * x^2 * sin(x)");
+        assert_eq!(simplify("(x / y) * z"), "x * z / y");
+        assert_eq!(simplify("x * 2 * y / 4"), "0.5 * x * y");
+        assert_eq!(simplify("-x * 3"), "-3 * x");
+        assert_eq!(simplify("2 / x"), "2 / x");
+    }
+
+    #[test]
+    fn factors_cancel() {
+        assert_eq!(simplify("x / x"), "1");
+        assert_eq!(simplify("x * y / x"), "y");
+        assert_eq!(simplify("2 * sin(x) / 4 / sin(x)"), "0.5");
+        assert_eq!(simplify("x / (y * x)"), "1 / y");
+        assert_eq!(simplify("x * (1 / x)"), "1");
+        assert_eq!(simplify("x / (y / z)"), "x * z / y");
+    }
+
+    #[test]
+    fn matrices_keep_their_order() {
+        let mut env = Environment::new();
+        env.set("a", parse_str("[[1, 2], [3, 4]]").unwrap().eval().unwrap());
+        let simplify = |input: &str| parse_str(input).unwrap().simplify_with(&env).to_string();
+        assert_eq!(simplify("b * a"), "b * a");
+        assert_eq!(simplify("a / a"), "a / a");
+        assert_eq!(simplify("b * [1, 2] * c"), "b * [1, 2] * c");
+        assert_eq!(simplify("c * b * 2"), "2 * b * c");
+    }
+
+    #[test]
+    fn matrices_are_the_same_after() {
+        let mut env = Environment::new();
+        env.set("a", parse_str("[[1, 2], [3, 4]]").unwrap().eval().unwrap());
+        env.set("b", parse_str("[[0, 1], [1, 0]]").unwrap().eval().unwrap());
+        for input in ["a * b * a", "a * a", "2 * b * a / 4", "a * (b / a)"] {
+            let tree = parse_str(input).unwrap();
+            let before = tree.eval_with(&env).unwrap().to_string();
+            let after = tree
+                .simplify_with(&env)
+                .eval_with(&env)
+                .unwrap()
+                .to_string();
+            assert_eq!(before, after, "{}", input);
+        }
+    }
+
+    #[test]
+    fn percentages_stay_where_they_are() {
+        assert_eq!(simplify("200 + 10% - 10%"), "200 + 10% - 10%");
+        assert_eq!(simplify("x + 10% + x"), "x + 10% + x");
+        assert_eq!(simplify("2 * x + 3 * x + 10%"), "5 * x + 10%");
+        assert_eq!(simplify("x + 0 + 10%"), "x + 10%");
+    }
+}
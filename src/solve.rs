@@ -0,0 +1,323 @@
+use crate::{
+    compile::CompiledExpr,
+    environment::Environment,
+    parser::TreeNode,
+    value::{EvalError, Value},
+};
+
+/// How many times Brent's method can narrow the interval before giving up
+const MAX_ITERATIONS: usize = 200;
+
+/// How many pieces `find_roots` cuts the interval into when looking for sign changes
+const STEPS: usize = 1000;
+
+/// An expression as a function of one variable
+pub(crate) struct Func {
+    tree: TreeNode,
+    var: String,
+    env: Environment,
+    /// `None` if the expression has something that can't be compiled
+    compiled: Option<CompiledExpr>,
+}
+
+impl Func {
+    /// Every other variable in the tree has to be set in `env`
+    pub(crate) fn new(tree: &TreeNode, var: &str, env: &Environment) -> Result<Func, EvalError> {
+        // The variable is the input, even if it's also set outside
+        let mut env = env.clone();
+        env.remove(var);
+
+        let compiled = match tree.compile_with(&env) {
+            Ok(compiled) => Some(compiled),
+            Err(EvalError::NotCompilable(_)) => None,
+            Err(e) => return Err(e),
+        };
+        if let Some(compiled) = &compiled {
+            if let Some(other) = compiled.variables().iter().find(|v| *v != var) {
+                return Err(EvalError::UnknownVariable(other.clone()));
+            }
+        }
+
+        return Ok(Func {
+            tree: tree.clone(),
+            var: var.to_string(),
+            env,
+            compiled,
+        });
+    }
+
+    pub(crate) fn at(&self, x: f64) -> Result<f64, EvalError> {
+        // Compiled expressions don't check, and this is where sums and solvers spend their time
+        self.env.check_cancelled()?;
+        if let Some(compiled) = &self.compiled {
+            return compiled.eval(&[x]);
+        }
+        let mut env = self.env.scope();
+        env.bind(&self.var, Value::Number(x));
+        return self.tree.eval_with(&env)?.as_number();
+    }
+
+    /// The derivative as a function, if it can be worked out
+    pub(crate) fn derivative(&self) -> Option<Func> {
+        let derivative = self.tree.derivative(&self.var).ok()?;
+        return Func::new(&derivative, &self.var, &self.env).ok();
+    }
+}
+
+fn same_sign(a: f64, b: f64) -> bool {
+    return (a < 0.0) == (b < 0.0);
+}
+
+/** brent
+
+Finds a root between `a` and `b`, where `f(a)` and `f(b)` have different signs.
+
+Algorithm:
+1. Keep an interval where the sign changes, with `b` being the best guess so far
+2. Guess where the root is from the last three points (inverse quadratic interpolation),
+   or from the last two (secant)
+3. If the guess is outside the interval or isn't shrinking it fast enough, bisect instead
+4. Stop when the interval is as small as the floats around `b` allow
+*/
+fn brent(f: &Func, mut a: f64, mut b: f64, mut fa: f64, mut fb: f64) -> Result<f64, EvalError> {
+    if fa.abs() < fb.abs() {
+        std::mem::swap(&mut a, &mut b);
+        std::mem::swap(&mut fa, &mut fb);
+    }
+    let mut c = a;
+    let mut fc = fa;
+    let mut d = c;
+    let mut bisected = true;
+
+    for _ in 0..MAX_ITERATIONS {
+        let tolerance = 4.0 * f64::EPSILON * b.abs() + f64::MIN_POSITIVE;
+        if fb == 0.0 || (b - a).abs() <= tolerance {
+            return Ok(b);
+        }
+
+        let mut s = if fa != fc && fb != fc {
+            a * fb * fc / ((fa - fb) * (fa - fc))
+                + b * fa * fc / ((fb - fa) * (fb - fc))
+                + c * fa * fb / ((fc - fa) * (fc - fb))
+        } else {
+            b - fb * (b - a) / (fb - fa)
+        };
+
+        let between = (s - (3.0 * a + b) / 4.0) * (s - b) < 0.0;
+        let slow = if bisected {
+            (s - b).abs() >= (b - c).abs() / 2.0 || (b - c).abs() < tolerance
+        } else {
+            (s - b).abs() >= (c - d).abs() / 2.0 || (c - d).abs() < tolerance
+        };
+        bisected = !between || slow || s.is_nan();
+        if bisected {
+            s = (a + b) / 2.0;
+        }
+
+        let fs = f.at(s)?;
+        d = c;
+        c = b;
+        fc = fb;
+        if same_sign(fa, fs) {
+            a = s;
+            fa = fs;
+        } else {
+            b = s;
+            fb = fs;
+        }
+        if fa.abs() < fb.abs() {
+            std::mem::swap(&mut a, &mut b);
+            std::mem::swap(&mut fa, &mut fb);
+        }
+    }
+    return Err(EvalError::TooManyIterations("root".to_string()));
+}
+
+/// Takes Newton steps from `x` while they stay between `low` and `high` and get closer to 0
+fn newton(f: &Func, df: &Func, mut x: f64, low: f64, high: f64, steps: usize) -> f64 {
+    let mut fx = match f.at(x) {
+        Ok(fx) => fx,
+        Err(_) => return x,
+    };
+    for _ in 0..steps {
+        let slope = match df.at(x) {
+            Ok(slope) if slope != 0.0 => slope,
+            _ => break,
+        };
+        let next = x - fx / slope;
+        if !(low..=high).contains(&next) {
+            break;
+        }
+        match f.at(next) {
+            Ok(f_next) if f_next.abs() < fx.abs() => {
+                x = next;
+                fx = f_next;
+            }
+            _ => break,
+        }
+    }
+    return x;
+}
+
+/// Whether `f(x)` is close to 0 next to the values at the ends of the interval, which it
+/// isn't where the sign changes at a jump (like `1/x` at 0) or a pole (like `tan(x)` at pi/2)
+fn is_root(f: &Func, x: f64, fa: f64, fb: f64) -> bool {
+    let size = 1.0_f64.max(fa.abs()).max(fb.abs());
+    return f.at(x).is_ok_and(|fx| fx.abs() <= 1e-6 * size);
+}
+
+/// A root between `a` and `b`, where the function has to change sign (and not because
+/// it jumps over 0)
+pub(crate) fn find_root(f: &Func, a: f64, b: f64) -> Result<f64, EvalError> {
+    let fa = f.at(a)?;
+    let fb = f.at(b)?;
+    if fa == 0.0 {
+        return Ok(a);
+    }
+    if fb == 0.0 {
+        return Ok(b);
+    }
+    if same_sign(fa, fb) || fa.is_nan() || fb.is_nan() {
+        return Err(EvalError::NoSignChange(a, b));
+    }
+
+    let discontinuity = || {
+        EvalError::Domain(format!(
+            "There's no root between {} and {}, only a sign change at a discontinuity",
+            a, b
+        ))
+    };
+    // Landing right on something like the 0 in `1/x`
+    let root = match brent(f, a, b, fa, fb) {
+        Err(EvalError::DivisionByZero) => return Err(discontinuity()),
+        root => root?,
+    };
+    let root = match f.derivative() {
+        Some(df) => newton(f, &df, root, a.min(b), a.max(b), 5),
+        None => root,
+    };
+    if !is_root(f, root, fa, fb) {
+        return Err(discontinuity());
+    }
+    return Ok(root);
+}
+
+/** find_roots
+
+Finds every root between `a` and `b` that it can.
+
+Algorithm:
+1. Cut the interval into `STEPS` pieces and evaluate the function at the ends of each
+2. Every piece where the sign changes has a root, found with `find_root`, unless the
+   function jumps over 0 there (like `1/x` at 0), which is skipped
+3. Where the function gets close to 0 without changing sign (like `x^2`), try Newton's method
+   from the closest point, if the derivative is known
+4. Sort the roots and remove the ones that were found twice
+*/
+pub(crate) fn find_roots(f: &Func, a: f64, b: f64) -> Result<Vec<f64>, EvalError> {
+    let step = (b - a) / STEPS as f64;
+    let xs = (0..=STEPS).map(|i| a + step * i as f64).collect::<Vec<_>>();
+    // Points outside the domain (like sqrt of a negative) are NaN
+    let ys = xs
+        .iter()
+        .map(|x| f.at(*x).unwrap_or(f64::NAN))
+        .collect::<Vec<_>>();
+    let df = f.derivative();
+
+    let mut roots = vec![];
+    for i in 0..=STEPS {
+        let (x, y) = (xs[i], ys[i]);
+        if y == 0.0 {
+            roots.push(x);
+            continue;
+        }
+        if i < STEPS && !y.is_nan() && !ys[i + 1].is_nan() && !same_sign(y, ys[i + 1]) {
+            match find_root(f, x, xs[i + 1]) {
+                Ok(root) => roots.push(root),
+                Err(EvalError::Domain(_)) => {}
+                Err(e) => return Err(e),
+            }
+            continue;
+        }
+
+        let dip = i > 0 && i < STEPS && y.abs() < ys[i - 1].abs() && y.abs() <= ys[i + 1].abs();
+        if let (true, Some(df)) = (dip, &df) {
+            let root = newton(f, df, x, xs[i - 1], xs[i + 1], 100);
+            if f.at(root)?.abs() < 1e-10 {
+                roots.push(root);
+            }
+        }
+    }
+
+    roots.sort_by(f64::total_cmp);
+    roots.dedup_by(|a, b| (*a - *b).abs() <= 1e-9 * (1.0 + b.abs()));
+    return Ok(roots);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::parse_str;
+    use std::f64::consts::PI;
+
+    fn func(input: &str) -> Func {
+        return Func::new(&parse_str(input).unwrap(), "x", &Environment::new()).unwrap();
+    }
+
+    #[test]
+    fn single_root() {
+        let root = find_root(&func("cos(x) - x"), 0.0, 1.0).unwrap();
+        assert!((root - 0.7390851332151607).abs() < 1e-12);
+        assert_eq!(find_root(&func("x - 1"), 1.0, 5.0).unwrap(), 1.0);
+        assert!(matches!(
+            find_root(&func("x^2 + 1"), 0.0, 2.0),
+            Err(EvalError::NoSignChange(..))
+        ));
+        // Poles and jumps change the sign without a root
+        for (input, a, b) in [("tan(x)", 1.0, 2.0), ("1 / x", -1.0, 2.0)] {
+            let root = find_root(&func(input), a, b);
+            assert!(matches!(root, Err(EvalError::Domain(_))), "{}", input);
+        }
+    }
+
+    #[test]
+    fn every_root() {
+        let roots = find_roots(&func("sin(x)"), -1.0, 7.0).unwrap();
+        assert_eq!(roots.len(), 3);
+        for (root, expected) in roots.iter().zip([0.0, PI, 2.0 * PI]) {
+            assert!((root - expected).abs() < 1e-12);
+        }
+        assert!(find_roots(&func("x^2 + 1"), -3.0, 3.0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn through_the_parser() {
+        let solve = parse_str("solve(x^2 = 4, x, -5, 5)")
+            .unwrap()
+            .eval()
+            .unwrap();
+        assert_eq!(solve.to_string(), "[-2, 2]");
+        let mut env = Environment::new();
+        env.set("a", Value::Number(9.0));
+        let root = parse_str("root(x^2 - a, 0, 5)")
+            .unwrap()
+            .eval_with(&env)
+            .unwrap();
+        assert_eq!(root.as_number().unwrap(), 3.0);
+        // Every other variable has to be set
+        assert!(parse_str("root(x^2 - b, 0, 5)").unwrap().eval().is_err());
+    }
+
+    #[test]
+    fn without_compiling() {
+        // `max` can't be compiled, so each point is evaluated in a scope of the environment
+        let mut env = Environment::new();
+        env.set("x", Value::Number(100.0));
+        env.set("big", Value::List(vec![Value::Number(1.0); 10_000]));
+        let f = Func::new(&parse_str("max(x, 1) - 2").unwrap(), "x", &env).unwrap();
+        assert!(f.compiled.is_none());
+        assert_eq!(f.at(5.0).unwrap(), 3.0);
+        assert_eq!(find_root(&f, 0.0, 5.0).unwrap(), 2.0);
+        assert_eq!(env.get("x").unwrap().as_number().ok(), Some(100.0));
+    }
+}
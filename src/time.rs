@@ -0,0 +1,253 @@
+//! Dates and durations.
+//!
+//! Both are kept as seconds: durations as how many seconds long they are, and dates as the
+//! seconds since 1970-01-01 in UTC. Dates use the Gregorian calendar, even before it existed.
+
+use crate::value::{EvalError, Value};
+
+pub const MINUTE: f64 = 60.0;
+pub const HOUR: f64 = 60.0 * MINUTE;
+pub const DAY: f64 = 24.0 * HOUR;
+pub const WEEK: f64 = 7.0 * DAY;
+
+/// The units a duration can be written in (ex: `90min`), and how many seconds they are
+pub const UNITS: [(&str, f64); 11] = [
+    ("ms", 0.001),
+    ("s", 1.0),
+    ("sec", 1.0),
+    ("min", MINUTE),
+    ("h", HOUR),
+    ("hr", HOUR),
+    ("d", DAY),
+    ("day", DAY),
+    ("days", DAY),
+    ("w", WEEK),
+    ("wk", WEEK),
+];
+
+fn is_leap_year(year: i64) -> bool {
+    return year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    return match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+}
+
+/** days_from_civil
+
+The days since 1970-01-01 of a date.
+
+Algorithm (from Howard Hinnant's `chrono`-compatible date algorithms):
+1. Start the year in March, so the leap day is at the end of it
+2. Count the days in the 400 year eras before it, the years in its era, and the days in its year
+*/
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let month_from_march = (month as i64 + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    return era * 146_097 + day_of_era - 719_468;
+}
+
+/// The year, month and day of the days since 1970-01-01 (the inverse of `days_from_civil`)
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_from_march + 2) / 5 + 1) as u32;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    } as u32;
+    let year = era * 400 + year_of_era + if month <= 2 { 1 } else { 0 };
+    return (year, month, day);
+}
+
+/// The date as seconds, or `None` if there's no such day (ex: `2026-02-30`)
+pub fn date(year: i64, month: u32, day: u32, seconds: f64) -> Option<f64> {
+    if !(1..=12).contains(&month) || day == 0 || day > days_in_month(year, month) {
+        return None;
+    }
+    if !(0.0..DAY).contains(&seconds) {
+        return None;
+    }
+    return Some(days_from_civil(year, month, day) as f64 * DAY + seconds);
+}
+
+/// The start of the day (in UTC) the date is on
+pub fn start_of_day(date: f64) -> f64 {
+    return (date / DAY).floor() * DAY;
+}
+
+/// Like `2026-10-18`, or `2026-10-18T09:30:00` if it isn't midnight
+pub fn format_date(date: f64) -> String {
+    if !date.is_finite() {
+        return format!("{} seconds", date);
+    }
+    // Milliseconds, so there's no rounding left over when it's split up
+    let ms = (date * 1000.0).round();
+    let days = (ms / (DAY * 1000.0)).floor();
+    let (year, month, day) = civil_from_days(days as i64);
+    let s = format!("{:04}-{:02}-{:02}", year, month, day);
+
+    let ms = (ms - days * DAY * 1000.0) as u64;
+    if ms == 0 {
+        return s;
+    }
+    let (hours, minutes, seconds) = (ms / 3_600_000, ms / 60_000 % 60, ms / 1000 % 60);
+    let s = format!("{}T{:02}:{:02}:{:02}", s, hours, minutes, seconds);
+    if ms.is_multiple_of(1000) {
+        return s;
+    }
+    return format!("{}.{:03}", s, ms % 1000);
+}
+
+/// Like `3d 4h 30min`, with only the parts that aren't 0
+pub fn format_duration(duration: f64) -> String {
+    if !duration.is_finite() {
+        return format!("{}s", duration);
+    }
+    let sign = if duration < 0.0 { "-" } else { "" };
+    let seconds = (duration.abs() * 1000.0).round() / 1000.0;
+
+    let mut parts = vec![];
+    let days = (seconds / DAY).floor();
+    let hours = (seconds % DAY / HOUR).floor();
+    let minutes = (seconds % HOUR / MINUTE).floor();
+    let rest = ((seconds % MINUTE) * 1000.0).round() / 1000.0;
+    for (amount, unit) in [(days, "d"), (hours, "h"), (minutes, "min"), (rest, "s")] {
+        if amount != 0.0 {
+            parts.push(format!("{}{}", amount, unit));
+        }
+    }
+    if parts.is_empty() {
+        return "0s".to_string();
+    }
+    return format!("{}{}", sign, parts.join(" "));
+}
+
+fn cant(verb: &str, a: &Value, b: &Value) -> EvalError {
+    return EvalError::WrongType(format!(
+        "Can't {} a {} and a {}",
+        verb,
+        a.type_name(),
+        b.type_name()
+    ));
+}
+
+/// `a + b` for two items, where a duration can be added to a date or another duration
+pub fn add(a: Value, b: Value) -> Result<Value, EvalError> {
+    return match (&a, &b) {
+        (Value::Date(d), Value::Duration(t)) | (Value::Duration(t), Value::Date(d)) => {
+            Ok(Value::Date(d + t))
+        }
+        (Value::Duration(a), Value::Duration(b)) => Ok(Value::Duration(a + b)),
+        (Value::Date(_), _) | (_, Value::Date(_)) => Err(cant("add", &a, &b)),
+        _ => Ok(Value::Number(a.as_number()? + b.as_number()?)),
+    };
+}
+
+/// `a - b` for two items, where two dates give the duration between them
+pub fn subtract(a: Value, b: Value) -> Result<Value, EvalError> {
+    return match (&a, &b) {
+        (Value::Date(a), Value::Date(b)) => Ok(Value::Duration(a - b)),
+        (Value::Date(d), Value::Duration(t)) => Ok(Value::Date(d - t)),
+        (Value::Duration(a), Value::Duration(b)) => Ok(Value::Duration(a - b)),
+        // 0 - x is how the parser writes -x
+        (Value::Number(zero), Value::Duration(t)) if *zero == 0.0 => Ok(Value::Duration(-t)),
+        (Value::Date(_) | Value::Duration(_), _) | (_, Value::Date(_) | Value::Duration(_)) => {
+            Err(cant("subtract", &a, &b))
+        }
+        _ => Ok(Value::Number(a.as_number()? - b.as_number()?)),
+    };
+}
+
+/// `a * b` for two items, where a duration can be multiplied by a number
+pub fn multiply(a: Value, b: Value) -> Result<Value, EvalError> {
+    return match (&a, &b) {
+        (Value::Duration(t), n @ (Value::Number(_) | Value::Bool(_)))
+        | (n @ (Value::Number(_) | Value::Bool(_)), Value::Duration(t)) => {
+            Ok(Value::Duration(t * n.as_number()?))
+        }
+        _ => Ok(Value::Number(a.as_number()? * b.as_number()?)),
+    };
+}
+
+/// `a / b` for two items, where a duration divided by another is how many of it fit,
+/// so `(2026-12-25 - 2026-10-18) / 1d` is the number of days between them
+pub fn divide(a: Value, b: Value) -> Result<Value, EvalError> {
+    let divisor = match &b {
+        Value::Duration(t) => *t,
+        b => b.as_number()?,
+    };
+    if divisor == 0.0 {
+        return Err(EvalError::DivisionByZero);
+    }
+    return match (&a, &b) {
+        (Value::Duration(t), Value::Duration(_)) => Ok(Value::Number(t / divisor)),
+        (Value::Duration(t), _) => Ok(Value::Duration(t / divisor)),
+        (_, Value::Duration(_)) => Err(cant("divide", &a, &b)),
+        _ => Ok(Value::Number(a.as_number()? / divisor)),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{environment::Environment, error::parse_str};
+
+    /// Evaluates with the clock at 2026-10-18T09:30 UTC
+    fn eval(input: &str) -> Result<String, EvalError> {
+        let mut env = Environment::new();
+        env.set_clock(date(2026, 10, 18, 9.5 * HOUR).unwrap());
+        return Ok(parse_str(input).unwrap().eval_with(&env)?.to_string());
+    }
+
+    #[test]
+    fn pinned_clock() {
+        assert_eq!(eval("now").unwrap(), "2026-10-18T09:30:00");
+        assert_eq!(eval("today").unwrap(), "2026-10-18");
+        assert_eq!(eval("now - today").unwrap(), "9h 30min");
+        assert_eq!(eval("today - now").unwrap(), "-9h 30min");
+    }
+
+    #[test]
+    fn date_arithmetic() {
+        assert_eq!(eval("today + 3d").unwrap(), "2026-10-21");
+        assert_eq!(eval("now + 36h").unwrap(), "2026-10-19T21:30:00");
+        assert_eq!(eval("2026-12-25 - today").unwrap(), "68d");
+        assert_eq!(eval("(2026-12-25 - today) / 1d").unwrap(), "68");
+        assert_eq!(eval("2024-02-28 + 1d").unwrap(), "2024-02-29");
+        assert!(matches!(
+            eval("today + today"),
+            Err(EvalError::WrongType(_))
+        ));
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(eval("90min").unwrap(), "1h 30min");
+        assert_eq!(eval("2h * 3").unwrap(), "6h");
+        assert_eq!(eval("1w / 2d").unwrap(), "3.5");
+    }
+
+    #[test]
+    fn calendar() {
+        assert_eq!(date(2026, 2, 30, 0.0), None);
+        assert_eq!(date(1970, 1, 1, 0.0), Some(0.0));
+        assert_eq!(format_date(date(2000, 2, 29, 0.0).unwrap()), "2000-02-29");
+        assert_eq!(format_date(date(1969, 12, 31, 0.0).unwrap()), "1969-12-31");
+    }
+}
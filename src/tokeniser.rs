@@ -1,36 +1,115 @@
-use std::ops::{Add, Div, Mul, Sub};
+use std::ops::RangeInclusive;
 
-use crate::{math::{factorial, nCr, nPr}, parser::TreeNode};
+use crate::{
+    builtins, distributions,
+    environment::Environment,
+    finance,
+    math::{beta, factorial, gamma, lgamma, nCr, nPr, sign},
+    matrix, number_theory,
+    parser::TreeNode,
+    time,
+    value::{EvalError, Value},
+};
 
 #[derive(Debug, Clone)]
 pub enum Token {
     LParen,
     RParen,
+    LBracket,
+    RBracket,
     Operator {
-        fun: fn(f64, f64) -> f64,
+        fun: fn(Value, Value) -> Result<Value, EvalError>,
         priority: i32,
         name: String,
     }, // 5 + 4   or   6 nCr 2   etc.
     Function {
-        fun: fn(f64) -> f64,
+        fun: fn(Value) -> Result<Value, EvalError>,
         priority: i32,
         after: bool,
         name: String,
     }, // sqrt 9 (before)    or    5! (after)
-    Constant(f64),
-    ParsedTree(Box<TreeNode>),
+    Call {
+        fun: fn(&[TreeNode], &Environment) -> Result<Value, EvalError>,
+        arity: RangeInclusive<usize>,
+        name: String,
+    }, // piecewise(x < 0, -1, 1)
+    Constant(Value),
+    Variable(String),
+    Keyword(String), // if, then, else, ? and :
+    Comma,
+}
+
+impl Token {
+    /// What sort of token it is (ex: `operator` or `lparen`)
+    pub fn kind(&self) -> &'static str {
+        return match self {
+            Token::LParen => "lparen",
+            Token::RParen => "rparen",
+            Token::LBracket => "lbracket",
+            Token::RBracket => "rbracket",
+            Token::Operator { .. } => "operator",
+            Token::Function { .. } => "function",
+            Token::Call { .. } => "call",
+            Token::Constant(_) => "constant",
+            Token::Variable(_) => "variable",
+            Token::Keyword(_) => "keyword",
+            Token::Comma => "comma",
+        };
+    }
 }
 
+/// A range of character (not byte) positions in the input
+pub type Span = std::ops::Range<usize>;
+
 #[derive(Debug)]
 pub enum TokenError {
-    InvalidToken(char),
-    InvalidName(String),
-    InvalidNumber(String),
+    InvalidToken(char, Span),
+    InvalidName(String, Span),
+    InvalidNumber(String, Span),
+    /// A value right after a percentage (ex: `7 % 3`), which would otherwise be the remainder
+    ValueAfterPercent(Span),
 }
 
-const LETTERS: &'static str = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWCYZ";
-const NUMS: &'static str = "1234567890.";
-const OPS: &'static str = "+-*/%^!";
+impl TokenError {
+    /// A name for the kind of error that won't change (ex: `invalid_token`)
+    pub fn kind(&self) -> &'static str {
+        return match self {
+            TokenError::InvalidToken(..) => "invalid_token",
+            TokenError::InvalidName(..) => "invalid_name",
+            TokenError::InvalidNumber(..) => "invalid_number",
+            TokenError::ValueAfterPercent(..) => "value_after_percent",
+        };
+    }
+
+    pub fn span(&self) -> Span {
+        return match self {
+            TokenError::InvalidToken(_, span)
+            | TokenError::InvalidName(_, span)
+            | TokenError::InvalidNumber(_, span)
+            | TokenError::ValueAfterPercent(span) => span.clone(),
+        };
+    }
+}
+
+impl std::fmt::Display for TokenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return match self {
+            TokenError::InvalidToken(c, _) => write!(f, "Unknown character '{}'", c),
+            TokenError::InvalidName(name, _) => write!(f, "Unknown name '{}'", name),
+            TokenError::InvalidNumber(number, _) => write!(f, "Invalid number '{}'", number),
+            TokenError::ValueAfterPercent(_) => write!(
+                f,
+                "'%' is a percentage, so a value can't come right after it (use 'mod' for the remainder)"
+            ),
+        };
+    }
+}
+
+const NUMS: &str = "1234567890.";
+const SEPARATORS: &str = "_'";
+const OPS: &str = "+-*/%^!<>=×·÷−√∛≤≥≠";
+const PUNCT: &str = ",?:[]";
+const SUPERSCRIPTS: &str = "⁰¹²³⁴⁵⁶⁷⁸⁹";
 
 #[derive(PartialEq)]
 enum TokenType {
@@ -39,11 +118,13 @@ enum TokenType {
     LParen,
     RParen,
     Op,
+    Punct,
+    Superscript,
     None,
 }
 
-fn get_token_type(c: char) -> Result<TokenType, TokenError> {
-    if LETTERS.contains(c) {
+fn get_token_type(c: char, at: usize) -> Result<TokenType, TokenError> {
+    if c.is_alphabetic() {
         return Ok(TokenType::Letter);
     }
     if NUMS.contains(c) {
@@ -52,278 +133,1185 @@ fn get_token_type(c: char) -> Result<TokenType, TokenError> {
     if OPS.contains(c) {
         return Ok(TokenType::Op);
     }
+    if PUNCT.contains(c) {
+        return Ok(TokenType::Punct);
+    }
+    if SUPERSCRIPTS.contains(c) || c == '⁻' {
+        return Ok(TokenType::Superscript);
+    }
+    if c.is_whitespace() {
+        return Ok(TokenType::None);
+    }
     return match c {
         '(' => Ok(TokenType::LParen),
         ')' => Ok(TokenType::RParen),
-        ' ' | '\n' => Ok(TokenType::None),
-        _ => Err(TokenError::InvalidToken(c)),
+        _ => Err(TokenError::InvalidToken(c, at..at + 1)),
+    };
+}
+
+/// Defines `get_thing_str` from a table of names and the tokens they're for, and `NAMES`
+/// from the same table, so there's no name one knows and the other doesn't
+macro_rules! things {
+    ($s:ident => { $($($name:literal)|+ => $token:expr,)* }) => {
+        fn get_thing_str($s: &str) -> Result<Token, TokenError> {
+            return match $s {
+                $($($name)|+ => $token,)*
+                _ => Err(TokenError::InvalidName($s.to_string(), 0..$s.chars().count())),
+            };
+        }
+
+        /// Every name `get_thing_str` knows (ex: for completions)
+        pub const NAMES: &[&str] = &[$($($name),+),*];
     };
 }
 
-fn get_thing_str(s: &str) -> Result<Token, TokenError> {
-    match s {
+things! {
+    s => {
         // Functions
         "sin" => Ok(Token::Function {
-            fun: f64::sin,
+            fun: |x| x.map(f64::sin),
             priority: 3,
             after: false,
             name: "sin".to_string(),
         }),
         "cos" => Ok(Token::Function {
-            fun: f64::cos,
+            fun: |x| x.map(f64::cos),
             priority: 3,
             after: false,
             name: "cos".to_string(),
         }),
         "tan" => Ok(Token::Function {
-            fun: f64::tan,
+            fun: |x| x.map(f64::tan),
             priority: 3,
             after: false,
             name: "tan".to_string(),
         }),
         "asin" => Ok(Token::Function {
-            fun: f64::asin,
+            fun: |x| x.map(f64::asin),
             priority: 3,
             after: false,
             name: "asin".to_string(),
         }),
         "acos" => Ok(Token::Function {
-            fun: f64::acos,
+            fun: |x| x.map(f64::acos),
             priority: 3,
             after: false,
             name: "acos".to_string(),
         }),
         "atan" => Ok(Token::Function {
-            fun: f64::atan,
+            fun: |x| x.map(f64::atan),
             priority: 3,
             after: false,
             name: "atan".to_string(),
         }),
         "sinh" => Ok(Token::Function {
-            fun: f64::sinh,
+            fun: |x| x.map(f64::sinh),
             priority: 3,
             after: false,
             name: "sinh".to_string(),
         }),
         "cosh" => Ok(Token::Function {
-            fun: f64::cosh,
+            fun: |x| x.map(f64::cosh),
             priority: 3,
             after: false,
             name: "cosh".to_string(),
         }),
         "tanh" => Ok(Token::Function {
-            fun: f64::tanh,
+            fun: |x| x.map(f64::tanh),
             priority: 3,
             after: false,
             name: "tanh".to_string(),
         }),
         "asinh" => Ok(Token::Function {
-            fun: f64::asinh,
+            fun: |x| x.map(f64::asinh),
             priority: 3,
             after: false,
             name: "asinh".to_string(),
         }),
         "acosh" => Ok(Token::Function {
-            fun: f64::acosh,
+            fun: |x| x.map(f64::acosh),
             priority: 3,
             after: false,
             name: "acosh".to_string(),
         }),
         "atanh" => Ok(Token::Function {
-            fun: f64::atanh,
+            fun: |x| x.map(f64::atanh),
             priority: 3,
             after: false,
             name: "atanh".to_string(),
         }),
         "sqrt" => Ok(Token::Function {
-            fun: f64::sqrt,
+            fun: |x| x.map(f64::sqrt),
             priority: 3,
             after: false,
             name: "sqrt".to_string(),
         }),
         "cbrt" => Ok(Token::Function {
-            fun: f64::cbrt,
+            fun: |x| x.map(f64::cbrt),
             priority: 3,
             after: false,
             name: "cbrt".to_string(),
         }),
         "exp" => Ok(Token::Function {
-            fun: f64::exp,
+            fun: |x| x.map(f64::exp),
             priority: 3,
             after: false,
             name: "exp".to_string(),
         }),
         "ln" => Ok(Token::Function {
-            fun: f64::ln,
+            fun: |x| x.map(f64::ln),
             priority: 3,
             after: false,
             name: "ln".to_string(),
         }),
         "log" => Ok(Token::Function {
-            fun: f64::log10,
+            fun: |x| x.map(f64::log10),
             priority: 3,
             after: false,
             name: "log".to_string(),
         }),
         "abs" => Ok(Token::Function {
-            fun: f64::abs,
+            fun: |x| x.map(f64::abs),
             priority: 3,
             after: false,
             name: "abs".to_string(),
         }),
         "floor" => Ok(Token::Function {
-            fun: f64::floor,
+            fun: |x| x.map(f64::floor),
             priority: 3,
             after: false,
             name: "floor".to_string(),
         }),
         "ceil" => Ok(Token::Function {
-            fun: f64::ceil,
+            fun: |x| x.map(f64::ceil),
             priority: 3,
             after: false,
             name: "ceil".to_string(),
         }),
-        "round" => Ok(Token::Function {
-            fun: f64::round,
+        "trunc" => Ok(Token::Function {
+            fun: |x| x.map(f64::trunc),
             priority: 3,
             after: false,
+            name: "trunc".to_string(),
+        }),
+        "frac" => Ok(Token::Function {
+            fun: |x| x.map(f64::fract),
+            priority: 3,
+            after: false,
+            name: "frac".to_string(),
+        }),
+        "sign" => Ok(Token::Function {
+            fun: |x| x.map(sign),
+            priority: 3,
+            after: false,
+            name: "sign".to_string(),
+        }),
+        // Rounding, which can take how many decimals and a mode (ex: `round(x, 2, half_even)`)
+        "round" => Ok(Token::Call {
+            fun: builtins::round,
+            arity: 1..=3,
             name: "round".to_string(),
         }),
+        "round_sig" => Ok(Token::Call {
+            fun: builtins::round_sig,
+            arity: 2..=3,
+            name: "round_sig".to_string(),
+        }),
+        "roundto" => Ok(Token::Call {
+            fun: builtins::roundto,
+            arity: 2..=3,
+            name: "roundto".to_string(),
+        }),
+        "gamma" => Ok(Token::Function {
+            fun: |x| x.try_map(gamma),
+            priority: 3,
+            after: false,
+            name: "gamma".to_string(),
+        }),
+        "lgamma" => Ok(Token::Function {
+            fun: |x| x.try_map(lgamma),
+            priority: 3,
+            after: false,
+            name: "lgamma".to_string(),
+        }),
         // Operators
         "nCr" => Ok(Token::Operator {
-            fun: nCr,
+            fun: |a, b| Value::try_arith(a, b, nCr),
             priority: 5, // on my calculator, nCr and nPr are higher than multiplication and division
             name: "nCr".to_string(),
         }),
         "nPr" => Ok(Token::Operator {
-            fun: nPr,
+            fun: |a, b| Value::try_arith(a, b, nPr),
             priority: 5,
             name: "nPr".to_string(),
         }),
+        "<" => Ok(Token::Operator {
+            fun: |a, b| Value::compare(a, b, f64::lt),
+            priority: 0, // comparisons go after all the arithmetic
+            name: "<".to_string(),
+        }),
+        "<=" => Ok(Token::Operator {
+            fun: |a, b| Value::compare(a, b, f64::le),
+            priority: 0,
+            name: "<=".to_string(),
+        }),
+        ">" => Ok(Token::Operator {
+            fun: |a, b| Value::compare(a, b, f64::gt),
+            priority: 0,
+            name: ">".to_string(),
+        }),
+        ">=" => Ok(Token::Operator {
+            fun: |a, b| Value::compare(a, b, f64::ge),
+            priority: 0,
+            name: ">=".to_string(),
+        }),
+        "==" => Ok(Token::Operator {
+            fun: |a, b| Ok(Value::Bool(a.equals(&b)?)),
+            priority: 0,
+            name: "==".to_string(),
+        }),
+        "mod" => Ok(Token::Operator {
+            fun: Value::remainder,
+            priority: 2,
+            name: "mod".to_string(),
+        }),
+        // `50% of 80`
+        "of" => Ok(Token::Operator {
+            fun: matrix::multiply,
+            priority: 2,
+            name: "of".to_string(),
+        }),
+        "//" => Ok(Token::Operator {
+            fun: |a, b| Value::try_arith(a, b, number_theory::floor_divide),
+            priority: 2,
+            name: "//".to_string(),
+        }),
+        "!=" => Ok(Token::Operator {
+            fun: |a, b| Ok(Value::Bool(!a.equals(&b)?)),
+            priority: 0,
+            name: "!=".to_string(),
+        }),
+        "not" => Ok(Token::Function {
+            fun: |x| Ok(Value::Bool(!x.as_bool()?)),
+            priority: -1, // not a == b is not (a == b)
+            after: false,
+            name: "not".to_string(),
+        }),
+        "and" => Ok(Token::Operator {
+            fun: |a, b| Value::logic(a, b, |a, b| a && b),
+            priority: -2,
+            name: "and".to_string(),
+        }),
+        "or" => Ok(Token::Operator {
+            fun: |a, b| Value::logic(a, b, |a, b| a || b),
+            priority: -3,
+            name: "or".to_string(),
+        }),
+        "piecewise" => Ok(Token::Call {
+            fun: builtins::piecewise,
+            arity: 1..=usize::MAX,
+            name: "piecewise".to_string(),
+        }),
+        "diff" => Ok(Token::Call {
+            fun: builtins::diff,
+            arity: 2..=2,
+            name: "diff".to_string(),
+        }),
+        "solve" => Ok(Token::Call {
+            fun: builtins::solve,
+            arity: 2..=4,
+            name: "solve".to_string(),
+        }),
+        "root" => Ok(Token::Call {
+            fun: builtins::root,
+            arity: 3..=3,
+            name: "root".to_string(),
+        }),
+        "integrate" => Ok(Token::Call {
+            fun: builtins::integrate,
+            arity: 4..=4,
+            name: "integrate".to_string(),
+        }),
+        "sum" => Ok(Token::Call {
+            fun: builtins::sum,
+            arity: 1..=usize::MAX,
+            name: "sum".to_string(),
+        }),
+        "prod" => Ok(Token::Call {
+            fun: builtins::prod,
+            arity: 4..=4,
+            name: "prod".to_string(),
+        }),
+        "list" => Ok(Token::Call {
+            fun: builtins::list,
+            arity: 0..=usize::MAX,
+            name: "list".to_string(),
+        }),
+        "count" => Ok(Token::Call {
+            fun: builtins::count,
+            arity: 1..=usize::MAX,
+            name: "count".to_string(),
+        }),
+        "mean" => Ok(Token::Call {
+            fun: builtins::mean,
+            arity: 1..=usize::MAX,
+            name: "mean".to_string(),
+        }),
+        "median" => Ok(Token::Call {
+            fun: builtins::median,
+            arity: 1..=usize::MAX,
+            name: "median".to_string(),
+        }),
+        "mode" => Ok(Token::Call {
+            fun: builtins::mode,
+            arity: 1..=usize::MAX,
+            name: "mode".to_string(),
+        }),
+        "var" => Ok(Token::Call {
+            fun: builtins::var,
+            arity: 1..=usize::MAX,
+            name: "var".to_string(),
+        }),
+        "pvar" => Ok(Token::Call {
+            fun: builtins::pvar,
+            arity: 1..=usize::MAX,
+            name: "pvar".to_string(),
+        }),
+        "stdev" => Ok(Token::Call {
+            fun: builtins::stdev,
+            arity: 1..=usize::MAX,
+            name: "stdev".to_string(),
+        }),
+        "pstdev" => Ok(Token::Call {
+            fun: builtins::pstdev,
+            arity: 1..=usize::MAX,
+            name: "pstdev".to_string(),
+        }),
+        "min" => Ok(Token::Call {
+            fun: builtins::min,
+            arity: 1..=usize::MAX,
+            name: "min".to_string(),
+        }),
+        "max" => Ok(Token::Call {
+            fun: builtins::max,
+            arity: 1..=usize::MAX,
+            name: "max".to_string(),
+        }),
+        "percentile" => Ok(Token::Call {
+            fun: builtins::percentile,
+            arity: 2..=2,
+            name: "percentile".to_string(),
+        }),
+        "beta" => Ok(Token::Call {
+            fun: |args, env| builtins::numeric(args, env, |x| beta(x[0], x[1])),
+            arity: 2..=2,
+            name: "beta".to_string(),
+        }),
+        // Dates, which can be written without parens
+        "now" => Ok(Token::Call {
+            fun: builtins::now,
+            arity: 0..=0,
+            name: "now".to_string(),
+        }),
+        "today" => Ok(Token::Call {
+            fun: builtins::today,
+            arity: 0..=0,
+            name: "today".to_string(),
+        }),
+        // Polynomials
+        "coeffs" => Ok(Token::Call {
+            fun: builtins::coeffs,
+            arity: 1..=2,
+            name: "coeffs".to_string(),
+        }),
+        "expand" => Ok(Token::Call {
+            fun: builtins::expand,
+            arity: 1..=2,
+            name: "expand".to_string(),
+        }),
+        "poly_div" => Ok(Token::Call {
+            fun: builtins::poly_div,
+            arity: 2..=3,
+            name: "poly_div".to_string(),
+        }),
+        "poly_roots" => Ok(Token::Call {
+            fun: builtins::poly_roots,
+            arity: 1..=2,
+            name: "poly_roots".to_string(),
+        }),
+        // Vectors and matrices
+        "det" => Ok(Token::Call {
+            fun: |args, env| builtins::values(args, env, matrix::det),
+            arity: 1..=1,
+            name: "det".to_string(),
+        }),
+        "inv" => Ok(Token::Call {
+            fun: |args, env| builtins::values(args, env, matrix::inv),
+            arity: 1..=1,
+            name: "inv".to_string(),
+        }),
+        "transpose" => Ok(Token::Call {
+            fun: |args, env| builtins::values(args, env, matrix::transpose),
+            arity: 1..=1,
+            name: "transpose".to_string(),
+        }),
+        "trace" => Ok(Token::Call {
+            fun: |args, env| builtins::values(args, env, matrix::trace),
+            arity: 1..=1,
+            name: "trace".to_string(),
+        }),
+        "rank" => Ok(Token::Call {
+            fun: |args, env| builtins::values(args, env, matrix::rank),
+            arity: 1..=1,
+            name: "rank".to_string(),
+        }),
+        "dot" => Ok(Token::Call {
+            fun: |args, env| builtins::values(args, env, matrix::dot),
+            arity: 2..=2,
+            name: "dot".to_string(),
+        }),
+        "cross" => Ok(Token::Call {
+            fun: |args, env| builtins::values(args, env, matrix::cross),
+            arity: 2..=2,
+            name: "cross".to_string(),
+        }),
+        "norm" => Ok(Token::Call {
+            fun: |args, env| builtins::values(args, env, matrix::norm),
+            arity: 1..=1,
+            name: "norm".to_string(),
+        }),
+        // Finance, with the same arguments as spreadsheets
+        "pv" => Ok(Token::Call {
+            fun: |args, env| builtins::numeric(args, env, finance::pv),
+            arity: 3..=5,
+            name: "pv".to_string(),
+        }),
+        "fv" => Ok(Token::Call {
+            fun: |args, env| builtins::numeric(args, env, finance::fv),
+            arity: 3..=5,
+            name: "fv".to_string(),
+        }),
+        "pmt" => Ok(Token::Call {
+            fun: |args, env| builtins::numeric(args, env, finance::pmt),
+            arity: 3..=5,
+            name: "pmt".to_string(),
+        }),
+        "nper" => Ok(Token::Call {
+            fun: |args, env| builtins::numeric(args, env, finance::nper),
+            arity: 3..=5,
+            name: "nper".to_string(),
+        }),
+        "rate" => Ok(Token::Call {
+            fun: |args, env| builtins::numeric(args, env, finance::rate),
+            arity: 3..=6,
+            name: "rate".to_string(),
+        }),
+        "npv" => Ok(Token::Call {
+            fun: builtins::npv,
+            arity: 2..=usize::MAX,
+            name: "npv".to_string(),
+        }),
+        "irr" => Ok(Token::Call {
+            fun: builtins::irr,
+            arity: 1..=usize::MAX,
+            name: "irr".to_string(),
+        }),
+        "compound" => Ok(Token::Call {
+            fun: |args, env| builtins::numeric(args, env, finance::compound),
+            arity: 3..=4,
+            name: "compound".to_string(),
+        }),
+        "amortize" => Ok(Token::Call {
+            fun: |args, env| builtins::numeric(args, env, finance::amortize),
+            arity: 3..=3,
+            name: "amortize".to_string(),
+        }),
+        // Number theory
+        "gcd" => Ok(Token::Call {
+            fun: |args, env| builtins::numeric(args, env, number_theory::gcd),
+            arity: 2..=usize::MAX,
+            name: "gcd".to_string(),
+        }),
+        "lcm" => Ok(Token::Call {
+            fun: |args, env| builtins::numeric(args, env, number_theory::lcm),
+            arity: 2..=usize::MAX,
+            name: "lcm".to_string(),
+        }),
+        "mod_pow" => Ok(Token::Call {
+            fun: |args, env| builtins::numeric(args, env, number_theory::mod_pow),
+            arity: 3..=3,
+            name: "mod_pow".to_string(),
+        }),
+        "mod_inv" => Ok(Token::Call {
+            fun: |args, env| builtins::numeric(args, env, number_theory::mod_inv),
+            arity: 2..=2,
+            name: "mod_inv".to_string(),
+        }),
+        "is_prime" => Ok(Token::Call {
+            fun: |args, env| builtins::numeric(args, env, number_theory::is_prime),
+            arity: 1..=1,
+            name: "is_prime".to_string(),
+        }),
+        "next_prime" => Ok(Token::Call {
+            fun: |args, env| builtins::numeric(args, env, number_theory::next_prime),
+            arity: 1..=1,
+            name: "next_prime".to_string(),
+        }),
+        "factor" => Ok(Token::Call {
+            fun: |args, env| builtins::numeric(args, env, number_theory::factor),
+            arity: 1..=1,
+            name: "factor".to_string(),
+        }),
+        "totient" => Ok(Token::Call {
+            fun: |args, env| builtins::numeric(args, env, number_theory::totient),
+            arity: 1..=1,
+            name: "totient".to_string(),
+        }),
+        // Distributions
+        "normpdf" => Ok(Token::Call {
+            fun: |args, env| builtins::numeric(args, env, distributions::normpdf),
+            arity: 1..=3,
+            name: "normpdf".to_string(),
+        }),
+        "normcdf" => Ok(Token::Call {
+            fun: |args, env| builtins::numeric(args, env, distributions::normcdf),
+            arity: 1..=3,
+            name: "normcdf".to_string(),
+        }),
+        "invnorm" => Ok(Token::Call {
+            fun: |args, env| builtins::numeric(args, env, distributions::invnorm),
+            arity: 1..=3,
+            name: "invnorm".to_string(),
+        }),
+        "binompdf" => Ok(Token::Call {
+            fun: |args, env| builtins::numeric(args, env, distributions::binompdf),
+            arity: 3..=3,
+            name: "binompdf".to_string(),
+        }),
+        "binomcdf" => Ok(Token::Call {
+            fun: |args, env| builtins::numeric(args, env, distributions::binomcdf),
+            arity: 3..=3,
+            name: "binomcdf".to_string(),
+        }),
+        "invbinom" => Ok(Token::Call {
+            fun: |args, env| builtins::numeric(args, env, distributions::invbinom),
+            arity: 3..=3,
+            name: "invbinom".to_string(),
+        }),
+        "poissonpdf" => Ok(Token::Call {
+            fun: |args, env| builtins::numeric(args, env, distributions::poissonpdf),
+            arity: 2..=2,
+            name: "poissonpdf".to_string(),
+        }),
+        "poissoncdf" => Ok(Token::Call {
+            fun: |args, env| builtins::numeric(args, env, distributions::poissoncdf),
+            arity: 2..=2,
+            name: "poissoncdf".to_string(),
+        }),
+        "invpoisson" => Ok(Token::Call {
+            fun: |args, env| builtins::numeric(args, env, distributions::invpoisson),
+            arity: 2..=2,
+            name: "invpoisson".to_string(),
+        }),
+        "exppdf" => Ok(Token::Call {
+            fun: |args, env| builtins::numeric(args, env, distributions::exppdf),
+            arity: 2..=2,
+            name: "exppdf".to_string(),
+        }),
+        "expcdf" => Ok(Token::Call {
+            fun: |args, env| builtins::numeric(args, env, distributions::expcdf),
+            arity: 2..=2,
+            name: "expcdf".to_string(),
+        }),
+        "invexp" => Ok(Token::Call {
+            fun: |args, env| builtins::numeric(args, env, distributions::invexp),
+            arity: 2..=2,
+            name: "invexp".to_string(),
+        }),
+        "unifpdf" => Ok(Token::Call {
+            fun: |args, env| builtins::numeric(args, env, distributions::unifpdf),
+            arity: 3..=3,
+            name: "unifpdf".to_string(),
+        }),
+        "unifcdf" => Ok(Token::Call {
+            fun: |args, env| builtins::numeric(args, env, distributions::unifcdf),
+            arity: 3..=3,
+            name: "unifcdf".to_string(),
+        }),
+        "invunif" => Ok(Token::Call {
+            fun: |args, env| builtins::numeric(args, env, distributions::invunif),
+            arity: 3..=3,
+            name: "invunif".to_string(),
+        }),
+        "tpdf" => Ok(Token::Call {
+            fun: |args, env| builtins::numeric(args, env, distributions::tpdf),
+            arity: 2..=2,
+            name: "tpdf".to_string(),
+        }),
+        "tcdf" => Ok(Token::Call {
+            fun: |args, env| builtins::numeric(args, env, distributions::tcdf),
+            arity: 2..=2,
+            name: "tcdf".to_string(),
+        }),
+        "invt" => Ok(Token::Call {
+            fun: |args, env| builtins::numeric(args, env, distributions::invt),
+            arity: 2..=2,
+            name: "invt".to_string(),
+        }),
+        "chi2pdf" => Ok(Token::Call {
+            fun: |args, env| builtins::numeric(args, env, distributions::chi2pdf),
+            arity: 2..=2,
+            name: "chi2pdf".to_string(),
+        }),
+        "chi2cdf" => Ok(Token::Call {
+            fun: |args, env| builtins::numeric(args, env, distributions::chi2cdf),
+            arity: 2..=2,
+            name: "chi2cdf".to_string(),
+        }),
+        "invchi2" => Ok(Token::Call {
+            fun: |args, env| builtins::numeric(args, env, distributions::invchi2),
+            arity: 2..=2,
+            name: "invchi2".to_string(),
+        }),
+        // Keywords
+        "if" | "then" | "else" => Ok(Token::Keyword(s.to_string())),
         // Constants
-        "pi" => Ok(Token::Constant(std::f64::consts::PI)),
-        "e" => Ok(Token::Constant(std::f64::consts::E)),
-        _ => Err(TokenError::InvalidName(s.to_string())),
+        "pi" | "π" => Ok(Token::Constant(Value::Number(std::f64::consts::PI))),
+        "e" => Ok(Token::Constant(Value::Number(std::f64::consts::E))),
+        "true" => Ok(Token::Constant(Value::Bool(true))),
+        "false" => Ok(Token::Constant(Value::Bool(false))),
+    }
+}
+
+/// Gets an operator, function or constant by its name (ex: `"sin"` or `"*"`)
+pub fn get_thing(name: &str) -> Result<Token, TokenError> {
+    let mut chars = name.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        if let Ok(t) = get_thing_char(c) {
+            return Ok(t);
+        }
     }
+    return get_thing_str(name);
 }
 
 fn get_thing_char(c: char) -> Result<Token, TokenError> {
     match c {
+        // Aliases for when people paste from documents
+        '×' | '·' => get_thing_char('*'),
+        '÷' => get_thing_char('/'),
+        '−' => get_thing_char('-'),
+        '√' => get_thing_str("sqrt"),
+        '∛' => get_thing_str("cbrt"),
+        '≤' => get_thing_str("<="),
+        '≥' => get_thing_str(">="),
+        '≠' => get_thing_str("!="),
+        '<' | '>' => get_thing_str(&c.to_string()),
+        // An equation like `x^2 = 2`, which is true when both sides are equal
+        '=' => Ok(Token::Operator {
+            fun: |a, b| Ok(Value::Bool(a.equals(&b)?)),
+            priority: 0,
+            name: "=".to_string(),
+        }),
         '+' => Ok(Token::Operator {
-            fun: Add::add,
+            fun: matrix::add,
             priority: 1,
             name: "+".to_string(),
         }),
         '-' => Ok(Token::Operator {
-            fun: Sub::sub,
+            fun: matrix::subtract,
             priority: 1,
             name: "-".to_string(),
         }),
         '*' => Ok(Token::Operator {
-            fun: Mul::mul,
+            fun: matrix::multiply,
             priority: 2,
             name: "*".to_string(),
         }),
         '/' => Ok(Token::Operator {
-            fun: Div::div,
+            fun: Value::divide,
             priority: 2,
             name: "/".to_string(),
         }),
         '^' => Ok(Token::Operator {
-            fun: f64::powf,
+            fun: |a, b| Value::arith(a, b, f64::powf),
             priority: 4,
             name: "^".to_string(),
         }),
-        '%' => Ok(Token::Operator {
-            fun: f64::rem_euclid,
-            priority: 2,
-            name: "%".to_string(),
-        }),
+        '%' => Ok(percent()),
         '!' => Ok(Token::Function {
-            fun: factorial,
+            fun: |x| x.try_map(factorial),
             priority: 3,
             name: "!".to_string(),
             after: true,
         }),
-        _ => Err(TokenError::InvalidToken(c)),
+        // Punctuation
+        ',' => Ok(Token::Comma),
+        '[' => Ok(Token::LBracket),
+        ']' => Ok(Token::RBracket),
+        '?' | ':' => Ok(Token::Keyword(c.to_string())),
+        _ => Err(TokenError::InvalidToken(c, 0..1)),
+    }
+}
+
+fn push(current_type: TokenType, tokens: &mut Vec<Token>, current_str: &String) {
+    if current_type == TokenType::Letter {
+        match get_thing_str(current_str) {
+            Ok(t) => tokens.push(t),
+            // Anything we don't know about is a variable
+            Err(_) => tokens.push(Token::Variable(current_str.to_owned())),
+        }
     }
 }
 
-fn push(
-    current_type: TokenType,
-    tokens: &mut Vec<Token>,
-    current_str: &String,
-) -> Result<(), TokenError> {
-    match current_type {
-        TokenType::Num => {
-            if let Ok(f) = current_str.parse() {
-                tokens.push(Token::Constant(f));
-            } else {
-                return Err(TokenError::InvalidNumber(current_str.to_owned()));
+fn is_digit(c: Option<&char>) -> bool {
+    return matches!(c, Some(c) if c.is_ascii_digit());
+}
+
+/** read_number
+
+Reads a number starting at `at` and returns it along with the position right after it.
+
+A number looks like `1_000.5e-3`:
+- digits can be separated by `_` or `'`, but only between two digits
+- there can be at most one `.`, and not in the exponent
+- `e` or `E` starts an exponent only if it's followed by digits (with an optional sign),
+  otherwise the number ends there and the `e` is read as the constant on its own (so `2e` is
+  a number followed by `e`, which doesn't parse, since there's no implicit multiplication)
+
+Anything else that looks like it belongs to the number (ex: `1.2.3` or `1e5.2`) is
+reported as an `InvalidNumber` spanning the whole thing.
+*/
+fn read_number(chars: &[char], at: usize) -> Result<(f64, usize), TokenError> {
+    let mut i = at;
+    let mut s = String::new();
+    let mut valid = true;
+    let mut dot = false;
+
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            '0'..='9' => s.push(c),
+            c if SEPARATORS.contains(c) => {
+                if !is_digit(chars.get(i + 1)) || i == at || !chars[i - 1].is_ascii_digit() {
+                    valid = false;
+                }
             }
+            '.' => {
+                if dot {
+                    valid = false;
+                }
+                dot = true;
+                s.push(c);
+            }
+            'e' | 'E' => {
+                let sign = matches!(chars.get(i + 1), Some('+' | '-'));
+                let first = if sign { i + 2 } else { i + 1 };
+                if !is_digit(chars.get(first)) {
+                    break;
+                }
+                s.push('e');
+                if sign {
+                    s.push(chars[i + 1]);
+                }
+                i = first;
+                while i < chars.len() && chars[i].is_ascii_digit() {
+                    s.push(chars[i]);
+                    i += 1;
+                }
+                // Swallow anything that would make this a malformed number
+                while i < chars.len() && (NUMS.contains(chars[i]) || SEPARATORS.contains(chars[i]))
+                {
+                    valid = false;
+                    i += 1;
+                }
+                break;
+            }
+            _ => break,
         }
-        TokenType::Letter => {
-            tokens.push(get_thing_str(current_str)?);
+        i += 1;
+    }
+
+    let text: String = chars[at..i].iter().collect();
+    if !valid {
+        return Err(TokenError::InvalidNumber(text, at..i));
+    }
+    if let Ok(f) = s.parse() {
+        return Ok((f, i));
+    } else {
+        return Err(TokenError::InvalidNumber(text, at..i));
+    }
+}
+
+/// `x%`, which is a percentage instead of the remainder operator
+fn percent() -> Token {
+    return Token::Function {
+        fun: Value::percent,
+        priority: 3,
+        after: true,
+        name: "%".to_string(),
+    };
+}
+
+/// Whether a value comes right after the `%` right before `at` (ex: `7 % 3` or `10% (3)`).
+/// A sign is an operator after a percentage, so `10% -5` is a percentage minus 5, and words
+/// like `of` or `and` go between two values, so they aren't the start of one.
+fn value_after_percent(chars: &[char], at: usize) -> bool {
+    let mut i = at;
+    while chars.get(i).is_some_and(|c| c.is_whitespace()) {
+        i += 1;
+    }
+    let c = match chars.get(i) {
+        Some(c) => *c,
+        None => return false,
+    };
+    if c.is_alphabetic() {
+        let word: String = chars[i..]
+            .iter()
+            .take_while(|c| c.is_alphanumeric())
+            .collect();
+        return !matches!(
+            get_thing_str(&word),
+            Ok(Token::Operator { .. } | Token::Keyword(_))
+        );
+    }
+    return NUMS.contains(c) || "([√∛".contains(c);
+}
+
+/// Reads exactly `count` digits starting at `at`, if they're there
+fn read_digits(chars: &[char], at: usize, count: usize) -> Option<u32> {
+    let digits = chars.get(at..at + count)?;
+    if !digits.iter().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    return digits.iter().collect::<String>().parse().ok();
+}
+
+/** read_date
+
+Reads a date starting at `at`, if there's one there, and returns it along with the
+position right after it.
+
+A date looks like `2026-10-18`, with an optional time in UTC after a `T`
+(ex: `2026-10-18T09:30` or `2026-10-18T09:30:15.5`). Something shaped like a date that
+isn't a real one (ex: `2026-02-30`) is reported as an `InvalidNumber`.
+*/
+fn read_date(chars: &[char], at: usize) -> Result<Option<(f64, usize)>, TokenError> {
+    let (year, month, day) = match (
+        read_digits(chars, at, 4),
+        chars.get(at + 4),
+        read_digits(chars, at + 5, 2),
+        chars.get(at + 7),
+        read_digits(chars, at + 8, 2),
+    ) {
+        (Some(year), Some('-'), Some(month), Some('-'), Some(day)) => (year, month, day),
+        _ => return Ok(None),
+    };
+    let mut i = at + 10;
+
+    let mut seconds = 0.0;
+    if chars.get(i) == Some(&'T') {
+        let (hours, minutes) = match (
+            read_digits(chars, i + 1, 2),
+            chars.get(i + 3),
+            read_digits(chars, i + 4, 2),
+        ) {
+            (Some(hours), Some(':'), Some(minutes)) if hours < 24 && minutes < 60 => {
+                (hours, minutes)
+            }
+            _ => {
+                let text: String = chars[at..i + 1].iter().collect();
+                return Err(TokenError::InvalidNumber(text, at..i + 1));
+            }
+        };
+        seconds = hours as f64 * time::HOUR + minutes as f64 * time::MINUTE;
+        i += 6;
+        if chars.get(i) == Some(&':') && is_digit(chars.get(i + 1)) {
+            let (s, end) = read_number(chars, i + 1)?;
+            seconds += s;
+            i = end;
         }
-        _ => {}
     }
-    Ok(())
+
+    let text: String = chars[at..i].iter().collect();
+    if is_digit(chars.get(i)) {
+        return Err(TokenError::InvalidNumber(text, at..i));
+    }
+    return match time::date(year as i64, month, day, seconds) {
+        Some(date) => Ok(Some((date, i))),
+        None => Err(TokenError::InvalidNumber(text, at..i)),
+    };
+}
+
+/// The number of seconds in the unit right at `at` (ex: the `min` in `90min`), and the
+/// position right after it
+fn read_unit(chars: &[char], at: usize) -> Option<(f64, usize)> {
+    let mut end = at;
+    while end < chars.len() && chars[end].is_ascii_alphabetic() {
+        end += 1;
+    }
+    // A unit can't be the start of a longer name (ex: `2days_left`)
+    if chars.get(end) == Some(&'_') {
+        return None;
+    }
+    let unit: String = chars[at..end].iter().collect();
+    let (_, seconds) = time::UNITS.iter().find(|(name, _)| *name == unit)?;
+    return Some((*seconds, end));
+}
+
+/** read_duration
+
+Reads a duration starting at `at`, if there's one there, and returns it in seconds
+along with the position right after it.
+
+A duration is a number followed right away by a unit (ex: `90min`). Durations written
+one after the other are added together, so `3d 4h` is one duration.
+*/
+fn read_duration(chars: &[char], at: usize) -> Result<Option<(f64, usize)>, TokenError> {
+    let (n, end) = read_number(chars, at)?;
+    let (unit, mut end) = match read_unit(chars, end) {
+        Some(unit) => unit,
+        None => return Ok(None),
+    };
+    let mut total = n * unit;
+
+    loop {
+        let mut next = end;
+        while chars.get(next).is_some_and(|c| c.is_whitespace()) {
+            next += 1;
+        }
+        if !is_digit(chars.get(next)) {
+            break;
+        }
+        let (n, after) = read_number(chars, next)?;
+        match read_unit(chars, after) {
+            Some((unit, after)) => {
+                total += n * unit;
+                end = after;
+            }
+            None => break,
+        }
+    }
+    return Ok(Some((total, end)));
+}
+
+/// Gives the tokens that were just pushed the span they came from
+fn mark(spans: &mut Vec<Span>, tokens: &[Token], span: Span) {
+    spans.resize(tokens.len(), span);
 }
 
 pub fn tokenise(input: String) -> Result<Vec<Token>, TokenError> {
+    return Ok(tokenise_with_spans(&input)?.0);
+}
+
+/// Like `tokenise`, but also gives where each token is in the input, for error messages
+pub fn tokenise_with_spans(input: &str) -> Result<(Vec<Token>, Vec<Span>), TokenError> {
+    let chars: Vec<char> = input.chars().collect();
     let mut tokens: Vec<Token> = vec![];
+    let mut spans: Vec<Span> = vec![];
 
     let mut current_type = TokenType::None;
     let mut current_str: String = "".to_owned();
+    let mut name_start = 0;
+
+    let mut i = 0;
+    // Where the last step started, so whatever it pushed is `start..i`
+    let mut start = 0;
+    while i < chars.len() {
+        mark(&mut spans, &tokens, start..i);
+        start = i;
+        let c = chars[i];
+
+        // Names can have digits and underscores after the first letter (ex: x_1)
+        if current_type == TokenType::Letter && (c.is_ascii_digit() || c == '_') {
+            current_str.push(c);
+            i += 1;
+            continue;
+        }
+
+        let t = get_token_type(c, i)?;
+
+        if t != TokenType::Letter && current_type == TokenType::Letter {
+            push(current_type, &mut tokens, &current_str);
+            mark(&mut spans, &tokens, name_start..i);
+            current_type = TokenType::None;
+        }
 
-    for c in input.chars() {
-        let t = get_token_type(c)?;
         match t {
-            TokenType::Letter | TokenType::Num => {
-                if current_type != TokenType::None {
-                    if current_type == t {
-                        current_str.push(c);
-                    } else {
-                        push(current_type, &mut tokens, &current_str)?;
-                        current_type = t;
-                        current_str = c.to_string();
-                    }
+            TokenType::Letter => {
+                if current_type == TokenType::Letter {
+                    current_str.push(c);
                 } else {
                     current_type = t;
                     current_str = c.to_string();
+                    name_start = i;
                 }
             }
+            TokenType::Num => {
+                if let Some((date, end)) = read_date(&chars, i)? {
+                    tokens.push(Token::Constant(Value::Date(date)));
+                    i = end;
+                    continue;
+                }
+                if let Some((duration, end)) = read_duration(&chars, i)? {
+                    tokens.push(Token::Constant(Value::Duration(duration)));
+                    i = end;
+                    continue;
+                }
+                let (f, end) = read_number(&chars, i)?;
+                tokens.push(Token::Constant(Value::Number(f)));
+                i = end;
+                continue;
+            }
             TokenType::LParen => {
-                push(current_type, &mut tokens, &current_str)?;
-                current_type = TokenType::None;
                 tokens.push(Token::LParen);
             }
             TokenType::RParen => {
-                push(current_type, &mut tokens, &current_str)?;
-                current_type = TokenType::None;
                 tokens.push(Token::RParen);
             }
-            TokenType::Op => {
-                push(current_type, &mut tokens, &current_str)?;
-                current_type = TokenType::None;
+            TokenType::Punct => {
                 tokens.push(get_thing_char(c)?);
             }
-            TokenType::None => {
-                match current_type {
-                    TokenType::Num => {
-                        // don't do anything as numbers can be represented as 6 312 503 (six million, three hundred and twelve thousand, five hundred and three)
-                    }
-                    TokenType::Letter => {
+            TokenType::Op => {
+                // <=, >=, == and !=
+                if "<>=!".contains(c) && chars.get(i + 1) == Some(&'=') {
+                    tokens.push(get_thing_str(&format!("{}=", c))?);
+                    i += 2;
+                    continue;
+                }
+                // Floor division
+                if c == '/' && chars.get(i + 1) == Some(&'/') {
+                    tokens.push(get_thing_str("//")?);
+                    i += 2;
+                    continue;
+                }
+                if c == '%' && value_after_percent(&chars, i + 1) {
+                    return Err(TokenError::ValueAfterPercent(i..i + 1));
+                }
+                match get_thing_char(c) {
+                    Ok(t) => tokens.push(t),
+                    Err(_) => return Err(TokenError::InvalidToken(c, i..i + 1)),
+                }
+            }
+            TokenType::Superscript => {
+                // x² is the same as x ^ 2
+                let mut exp = String::new();
+                while i < chars.len() && get_token_type(chars[i], i)? == TokenType::Superscript {
+                    exp.push(match SUPERSCRIPTS.chars().position(|s| s == chars[i]) {
+                        Some(d) => std::char::from_digit(d as u32, 10).unwrap_or('0'),
+                        None => '-',
+                    });
+                    i += 1;
+                }
+                match exp.parse() {
+                    Ok(f) => {
+                        tokens.push(get_thing_char('^')?);
+                        tokens.push(Token::Constant(Value::Number(f)));
                     }
-                    _ => {
-                        current_type = TokenType::None;
+                    Err(_) => {
+                        let start = i - exp.chars().count();
+                        return Err(TokenError::InvalidNumber(exp, start..i));
                     }
                 }
+                continue;
+            }
+            TokenType::None => {
+                // whitespace only separates tokens
             }
         }
+        i += 1;
     }
 
-    push(current_type, &mut tokens, &current_str)?;
+    mark(&mut spans, &tokens, start..chars.len());
+    push(current_type, &mut tokens, &current_str);
+    mark(&mut spans, &tokens, name_start..chars.len());
+
+    Ok((tokens, spans))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::parse_str;
+
+    fn number(input: &str) -> f64 {
+        let tree = parse_str(input).unwrap();
+        return tree.eval().unwrap().as_number().unwrap();
+    }
+
+    fn error(input: &str) -> (&'static str, Span) {
+        let e = tokenise_with_spans(input).unwrap_err();
+        return (e.kind(), e.span());
+    }
+
+    #[test]
+    fn scientific_notation() {
+        assert_eq!(number("1.5e-3"), 0.0015);
+        assert_eq!(number("2E10"), 2e10);
+        assert_eq!(number("1e+2"), 100.0);
+        // Without digits after it, `e` is the constant
+        assert_eq!(number("e"), std::f64::consts::E);
+        assert!(parse_str("2e").is_err());
+    }
 
-    Ok(tokens)
+    #[test]
+    fn digit_separators() {
+        assert_eq!(number("1_000_000"), 1e6);
+        assert_eq!(number("1'000.5"), 1000.5);
+    }
+
+    #[test]
+    fn malformed_numbers() {
+        assert_eq!(error("1.2.3"), ("invalid_number", 0..5));
+        assert_eq!(error("2 + 1e5.2"), ("invalid_number", 4..9));
+        assert_eq!(error("1__0"), ("invalid_number", 0..4));
+        assert_eq!(error("1 $ 2"), ("invalid_token", 2..3));
+    }
+
+    #[test]
+    fn unicode_operators() {
+        assert_eq!(number("3 × 4 · 2"), 24.0);
+        assert_eq!(number("8 ÷ 2 − 1"), 3.0);
+        assert_eq!(number("√16 + ∛27"), 7.0);
+        assert_eq!(number("2³"), 8.0);
+        assert_eq!(number("2⁻¹"), 0.5);
+        assert_eq!(number("π"), std::f64::consts::PI);
+    }
+
+    #[test]
+    fn unicode_names() {
+        let (tokens, spans) = tokenise_with_spans("λé + 1").unwrap();
+        assert!(matches!(&tokens[0], Token::Variable(name) if name == "λé"));
+        // Spans are in characters, not bytes
+        assert_eq!(spans[1], 3..4);
+    }
+
+    #[test]
+    fn percentages() {
+        assert_eq!(number("200 + 10%"), 220.0);
+        assert_eq!(number("200 + 10% - 5"), 215.0);
+        // The sign is always an operator after a percentage, with or without spaces
+        assert_eq!(number("200 + 10% -5"), 215.0);
+        assert_eq!(number("200 + 10%-5"), 215.0);
+        assert_eq!(number("10% of 80"), 8.0);
+        assert_eq!(number("50%"), 0.5);
+    }
+
+    #[test]
+    fn mod_is_the_remainder() {
+        assert_eq!(number("7 mod 3"), 1.0);
+        assert_eq!(number("7 mod -3"), 1.0);
+        assert_eq!(error("7 % 3"), ("value_after_percent", 2..3));
+        assert_eq!(error("7%3"), ("value_after_percent", 1..2));
+        assert_eq!(error("10% (3)"), ("value_after_percent", 2..3));
+        assert_eq!(error("10% x"), ("value_after_percent", 2..3));
+    }
+
+    #[test]
+    fn every_name_is_a_thing() {
+        for name in NAMES {
+            assert!(get_thing(name).is_ok(), "{}", name);
+        }
+        assert!(NAMES.contains(&"sin") && NAMES.contains(&"π") && NAMES.contains(&"else"));
+        assert!(get_thing("nothing").is_err());
+    }
 }
@@ -0,0 +1,368 @@
+use std::fmt::Display;
+
+use crate::{parser::TreeNode, time};
+
+/// The result of evaluating an expression
+#[derive(Debug, Clone)]
+pub enum Value {
+    Number(f64),
+    Bool(bool),
+    List(Vec<Value>),
+    /// Seconds since 1970-01-01 in UTC (ex: `2026-10-18`)
+    Date(f64),
+    /// A length of time in seconds (ex: `3d 4h`)
+    Duration(f64),
+    /// A percentage like `10%`, which is 0.1 as a number, except that `200 + 10%` is 220
+    Percent(f64),
+    /// An expression that couldn't be evaluated any further, like `diff(x^2, x)` when `x` isn't set
+    Expression(Box<TreeNode>),
+}
+
+#[derive(Debug)]
+pub enum EvalError {
+    /// A value of the wrong type was given to an operator or function
+    WrongType(String),
+    /// The tree wasn't built by the parser and is missing something
+    MalformedTree(String),
+    DivisionByZero,
+    UnknownVariable(String),
+    /// Something other than a variable was given where a variable is needed (ex: `diff(x^2, 2)`)
+    ExpectedVariable(String),
+    /// `diff` doesn't know how to differentiate this function
+    NotDifferentiable(String),
+    /// The expression isn't a polynomial in the variable (ex: `sin(x)` or `1 / x`)
+    NotPolynomial(String),
+    /// None of the conditions of a `piecewise` were true and there was no default
+    NoMatchingPiece,
+    /// The function can't be used in a compiled expression
+    NotCompilable(String),
+    /// Columns of different lengths were given together (length of one, length expected)
+    MismatchedLengths(usize, usize),
+    /// Matrices or vectors that don't fit together (ex: multiplying a 2x3 matrix by a 2x2 one)
+    MismatchedShapes(String),
+    /// `root` was given an interval where the function doesn't change sign
+    NoSignChange(f64, f64),
+    /// A function was given something it isn't defined for (ex: the mean of an empty list)
+    Domain(String),
+    /// A numerical method didn't get close enough in time (name of the function)
+    TooManyIterations(String),
+    /// A call to a function that isn't defined (ex: `f(2)` before `f(x) = x^2`)
+    UnknownFunction(String),
+    /// The evaluation was stopped from outside (see `Environment::set_cancel`)
+    Cancelled,
+    /// An `assert` in a script was false (the expression)
+    AssertionFailed(String),
+}
+
+impl EvalError {
+    /// A name for the kind of error that won't change (ex: `division_by_zero`)
+    pub fn kind(&self) -> &'static str {
+        return match self {
+            EvalError::WrongType(_) => "wrong_type",
+            EvalError::MalformedTree(_) => "malformed_tree",
+            EvalError::DivisionByZero => "division_by_zero",
+            EvalError::UnknownVariable(_) => "unknown_variable",
+            EvalError::ExpectedVariable(_) => "expected_variable",
+            EvalError::NotDifferentiable(_) => "not_differentiable",
+            EvalError::NotPolynomial(_) => "not_polynomial",
+            EvalError::NoMatchingPiece => "no_matching_piece",
+            EvalError::NotCompilable(_) => "not_compilable",
+            EvalError::MismatchedLengths(..) => "mismatched_lengths",
+            EvalError::MismatchedShapes(_) => "mismatched_shapes",
+            EvalError::NoSignChange(..) => "no_sign_change",
+            EvalError::Domain(_) => "domain",
+            EvalError::TooManyIterations(_) => "too_many_iterations",
+            EvalError::UnknownFunction(_) => "unknown_function",
+            EvalError::Cancelled => "cancelled",
+            EvalError::AssertionFailed(_) => "assertion_failed",
+        };
+    }
+}
+
+impl Display for EvalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return match self {
+            EvalError::WrongType(message)
+            | EvalError::NotPolynomial(message)
+            | EvalError::MismatchedShapes(message)
+            | EvalError::Domain(message) => write!(f, "{}", message),
+            EvalError::MalformedTree(message) => write!(f, "Malformed tree: {}", message),
+            EvalError::DivisionByZero => write!(f, "Division by zero"),
+            EvalError::UnknownVariable(name) => write!(f, "Unknown variable '{}'", name),
+            EvalError::ExpectedVariable(found) => {
+                write!(f, "Expected a variable, not '{}'", found)
+            }
+            EvalError::NotDifferentiable(name) => write!(f, "Can't differentiate {}", name),
+            EvalError::NoMatchingPiece => {
+                write!(f, "None of the conditions of piecewise are true")
+            }
+            EvalError::NotCompilable(name) => write!(f, "{} can't be compiled", name),
+            EvalError::MismatchedLengths(a, b) => {
+                write!(f, "Lists of different lengths ({} and {})", a, b)
+            }
+            EvalError::NoSignChange(a, b) => {
+                write!(
+                    f,
+                    "The function doesn't change sign between {} and {}",
+                    a, b
+                )
+            }
+            EvalError::TooManyIterations(name) => write!(f, "{} didn't converge", name),
+            EvalError::UnknownFunction(name) => write!(f, "Unknown function '{}'", name),
+            EvalError::Cancelled => write!(f, "Cancelled"),
+            EvalError::AssertionFailed(expression) => write!(f, "Assertion failed: {}", expression),
+        };
+    }
+}
+
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        return match self {
+            Value::Number(_) => "number",
+            Value::Bool(_) => "boolean",
+            Value::List(_) => "list",
+            Value::Date(_) => "date",
+            Value::Duration(_) => "duration",
+            Value::Percent(_) => "percentage",
+            Value::Expression(_) => "expression",
+        };
+    }
+
+    /// Booleans count as 1 and 0, so `true + true` is 2
+    pub fn as_number(&self) -> Result<f64, EvalError> {
+        return match self {
+            Value::Number(n) => Ok(*n),
+            Value::Bool(b) => Ok(if *b { 1.0 } else { 0.0 }),
+            Value::Percent(p) => Ok(p / 100.0),
+            _ => Err(self.wrong_type("number")),
+        };
+    }
+
+    /// Any number other than 0 (and NaN) is true
+    pub fn as_bool(&self) -> Result<bool, EvalError> {
+        return match self {
+            Value::Number(n) => Ok(*n != 0.0 && !n.is_nan()),
+            Value::Bool(b) => Ok(*b),
+            _ => Err(self.wrong_type("boolean")),
+        };
+    }
+
+    fn wrong_type(&self, expected: &str) -> EvalError {
+        return EvalError::WrongType(format!(
+            "Expected a {}, found a {}",
+            expected,
+            self.type_name()
+        ));
+    }
+
+    /// Like `as_bool`, but for every value: lists are true when they aren't empty,
+    /// durations and percentages when they aren't 0, and dates and expressions always are
+    pub fn is_truthy(&self) -> bool {
+        return match self {
+            Value::Number(n) | Value::Percent(n) => *n != 0.0 && !n.is_nan(),
+            Value::Bool(b) => *b,
+            Value::List(items) => !items.is_empty(),
+            Value::Duration(t) => *t != 0.0,
+            Value::Date(_) | Value::Expression(_) => true,
+        };
+    }
+
+    /// The numbers to compare two items by, where dates and durations can only be
+    /// compared to their own kind
+    fn comparable(a: &Value, b: &Value) -> Result<(f64, f64), EvalError> {
+        return match (a, b) {
+            (Value::Date(a), Value::Date(b)) | (Value::Duration(a), Value::Duration(b)) => {
+                Ok((*a, *b))
+            }
+            _ => Ok((a.as_number()?, b.as_number()?)),
+        };
+    }
+
+    /// Applies `fun` to each item when either side is a list, so `[1, 2] + 1` is `[2, 3]`
+    /// and `[1, 2] * [3, 4]` is `[3, 8]`
+    pub fn broadcast(
+        a: Value,
+        b: Value,
+        fun: impl Fn(Value, Value) -> Result<Value, EvalError> + Copy,
+    ) -> Result<Value, EvalError> {
+        let items: Result<Vec<Value>, EvalError> = match (a, b) {
+            (Value::List(a), Value::List(b)) => {
+                if a.len() != b.len() {
+                    return Err(EvalError::MismatchedLengths(a.len(), b.len()));
+                }
+                a.into_iter()
+                    .zip(b)
+                    .map(|(a, b)| Value::broadcast(a, b, fun))
+                    .collect()
+            }
+            (Value::List(a), b) => a
+                .into_iter()
+                .map(|a| Value::broadcast(a, b.clone(), fun))
+                .collect(),
+            (a, Value::List(b)) => b
+                .into_iter()
+                .map(|b| Value::broadcast(a.clone(), b, fun))
+                .collect(),
+            (a, b) => return fun(a, b),
+        };
+        return Ok(Value::List(items?));
+    }
+
+    /// Applies a numeric operator like `+` or `nCr`
+    pub fn arith(a: Value, b: Value, fun: fn(f64, f64) -> f64) -> Result<Value, EvalError> {
+        return Value::broadcast(a, b, |a, b| {
+            Ok(Value::Number(fun(a.as_number()?, b.as_number()?)))
+        });
+    }
+
+    /// Like `arith`, for operators that can fail (like `nCr` of a negative number)
+    pub fn try_arith(
+        a: Value,
+        b: Value,
+        fun: fn(f64, f64) -> Result<f64, EvalError>,
+    ) -> Result<Value, EvalError> {
+        return Value::broadcast(a, b, |a, b| {
+            Ok(Value::Number(fun(a.as_number()?, b.as_number()?)?))
+        });
+    }
+
+    pub fn divide(a: Value, b: Value) -> Result<Value, EvalError> {
+        return Value::broadcast(a, b, time::divide);
+    }
+
+    pub fn remainder(a: Value, b: Value) -> Result<Value, EvalError> {
+        return Value::broadcast(a, b, |a, b| {
+            let b = b.as_number()?;
+            if b == 0.0 {
+                return Err(EvalError::DivisionByZero);
+            }
+            return Ok(Value::Number(a.as_number()?.rem_euclid(b)));
+        });
+    }
+
+    /// `x%`, to each item if it's a list
+    pub fn percent(self) -> Result<Value, EvalError> {
+        if let Value::List(items) = self {
+            let items = items.into_iter().map(|v| v.percent());
+            return Ok(Value::List(items.collect::<Result<_, _>>()?));
+        }
+        return Ok(Value::Percent(self.as_number()?));
+    }
+
+    /// `self + p%` (or `self - p%` with a negative p), which is `self` made p percent bigger,
+    /// like on a desk calculator
+    pub fn change_by_percent(self, p: f64) -> Result<Value, EvalError> {
+        let change = time::multiply(self.clone(), Value::Number(p / 100.0))?;
+        return time::add(self, change);
+    }
+
+    /// Applies a numeric function like `sin` or `!`, to each item if it's a list
+    pub fn map(self, fun: fn(f64) -> f64) -> Result<Value, EvalError> {
+        if let Value::List(items) = self {
+            let items = items.into_iter().map(|v| v.map(fun));
+            return Ok(Value::List(items.collect::<Result<_, _>>()?));
+        }
+        return Ok(Value::Number(fun(self.as_number()?)));
+    }
+
+    /// Like `map`, for functions that can fail (like `gamma` at 0)
+    pub fn try_map(self, fun: fn(f64) -> Result<f64, EvalError>) -> Result<Value, EvalError> {
+        if let Value::List(items) = self {
+            let items = items.into_iter().map(|v| v.try_map(fun));
+            return Ok(Value::List(items.collect::<Result<_, _>>()?));
+        }
+        return Ok(Value::Number(fun(self.as_number()?)?));
+    }
+
+    /// Applies an ordering operator like `<`
+    pub fn compare(a: Value, b: Value, fun: fn(&f64, &f64) -> bool) -> Result<Value, EvalError> {
+        return Value::broadcast(a, b, |a, b| {
+            let (a, b) = Value::comparable(&a, &b)?;
+            Ok(Value::Bool(fun(&a, &b)))
+        });
+    }
+
+    /// Applies a logical operator like `and`
+    pub fn logic(a: Value, b: Value, fun: fn(bool, bool) -> bool) -> Result<Value, EvalError> {
+        return Ok(Value::Bool(fun(a.as_bool()?, b.as_bool()?)));
+    }
+
+    /// Lists are equal when they're the same length and all their items are equal
+    pub fn equals(&self, other: &Value) -> Result<bool, EvalError> {
+        return match (self, other) {
+            (Value::Bool(a), Value::Bool(b)) => Ok(a == b),
+            (Value::List(a), Value::List(b)) => {
+                if a.len() != b.len() {
+                    return Ok(false);
+                }
+                for (a, b) in a.iter().zip(b) {
+                    if !a.equals(b)? {
+                        return Ok(false);
+                    }
+                }
+                return Ok(true);
+            }
+            _ => {
+                let (a, b) = Value::comparable(self, other)?;
+                Ok(a == b)
+            }
+        };
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return match self {
+            Value::Number(n) => write!(f, "{}", n),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::List(items) => {
+                let items = items.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+                write!(f, "[{}]", items.join(", "))
+            }
+            Value::Date(date) => write!(f, "{}", time::format_date(*date)),
+            Value::Duration(t) => write!(f, "{}", time::format_duration(*t)),
+            Value::Percent(p) => write!(f, "{}%", p),
+            Value::Expression(tree) => write!(f, "{}", tree),
+        };
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        return Value::Number(value);
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        return Value::Bool(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::parse_str;
+
+    #[test]
+    fn truthiness() {
+        assert!(Value::Percent(10.0).is_truthy());
+        assert!(!Value::Percent(0.0).is_truthy());
+        assert!(!Value::Number(f64::NAN).is_truthy());
+        assert!(!Value::List(vec![]).is_truthy());
+        assert!(Value::Duration(60.0).is_truthy());
+        let tree = parse_str("x^2").unwrap();
+        assert!(Value::Expression(Box::new(tree)).is_truthy());
+    }
+
+    #[test]
+    fn mismatched_lengths_are_in_order() {
+        let error = parse_str("[1, 2] + [1, 2, 3]").unwrap().eval().unwrap_err();
+        assert_eq!(error.to_string(), "Lists of different lengths (2 and 3)");
+        let error = parse_str("normcdf([0, 1, 2], [0, 1], 1)")
+            .unwrap()
+            .eval()
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Lists of different lengths (3 and 2)");
+    }
+}